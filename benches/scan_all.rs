@@ -1,28 +1,53 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use sync::scan_all;
-use tokio::runtime::Runtime;
-use zcash_client_backend::encoding::decode_extended_full_viewing_key;
-use zcash_primitives::consensus::{Network, Parameters};
+use std::collections::HashMap;
+use warp_api_ffi::{advance_tree, AccountViewKey, CTree, DecryptNode, SyntheticChain};
+use zcash_primitives::consensus::{Network, NetworkUpgrade, Parameters};
+use zcash_primitives::sapling::Node;
+use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+const NETWORK: Network = Network::MainNetwork;
 
 fn scan(c: &mut Criterion) {
-    dotenv::dotenv().unwrap();
-    env_logger::init();
+    let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+    let start_height: u32 = NETWORK
+        .activation_height(NetworkUpgrade::Sapling)
+        .unwrap()
+        .into();
+    let mut chain = SyntheticChain::new(NETWORK, start_height, 0);
+    for i in 0..100 {
+        chain.add_filler_outputs(50);
+        if i % 10 == 0 {
+            chain.add_note(&fvk, 100_000);
+        }
+        chain.mine_block();
+    }
+    let blocks = chain.blocks();
 
-    let ivk = dotenv::var("IVK").unwrap();
-    let fvk = decode_extended_full_viewing_key(
-        Network::MainNetwork.hrp_sapling_extended_full_viewing_key(),
-        &ivk,
-    )
-    .unwrap()
-    .unwrap();
+    let mut vks = HashMap::new();
+    vks.insert(1, AccountViewKey::from_fvk(&fvk));
+    let decrypter = DecryptNode::new(vks);
 
-    let fvks = &vec![fvk];
+    c.bench_function("decrypt", |b| {
+        b.iter(|| {
+            let dec_blocks = decrypter.decrypt_blocks(&NETWORK, blocks);
+            assert_eq!(dec_blocks.iter().map(|b| b.notes.len()).sum::<usize>(), 10);
+        });
+    });
 
-    c.bench_function("scan all", |b| {
+    let nodes: Vec<Node> = blocks
+        .iter()
+        .flat_map(|b| b.vtx.iter())
+        .flat_map(|tx| tx.outputs.iter())
+        .map(|co| {
+            let mut cmu = [0u8; 32];
+            cmu.copy_from_slice(&co.cmu);
+            Node::new(cmu)
+        })
+        .collect();
+    c.bench_function("advance tree", |b| {
         b.iter(|| {
-            let r = Runtime::new().unwrap();
-            r.block_on(scan_all(&Network::MainNetwork, fvks.clone().as_slice()))
-                .unwrap();
+            let mut nodes = nodes.clone();
+            advance_tree(&CTree::new(), &[], &mut nodes, true)
         });
     });
 }
@@ -33,11 +33,27 @@ void set_active_account(uint8_t coin, uint32_t id);
 
 void set_coin_lwd_url(uint8_t coin, char *lwd_url);
 
+void set_coin_block_cache(uint8_t coin, char *dir, uint32_t max_size_mb);
+
+void set_coin_lwd_servers(uint8_t coin, char **servers, uint32_t count, uint32_t samples);
+
+void set_coin_proxy(uint8_t coin, char *address, bool isolate);
+
+void set_coin_tls_settings(uint8_t coin, char *url, char *settings);
+
+void set_coin_tree_check_interval(uint8_t coin, uint32_t interval);
+
+void set_coin_witness_retention(uint8_t coin,
+                                uint32_t recent_blocks,
+                                uint32_t checkpoint_interval);
+
+void clear_block_cache(uint8_t coin);
+
 char *get_lwd_url(uint8_t coin);
 
 void reset_app(void);
 
-uint32_t new_account(uint8_t coin, char *name, char *data, int32_t index);
+uint32_t new_account(uint8_t coin, char *name, char *data, int32_t index, uint32_t birthday);
 
 void new_sub_account(char *name, int32_t index, uint32_t count);
 
@@ -45,25 +61,43 @@ void import_transparent_key(uint8_t coin, uint32_t id_account, char *path);
 
 void import_transparent_secret_key(uint8_t coin, uint32_t id_account, char *secret_key);
 
-void cancel_warp(void);
+void cancel_warp(uint8_t coin);
+
+void pause_warp(uint8_t coin);
+
+void resume_warp(uint8_t coin);
+
+char *get_sync_status(uint8_t coin);
 
 uint8_t warp(uint8_t coin, bool get_tx, uint32_t anchor_offset, int64_t port);
 
+uint8_t rescan_account(uint8_t coin, uint32_t id_account, uint32_t from_height, int64_t port);
+
 int8_t is_valid_key(uint8_t coin, char *key);
 
 bool valid_address(uint8_t coin, char *address);
 
 char *new_diversified_address(void);
 
+char *get_unified_address(uint8_t coin, uint32_t id_account);
+
+char *new_diversified_unified_address(void);
+
 uint32_t get_latest_height(void);
 
 char *send_multi_payment(char *recipients_json,
                          bool use_transparent,
                          uint32_t anchor_offset,
+                         uint8_t note_selector,
+                         bool fee_from_recipients,
                          int64_t port);
 
 void skip_to_last_height(uint8_t coin);
 
+uint32_t backfill_tx_details(void);
+
+char *check_servers(uint8_t coin);
+
 void rewind_to_height(uint32_t height);
 
 int64_t mempool_sync(void);
@@ -78,12 +112,31 @@ char *shield_taddr(void);
 
 void scan_transparent_accounts(uint32_t gap_limit);
 
-char *prepare_multi_payment(char *recipients_json, bool use_transparent, uint32_t anchor_offset);
+char *prepare_multi_payment(char *recipients_json,
+                            bool use_transparent,
+                            uint32_t anchor_offset,
+                            uint8_t note_selector,
+                            bool fee_from_recipients);
+
+char *get_notes(void);
+
+void freeze_notes(char *notes_json, bool frozen);
+
+char *send_payment_with_notes(char *recipients_json,
+                              char *notes_json,
+                              uint32_t anchor_offset,
+                              int64_t port);
 
 char *sign(char *tx, int64_t port);
 
 char *broadcast_tx(char *tx_str);
 
+char *get_pending_txs(void);
+
+char *rebroadcast_pending_tx(uint32_t id_tx);
+
+void abandon_pending_tx(uint32_t id_tx);
+
 uint32_t get_activation_date(void);
 
 uint32_t get_block_by_time(uint32_t time);
@@ -118,9 +171,9 @@ char *merge_data(char *drop);
 
 char *get_tx_summary(char *tx);
 
-char *get_best_server(char **servers, uint32_t count);
+char *get_best_server(uint8_t coin, char **servers, uint32_t count);
 
-void import_from_zwl(uint8_t coin, char *name, char *data);
+void import_from_zwl(uint8_t coin, char *name, char *data, uint32_t birthday);
 
 char *derive_zip32(uint8_t coin,
                    uint32_t id_account,
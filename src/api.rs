@@ -4,6 +4,7 @@ pub mod fullbackup;
 pub mod historical_prices;
 pub mod mempool;
 pub mod message;
+pub mod notes;
 pub mod payment;
 pub mod payment_uri;
 pub mod sync;
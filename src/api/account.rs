@@ -3,19 +3,25 @@
 use crate::coinconfig::CoinConfig;
 use crate::key2::decode_key;
 use crate::taddr::{derive_taddr, derive_tkeys};
+use crate::ua::get_ua;
 use crate::{derive_zip32, KeyPack};
 use anyhow::anyhow;
 use bip39::{Language, Mnemonic};
+use orchard::keys::Scope;
 use rand::rngs::OsRng;
 use rand::RngCore;
 use zcash_client_backend::encoding::{decode_extended_full_viewing_key, encode_payment_address};
 use zcash_primitives::consensus::Parameters;
 
+/// Create or import an account
+///
+/// `birthday` is the height from which [crate::api::sync::start_rescan] looks for its notes
 pub fn new_account(
     coin: u8,
     name: &str,
     key: Option<String>,
     index: Option<u32>,
+    birthday: Option<u32>,
 ) -> anyhow::Result<u32> {
     let key = match key {
         Some(key) => key,
@@ -27,6 +33,10 @@ pub fn new_account(
         }
     };
     let id_account = new_account_with_key(coin, name, &key, index.unwrap_or(0))?;
+    if birthday.is_some() {
+        let c = CoinConfig::get(coin);
+        c.db()?.set_birthday(id_account, birthday)?;
+    }
     Ok(id_account)
 }
 
@@ -51,6 +61,7 @@ fn new_account_with_key(coin: u8, name: &str, key: &str, index: u32) -> anyhow::
         db.store_account(name, seed.as_deref(), index, sk.as_deref(), &ivk, &pa)?;
     if !exists {
         db.create_taddr(account)?;
+        db.create_orchard(account)?;
     }
     Ok(account)
 }
@@ -92,19 +103,59 @@ pub fn new_diversified_address() -> anyhow::Result<String> {
     Ok(pa)
 }
 
+/// Default unified address of an account
+///
+/// Combines the Sapling address, the transparent address and the
+/// Orchard receiver when the account has them
+pub fn get_unified_address(coin: u8, id_account: u32) -> anyhow::Result<String> {
+    let c = CoinConfig::get(coin);
+    let db = c.db()?;
+    let address = db.get_address(id_account)?;
+    let taddr = db.get_taddr(id_account)?;
+    let orchard_address = db
+        .get_orchard_fvk(id_account)?
+        .map(|fvk| fvk.address_at(0u32, Scope::External));
+    let ua = get_ua(&address, taddr.as_deref(), orchard_address.as_ref())?;
+    Ok(ua.to_string())
+}
+
+/// New diversified unified address of the active account
+///
+/// The Sapling and Orchard receivers use the same diversifier index.
+/// The transparent receiver is left out because it would link
+/// all the diversified addresses together
+pub fn new_diversified_unified_address() -> anyhow::Result<String> {
+    let c = CoinConfig::get_active();
+    let db = c.db()?;
+    let ivk = db.get_ivk(c.id_account)?;
+    let fvk = decode_extended_full_viewing_key(
+        c.chain.network().hrp_sapling_extended_full_viewing_key(),
+        &ivk,
+    )?
+    .unwrap();
+    let mut diversifier_index = db.get_diversifier(c.id_account)?;
+    diversifier_index.increment().unwrap();
+    let (new_diversifier_index, pa) = fvk
+        .find_address(diversifier_index)
+        .ok_or_else(|| anyhow::anyhow!("Cannot generate new address"))?;
+    let orchard_address = db
+        .get_orchard_fvk(c.id_account)?
+        .map(|fvk| fvk.address_at(new_diversifier_index.0, Scope::External));
+    db.store_diversifier(c.id_account, &new_diversifier_index)?;
+    let pa = encode_payment_address(c.chain.network().hrp_sapling_payment_address(), &pa);
+    let ua = get_ua(&pa, None, orchard_address.as_ref())?;
+    Ok(ua.to_string())
+}
+
 pub async fn get_taddr_balance_default() -> anyhow::Result<u64> {
     let c = CoinConfig::get_active();
     get_taddr_balance(c.coin, c.id_account).await
 }
 
+/// Balance of the transparent addresses of the account, from the utxos found by the last sync
 pub async fn get_taddr_balance(coin: u8, id_account: u32) -> anyhow::Result<u64> {
     let c = CoinConfig::get(coin);
-    let mut client = c.connect_lwd().await?;
-    let address = c.db()?.get_taddr(id_account)?;
-    let balance = match address {
-        None => 0u64,
-        Some(address) => crate::taddr::get_taddr_balance(&mut client, &address).await?,
-    };
+    let balance = c.db()?.get_transparent_balance(id_account)?;
     Ok(balance)
 }
 
@@ -154,14 +205,23 @@ pub fn delete_account(coin: u8, account: u32) -> anyhow::Result<()> {
     Ok(())
 }
 
-pub fn import_from_zwl(coin: u8, name: &str, data: &str) -> anyhow::Result<()> {
+pub fn import_from_zwl(
+    coin: u8,
+    name: &str,
+    data: &str,
+    birthday: Option<u32>,
+) -> anyhow::Result<()> {
     let c = CoinConfig::get(coin);
     let sks = crate::read_zwl(data)?;
     let db = c.db()?;
     for (i, key) in sks.iter().enumerate() {
         let name = format!("{}-{}", name, i + 1);
         let (seed, sk, ivk, pa) = decode_key(coin, key, 0)?;
-        db.store_account(&name, seed.as_deref(), 0, sk.as_deref(), &ivk, &pa)?;
+        let (account, exists) =
+            db.store_account(&name, seed.as_deref(), 0, sk.as_deref(), &ivk, &pa)?;
+        if !exists && birthday.is_some() {
+            db.set_birthday(account, birthday)?;
+        }
     }
     Ok(())
 }
@@ -1,4 +1,4 @@
-use crate::api::payment::{build_sign_send_multi_payment, RecipientMemo};
+use crate::api::payment::{build_sign_send_multi_payment, NoteSelector, RecipientMemo};
 use crate::api::sync::get_latest_height;
 use crate::coinconfig::CoinConfig;
 use crate::contact::{serialize_contacts, Contact};
@@ -34,6 +34,8 @@ pub async fn save_contacts_tx(memos: &[Memo], anchor_offset: u32) -> anyhow::Res
             amount: 0,
             memo: m.clone(),
             max_amount_per_note: 0,
+            max: false,
+            pays_fee: false,
         })
         .collect();
 
@@ -42,6 +44,8 @@ pub async fn save_contacts_tx(memos: &[Memo], anchor_offset: u32) -> anyhow::Res
         &recipients,
         false,
         anchor_offset,
+        NoteSelector::default(),
+        false,
         Box::new(|_| {}),
     )
     .await?;
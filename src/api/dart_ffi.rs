@@ -9,7 +9,6 @@ use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use tokio::sync::Semaphore;
 use zcash_primitives::transaction::builder::Progress;
 
 static mut POST_COBJ: Option<ffi::DartPostCObjectFnType> = None;
@@ -126,6 +125,80 @@ pub unsafe extern "C" fn set_coin_lwd_url(coin: u8, lwd_url: *mut c_char) {
     crate::coinconfig::set_coin_lwd_url(coin, &lwd_url);
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_block_cache(coin: u8, dir: *mut c_char, max_size_mb: u32) {
+    from_c_str!(dir);
+    let res = crate::coinconfig::set_coin_block_cache(coin, &dir, max_size_mb as u64 * 1_000_000);
+    log_result(res)
+}
+
+/// Cross check the lwd url against `servers` and fail over to them (none to disable)
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_lwd_servers(
+    coin: u8,
+    servers: *mut *mut c_char,
+    count: u32,
+    samples: u32,
+) {
+    let mut cservers = vec![];
+    for i in 0..count {
+        let ptr = *servers.offset(i as isize);
+        let s = CStr::from_ptr(ptr).to_string_lossy();
+        cservers.push(s.to_string());
+    }
+    crate::coinconfig::set_coin_lwd_servers(coin, &cservers, samples);
+}
+
+/// Connect through the SOCKS5 proxy at `address` (host:port), or directly if empty.
+/// With `isolate`, each connection uses its own credentials (Tor stream isolation)
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_proxy(coin: u8, address: *mut c_char, isolate: bool) {
+    from_c_str!(address);
+    let proxy = if address.is_empty() {
+        None
+    } else {
+        Some(crate::Proxy::new(&address, isolate))
+    };
+    crate::coinconfig::set_coin_proxy(coin, proxy);
+}
+
+/// TLS settings of the server at `url` as JSON, or the default settings if empty
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_tls_settings(coin: u8, url: *mut c_char, settings: *mut c_char) {
+    from_c_str!(url);
+    from_c_str!(settings);
+    let res = || {
+        let tls = if settings.is_empty() {
+            None
+        } else {
+            Some(serde_json::from_str::<crate::TlsSettings>(&settings)?)
+        };
+        crate::coinconfig::set_coin_tls_settings(coin, &url, tls);
+        Ok(())
+    };
+    log_result(res())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_tree_check_interval(coin: u8, interval: u32) {
+    crate::coinconfig::set_coin_tree_check_interval(coin, interval);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn set_coin_witness_retention(
+    coin: u8,
+    recent_blocks: u32,
+    checkpoint_interval: u32,
+) {
+    crate::coinconfig::set_coin_witness_retention(coin, recent_blocks, checkpoint_interval);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn clear_block_cache(coin: u8) {
+    let res = crate::api::sync::clear_block_cache(coin);
+    log_result(res)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn get_lwd_url(coin: u8) -> *mut c_char {
     let server = crate::coinconfig::get_coin_lwd_url(coin);
@@ -148,6 +221,7 @@ pub unsafe extern "C" fn new_account(
     name: *mut c_char,
     data: *mut c_char,
     index: i32,
+    birthday: u32,
 ) -> u32 {
     from_c_str!(name);
     from_c_str!(data);
@@ -157,7 +231,8 @@ pub unsafe extern "C" fn new_account(
         None
     };
     let index = if index >= 0 { Some(index as u32) } else { None };
-    let res = crate::api::account::new_account(coin, &name, data, index);
+    let birthday = if birthday != 0 { Some(birthday) } else { None };
+    let res = crate::api::account::new_account(coin, &name, data, index, birthday);
     log_result(res)
 }
 
@@ -187,38 +262,55 @@ pub unsafe extern "C" fn import_transparent_secret_key(
     log_result(res)
 }
 
-lazy_static! {
-    static ref SYNC_LOCK: Semaphore = Semaphore::new(1);
-    static ref SYNC_CANCELED: AtomicBool = AtomicBool::new(false);
+#[no_mangle]
+pub unsafe extern "C" fn cancel_warp(coin: u8) {
+    if let Some(handle) = crate::api::sync::get_sync_handle(coin) {
+        handle.cancel();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pause_warp(coin: u8) {
+    if let Some(handle) = crate::api::sync::get_sync_handle(coin) {
+        handle.pause();
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn cancel_warp() {
-    log::info!("Sync canceled");
-    SYNC_CANCELED.store(true, Ordering::Release);
+pub unsafe extern "C" fn resume_warp(coin: u8) {
+    if let Some(handle) = crate::api::sync::get_sync_handle(coin) {
+        handle.resume();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn get_sync_status(coin: u8) -> *mut c_char {
+    let res = || {
+        let status = crate::api::sync::get_sync_handle(coin).map(|h| h.status());
+        let status_str = serde_json::to_string(&status)?;
+        Ok(status_str)
+    };
+    to_c_str(log_string(res()))
 }
 
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn warp(coin: u8, get_tx: bool, anchor_offset: u32, port: i64) -> u8 {
     let res = async {
-        let _permit = SYNC_LOCK.acquire().await?;
         log::info!("Sync started");
-        let result = crate::api::sync::coin_sync(
-            coin,
-            get_tx,
-            anchor_offset,
-            move |height| {
-                let mut height = height.into_dart();
-                if port != 0 {
-                    if let Some(p) = POST_COBJ {
-                        p(port, &mut height);
-                    }
+        let handle = crate::api::sync::start_sync(coin, get_tx, anchor_offset, move |progress| {
+            if port != 0 {
+                let progress = serde_json::to_string(&progress).unwrap();
+                let mut progress = progress.into_dart();
+                if let Some(p) = POST_COBJ {
+                    p(port, &mut progress);
                 }
-            },
-            &SYNC_CANCELED,
-        )
-        .await;
+            }
+        });
+        let result = match handle {
+            Ok(handle) => handle.wait().await,
+            Err(err) => Err(err),
+        };
         log::info!("Sync finished");
 
         crate::api::mempool::scan().await?;
@@ -230,6 +322,9 @@ pub async unsafe extern "C" fn warp(coin: u8, get_tx: bool, anchor_offset: u32,
                     match e {
                         ChainError::Reorg => Ok(1),
                         ChainError::Busy => Ok(2),
+                        ChainError::TreeMismatch { .. } => Ok(3),
+                        ChainError::NoConsensus => Ok(4),
+                        ChainError::InvalidData { .. } => Ok(5),
                     }
                 } else {
                     log::error!("{}", err);
@@ -239,10 +334,53 @@ pub async unsafe extern "C" fn warp(coin: u8, get_tx: bool, anchor_offset: u32,
         }
     };
     let r = res.await;
-    SYNC_CANCELED.store(false, Ordering::Release);
     log_result(r)
 }
 
+/// Rescan the blocks from `from_height` for one account. 0 uses the account birthday
+#[tokio::main]
+#[no_mangle]
+pub async unsafe extern "C" fn rescan_account(
+    coin: u8,
+    id_account: u32,
+    from_height: u32,
+    port: i64,
+) -> u8 {
+    let res = async {
+        let from_height = if from_height != 0 {
+            Some(from_height)
+        } else {
+            None
+        };
+        let handle =
+            crate::api::sync::start_rescan(coin, id_account, from_height, move |progress| {
+                if port != 0 {
+                    let progress = serde_json::to_string(&progress).unwrap();
+                    let mut progress = progress.into_dart();
+                    if let Some(p) = POST_COBJ {
+                        p(port, &mut progress);
+                    }
+                }
+            });
+        let result = match handle {
+            Ok(handle) => handle.wait().await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(_) => Ok(0),
+            Err(err) => match err.downcast_ref::<ChainError>() {
+                Some(ChainError::Reorg) => Ok(1),
+                Some(ChainError::Busy) => Ok(2),
+                Some(ChainError::TreeMismatch { .. }) => Ok(3),
+                Some(ChainError::NoConsensus) => Ok(4),
+                Some(ChainError::InvalidData { .. }) => Ok(5),
+                None => Err(err),
+            },
+        }
+    };
+    log_result(res.await)
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn is_valid_key(coin: u8, key: *mut c_char) -> i8 {
     from_c_str!(key);
@@ -261,6 +399,18 @@ pub unsafe extern "C" fn new_diversified_address() -> *mut c_char {
     to_c_str(log_string(res()))
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn get_unified_address(coin: u8, id_account: u32) -> *mut c_char {
+    let res = crate::api::account::get_unified_address(coin, id_account);
+    to_c_str(log_string(res))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn new_diversified_unified_address() -> *mut c_char {
+    let res = || crate::api::account::new_diversified_unified_address();
+    to_c_str(log_string(res()))
+}
+
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn get_latest_height() -> u32 {
@@ -289,6 +439,8 @@ pub async unsafe extern "C" fn send_multi_payment(
     recipients_json: *mut c_char,
     use_transparent: bool,
     anchor_offset: u32,
+    note_selector: u8,
+    fee_from_recipients: bool,
     port: i64,
 ) -> *mut c_char {
     from_c_str!(recipients_json);
@@ -300,6 +452,8 @@ pub async unsafe extern "C" fn send_multi_payment(
             &recipients,
             use_transparent,
             anchor_offset,
+            note_selector.into(),
+            fee_from_recipients,
             Box::new(move |progress| {
                 report_progress(progress, port);
             }),
@@ -317,6 +471,25 @@ pub async unsafe extern "C" fn skip_to_last_height(coin: u8) {
     log_result(res)
 }
 
+#[tokio::main]
+#[no_mangle]
+pub async unsafe extern "C" fn backfill_tx_details() -> u32 {
+    let res = crate::api::sync::backfill_tx_details().await;
+    log_result(res)
+}
+
+/// Status of each server of the coin as JSON
+#[tokio::main]
+#[no_mangle]
+pub async unsafe extern "C" fn check_servers(coin: u8) -> *mut c_char {
+    let res = async {
+        let reports = crate::api::sync::check_coin_servers(coin).await?;
+        let reports = serde_json::to_string(&reports)?;
+        Ok(reports)
+    };
+    to_c_str(log_string(res.await))
+}
+
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn rewind_to_height(height: u32) {
@@ -376,6 +549,8 @@ pub async unsafe extern "C" fn prepare_multi_payment(
     recipients_json: *mut c_char,
     use_transparent: bool,
     anchor_offset: u32,
+    note_selector: u8,
+    fee_from_recipients: bool,
 ) -> *mut c_char {
     from_c_str!(recipients_json);
     let res = async {
@@ -386,6 +561,8 @@ pub async unsafe extern "C" fn prepare_multi_payment(
             &recipients,
             use_transparent,
             anchor_offset,
+            note_selector.into(),
+            fee_from_recipients,
         )
         .await?;
         let tx_str = serde_json::to_string(&tx)?;
@@ -394,6 +571,59 @@ pub async unsafe extern "C" fn prepare_multi_payment(
     to_c_str(log_string(res.await))
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn get_notes() -> *mut c_char {
+    let res = || {
+        let notes = crate::api::notes::get_notes()?;
+        let notes_str = serde_json::to_string(&notes)?;
+        Ok(notes_str)
+    };
+    to_c_str(log_string(res()))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn freeze_notes(notes_json: *mut c_char, frozen: bool) {
+    from_c_str!(notes_json);
+    let res = || {
+        let notes: Vec<crate::api::notes::NoteId> = serde_json::from_str(&notes_json)?;
+        if frozen {
+            crate::api::notes::freeze_notes(&notes)
+        } else {
+            crate::api::notes::unfreeze_notes(&notes)
+        }
+    };
+    log_result(res())
+}
+
+#[tokio::main]
+#[no_mangle]
+pub async unsafe extern "C" fn send_payment_with_notes(
+    recipients_json: *mut c_char,
+    notes_json: *mut c_char,
+    anchor_offset: u32,
+    port: i64,
+) -> *mut c_char {
+    from_c_str!(recipients_json);
+    from_c_str!(notes_json);
+    let res = async move {
+        let height = crate::api::sync::get_latest_height().await?;
+        let recipients = crate::api::payment::parse_recipients(&recipients_json)?;
+        let notes: Vec<crate::api::notes::NoteId> = serde_json::from_str(&notes_json)?;
+        let res = crate::api::notes::build_sign_send_payment_with_notes(
+            height,
+            &recipients,
+            &notes,
+            anchor_offset,
+            Box::new(move |progress| {
+                report_progress(progress, port);
+            }),
+        )
+        .await?;
+        Ok(res)
+    };
+    to_c_str(log_string(res.await))
+}
+
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn sign(tx: *mut c_char, port: i64) -> *mut c_char {
@@ -424,6 +654,29 @@ pub async unsafe extern "C" fn broadcast_tx(tx_str: *mut c_char) -> *mut c_char
     to_c_str(log_string(res.await))
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn get_pending_txs() -> *mut c_char {
+    let res = || {
+        let txs = crate::api::payment::get_pending_txs()?;
+        let txs_str = serde_json::to_string(&txs)?;
+        Ok(txs_str)
+    };
+    to_c_str(log_string(res()))
+}
+
+#[tokio::main]
+#[no_mangle]
+pub async unsafe extern "C" fn rebroadcast_pending_tx(id_tx: u32) -> *mut c_char {
+    let res = crate::api::payment::rebroadcast_pending_tx(id_tx).await;
+    to_c_str(log_string(res))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn abandon_pending_tx(id_tx: u32) {
+    let res = crate::api::payment::abandon_pending_tx(id_tx);
+    log_result(res)
+}
+
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn get_activation_date() -> u32 {
@@ -595,6 +848,7 @@ pub unsafe extern "C" fn get_tx_summary(tx: *mut c_char) -> *mut c_char {
 #[tokio::main]
 #[no_mangle]
 pub async unsafe extern "C" fn get_best_server(
+    coin: u8,
     servers: *mut *mut c_char,
     count: u32,
 ) -> *mut c_char {
@@ -604,17 +858,24 @@ pub async unsafe extern "C" fn get_best_server(
         let s = CStr::from_ptr(ptr).to_string_lossy();
         cservers.push(s.to_string());
     }
-    let best_server = crate::get_best_server(&cservers)
+    let c = CoinConfig::get(coin);
+    let best_server = crate::get_best_server(&cservers, &c.connection_settings)
         .await
         .unwrap_or(String::new());
     to_c_str(best_server)
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn import_from_zwl(coin: u8, name: *mut c_char, data: *mut c_char) {
+pub unsafe extern "C" fn import_from_zwl(
+    coin: u8,
+    name: *mut c_char,
+    data: *mut c_char,
+    birthday: u32,
+) {
     from_c_str!(name);
     from_c_str!(data);
-    let res = crate::api::account::import_from_zwl(coin, &name, &data);
+    let birthday = if birthday != 0 { Some(birthday) } else { None };
+    let res = crate::api::account::import_from_zwl(coin, &name, &data, birthday);
     log_result(res)
 }
 
@@ -0,0 +1,131 @@
+// Coin control
+
+use crate::api::payment::{
+    prepare_multi_payment, sign_send, NoteSelection, PaymentProgressCallback, RecipientMemo,
+};
+use crate::coinconfig::CoinConfig;
+pub use crate::db::{NoteId, Pool};
+use crate::ua::get_orchard_ua;
+use crate::Tx;
+use orchard::keys::{Diversifier as OrchardDiversifier, Scope};
+use serde::Serialize;
+use zcash_client_backend::encoding::{decode_extended_full_viewing_key, encode_payment_address};
+use zcash_primitives::consensus::Parameters;
+use zcash_primitives::sapling::Diversifier;
+
+#[derive(Serialize)]
+pub struct NoteRec {
+    pub id: NoteId,
+    pub value: u64,
+    pub height: u32,
+    pub confirmations: u32,
+    /// Diversified address that received the note
+    pub address: String,
+    pub spent: bool,
+    pub excluded: bool,
+}
+
+/// List the received notes of the active account
+pub fn get_notes() -> anyhow::Result<Vec<NoteRec>> {
+    let c = CoinConfig::get_active();
+    let network = c.chain.network();
+    let db = c.db()?;
+    let ivk = db.get_ivk(c.id_account)?;
+    let fvk =
+        decode_extended_full_viewing_key(network.hrp_sapling_extended_full_viewing_key(), &ivk)?
+            .ok_or_else(|| anyhow::anyhow!("Invalid viewing key"))?;
+    let orchard_fvk = db.get_orchard_fvk(c.id_account)?;
+    let default_address = db.get_address(c.id_account)?;
+    let last_height = db.get_last_sync_height()?.unwrap_or(0);
+
+    let notes = db.get_notes(c.id_account)?;
+    let mut note_recs = vec![];
+    for n in notes {
+        let mut d = [0u8; 11];
+        d.copy_from_slice(&n.diversifier);
+        let address = match n.id.pool {
+            Pool::Sapling => fvk
+                .fvk
+                .vk
+                .to_payment_address(Diversifier(d))
+                .map(|pa| encode_payment_address(network.hrp_sapling_payment_address(), &pa))
+                .unwrap_or_default(),
+            Pool::Orchard => match &orchard_fvk {
+                Some(orchard_fvk) => {
+                    // change notes are received on the internal scope
+                    let scope = n.scope.unwrap_or(Scope::External);
+                    let address = orchard_fvk.address(OrchardDiversifier::from_bytes(d), scope);
+                    get_orchard_ua(&default_address, &address)?.to_string()
+                }
+                None => String::new(),
+            },
+        };
+        note_recs.push(NoteRec {
+            id: n.id,
+            value: n.value,
+            height: n.height,
+            confirmations: (last_height + 1).saturating_sub(n.height),
+            address,
+            spent: n.spent,
+            excluded: n.excluded,
+        });
+    }
+    Ok(note_recs)
+}
+
+/// Exclude notes from the automatic note selection
+pub fn freeze_notes(notes: &[NoteId]) -> anyhow::Result<()> {
+    let c = CoinConfig::get_active();
+    c.db()?.exclude_notes(c.id_account, notes, true)?;
+    Ok(())
+}
+
+/// Make frozen notes spendable again
+pub fn unfreeze_notes(notes: &[NoteId]) -> anyhow::Result<()> {
+    let c = CoinConfig::get_active();
+    c.db()?.exclude_notes(c.id_account, notes, false)?;
+    Ok(())
+}
+
+/// Build a payment that spends exactly the given notes, for offline signing
+///
+/// Frozen notes must be unfrozen before they can be picked
+pub async fn build_only_payment_with_notes(
+    last_height: u32,
+    recipients: &[RecipientMemo],
+    notes: &[NoteId],
+    anchor_offset: u32,
+) -> anyhow::Result<Tx> {
+    let (tx, _) = prepare_multi_payment(
+        last_height,
+        recipients,
+        false,
+        anchor_offset,
+        NoteSelection::Exact(notes),
+        false,
+    )
+    .await?;
+    Ok(tx)
+}
+
+/// Build, sign and broadcast a payment that spends exactly the given notes
+///
+/// Frozen notes must be unfrozen before they can be picked
+pub async fn build_sign_send_payment_with_notes(
+    last_height: u32,
+    recipients: &[RecipientMemo],
+    notes: &[NoteId],
+    anchor_offset: u32,
+    progress_callback: PaymentProgressCallback,
+) -> anyhow::Result<String> {
+    let (tx, note_ids) = prepare_multi_payment(
+        last_height,
+        recipients,
+        false,
+        anchor_offset,
+        NoteSelection::Exact(notes),
+        false,
+    )
+    .await?;
+    sign_send(&tx, &note_ids, progress_callback).await
+}
@@ -4,30 +4,44 @@ use secp256k1::SecretKey;
 
 use crate::api::sync::get_latest_height;
 use crate::coinconfig::{get_prover, CoinConfig};
-use crate::pay::TxBuilder;
+pub use crate::pay::NoteSelector;
+use crate::pay::{SelectedNotes, TxBuilder};
 pub use crate::{broadcast_tx, Tx};
 use zcash_client_backend::encoding::{
     decode_extended_full_viewing_key, decode_extended_spending_key,
 };
+use zcash_params::coin::get_branch;
 use zcash_primitives::consensus::Parameters;
 use zcash_primitives::transaction::builder::Progress;
+use zcash_primitives::transaction::Transaction;
 
-use crate::db::ZMessage;
+use crate::db::{NoteId, PendingTxRec, Pool, ZMessage};
 use crate::taddr::get_utxos;
 use serde::Deserialize;
 use zcash_primitives::memo::Memo;
 // use crate::wallet::Recipient;
 
-type PaymentProgressCallback = Box<dyn Fn(Progress) + Send + Sync>;
+pub(crate) type PaymentProgressCallback = Box<dyn Fn(Progress) + Send + Sync>;
 
-async fn prepare_multi_payment(
+/// How the notes spent by a payment are chosen
+pub(crate) enum NoteSelection<'a> {
+    /// By the wallet, following a strategy
+    Auto(NoteSelector),
+    /// Exactly these notes
+    Exact(&'a [NoteId]),
+}
+
+pub(crate) async fn prepare_multi_payment(
     last_height: u32,
     recipients: &[RecipientMemo],
     use_transparent: bool,
     anchor_offset: u32,
-) -> anyhow::Result<(Tx, Vec<u32>)> {
+    selection: NoteSelection<'_>,
+    fee_from_recipients: bool,
+) -> anyhow::Result<(Tx, SelectedNotes)> {
     let c = CoinConfig::get_active();
     let mut tx_builder = TxBuilder::new(c.coin_type, last_height);
+    tx_builder.set_fee_from_recipients(fee_from_recipients);
 
     let fvk = c.db()?.get_ivk(c.id_account)?;
     let fvk = decode_extended_full_viewing_key(
@@ -36,6 +50,7 @@ async fn prepare_multi_payment(
     )
     .unwrap()
     .unwrap();
+    let orchard_fvk = c.db()?.get_orchard_fvk(c.id_account)?;
     let utxos = if use_transparent {
         let mut client = c.connect_lwd().await?;
         let t_address = c.db()?.get_taddr(c.id_account)?;
@@ -48,13 +63,48 @@ async fn prepare_multi_payment(
         vec![]
     };
 
-    let target_amount: u64 = recipients.iter().map(|r| r.amount).sum();
     let anchor_height = last_height.saturating_sub(anchor_offset);
     let spendable_notes = c
         .db()?
         .get_spendable_notes(c.id_account, anchor_height, &fvk)?;
-    let note_ids = tx_builder.select_inputs(&fvk, &spendable_notes, &utxos, target_amount)?;
-    tx_builder.select_outputs(&fvk, recipients)?;
+    let spendable_orchard_notes = match &orchard_fvk {
+        Some(orchard_fvk) => {
+            c.db()?
+                .get_spendable_orchard_notes(c.id_account, anchor_height, orchard_fvk)?
+        }
+        None => vec![],
+    };
+    tx_builder.select_outputs(&fvk, orchard_fvk.as_ref(), recipients)?;
+    let note_ids = match selection {
+        NoteSelection::Auto(selector) => tx_builder.select_inputs(
+            &fvk,
+            orchard_fvk.as_ref(),
+            &spendable_notes,
+            &spendable_orchard_notes,
+            &utxos,
+            selector,
+        )?,
+        NoteSelection::Exact(note_ids) => {
+            let picked = |pool: Pool, id: u32| note_ids.contains(&NoteId { pool, id_note: id });
+            let spendable_notes: Vec<_> = spendable_notes
+                .into_iter()
+                .filter(|n| picked(Pool::Sapling, n.id))
+                .collect();
+            let spendable_orchard_notes: Vec<_> = spendable_orchard_notes
+                .into_iter()
+                .filter(|n| picked(Pool::Orchard, n.id))
+                .collect();
+            if spendable_notes.len() + spendable_orchard_notes.len() != note_ids.len() {
+                anyhow::bail!("Some of the selected notes are not spendable");
+            }
+            tx_builder.select_exact_inputs(
+                &fvk,
+                orchard_fvk.as_ref(),
+                &spendable_notes,
+                &spendable_orchard_notes,
+            )?
+        }
+    };
     Ok((tx_builder.tx, note_ids))
 }
 
@@ -66,23 +116,36 @@ fn sign(tx: &Tx, progress_callback: PaymentProgressCallback) -> anyhow::Result<V
     let tsk = db
         .get_tsk(c.id_account)?
         .map(|tsk| SecretKey::from_str(&tsk).unwrap());
+    let osk = db.get_orchard_sk(c.id_account)?;
     let extsk =
         decode_extended_spending_key(c.chain.network().hrp_sapling_extended_spending_key(), &zsk)
             .unwrap()
             .unwrap();
-    let raw_tx = tx.sign(tsk, &extsk, prover, progress_callback)?;
+    let raw_tx = tx.sign(tsk, &extsk, osk.as_ref(), prover, progress_callback)?;
     Ok(raw_tx)
 }
 
 /// Build a multi payment for offline signing
+///
+/// If `fee_from_recipients` is set, the fee is deducted from the amounts of the
+/// recipients that pay the fee (or all of them if none does)
 pub async fn build_only_multi_payment(
     last_height: u32,
     recipients: &[RecipientMemo],
     use_transparent: bool,
     anchor_offset: u32,
+    selector: NoteSelector,
+    fee_from_recipients: bool,
 ) -> anyhow::Result<Tx> {
-    let (tx, _) =
-        prepare_multi_payment(last_height, recipients, use_transparent, anchor_offset).await?;
+    let (tx, _) = prepare_multi_payment(
+        last_height,
+        recipients,
+        use_transparent,
+        anchor_offset,
+        NoteSelection::Auto(selector),
+        fee_from_recipients,
+    )
+    .await?;
     // let tx_str = serde_json::to_string(&tx)?;
     Ok(tx)
 }
@@ -97,26 +160,111 @@ pub async fn sign_only_multi_payment(
 }
 
 /// Build, sign and broadcast a multi payment
+///
+/// See [build_only_multi_payment] for `fee_from_recipients`
 pub async fn build_sign_send_multi_payment(
     last_height: u32,
     recipients: &[RecipientMemo],
     use_transparent: bool,
     anchor_offset: u32,
+    selector: NoteSelector,
+    fee_from_recipients: bool,
+    progress_callback: PaymentProgressCallback,
+) -> anyhow::Result<String> {
+    let (tx, note_ids) = prepare_multi_payment(
+        last_height,
+        recipients,
+        use_transparent,
+        anchor_offset,
+        NoteSelection::Auto(selector),
+        fee_from_recipients,
+    )
+    .await?;
+    sign_send(&tx, &note_ids, progress_callback).await
+}
+
+/// Sign a transaction and keep it as pending until sync sees it, then broadcast it
+///
+/// Its notes are marked as spent and are released if the transaction expires
+/// or cannot be broadcast
+pub(crate) async fn sign_send(
+    tx: &Tx,
+    note_ids: &SelectedNotes,
     progress_callback: PaymentProgressCallback,
 ) -> anyhow::Result<String> {
     let c = CoinConfig::get_active();
-    let (tx, note_ids) =
-        prepare_multi_payment(last_height, recipients, use_transparent, anchor_offset).await?;
-    let raw_tx = sign(&tx, progress_callback)?;
-    let tx_id = broadcast_tx(&raw_tx).await?;
+    let raw_tx = sign(tx, progress_callback)?;
 
-    c.db()?.tx_mark_spend(&note_ids)?;
-    Ok(tx_id)
+    // record the transaction first: once broadcast, its notes must not be
+    // selected again even if the database update fails
+    let branch_id = get_branch(c.chain.network(), tx.height);
+    let signed_tx = Transaction::read(&*raw_tx, branch_id)?;
+    let id_tx = c.db()?.store_pending_tx(
+        c.id_account,
+        signed_tx.txid().as_ref(),
+        &raw_tx,
+        tx.height,
+        u32::from(signed_tx.expiry_height()),
+        &note_ids.sapling,
+        &note_ids.orchard,
+    )?;
+    match broadcast_tx(&raw_tx).await {
+        Ok(tx_id) => Ok(tx_id),
+        Err(err) => {
+            if let Err(e) = c.db().and_then(|mut db| db.abandon_pending_tx(id_tx)) {
+                log::error!("Cannot abandon pending transaction {}: {}", id_tx, e);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// List the transactions sent from the active account that are not
+/// confirmed yet or were confirmed recently
+pub fn get_pending_txs() -> anyhow::Result<Vec<PendingTxRec>> {
+    let c = CoinConfig::get_active();
+    let txs = c.db()?.get_pending_txs(c.id_account)?;
+    Ok(txs)
+}
+
+/// Broadcast a pending transaction again
+pub async fn rebroadcast_pending_tx(id_tx: u32) -> anyhow::Result<String> {
+    let c = CoinConfig::get_active();
+    let (raw_tx, expiry_height, confirmed) = c
+        .db()?
+        .get_pending_tx(id_tx)?
+        .ok_or_else(|| anyhow::anyhow!("Unknown pending transaction"))?;
+    if confirmed.is_some() {
+        anyhow::bail!("Transaction already confirmed");
+    }
+    let latest_height = get_latest_height().await?;
+    if expiry_height != 0 && latest_height >= expiry_height {
+        anyhow::bail!("Transaction expired");
+    }
+    broadcast_tx(&raw_tx).await
+}
+
+/// Forget a pending transaction and release its notes
+///
+/// If the transaction is mined after all, sync marks the notes as spent again
+pub fn abandon_pending_tx(id_tx: u32) -> anyhow::Result<()> {
+    let c = CoinConfig::get_active();
+    c.db()?.abandon_pending_tx(id_tx)?;
+    Ok(())
 }
 
 pub async fn shield_taddr() -> anyhow::Result<String> {
     let last_height = get_latest_height().await?;
-    let tx_id = build_sign_send_multi_payment(last_height, &[], true, 0, Box::new(|_| {})).await?;
+    let tx_id = build_sign_send_multi_payment(
+        last_height,
+        &[],
+        true,
+        0,
+        NoteSelector::default(),
+        false,
+        Box::new(|_| {}),
+    )
+    .await?;
     Ok(tx_id)
 }
 
@@ -173,6 +321,12 @@ pub struct Recipient {
     pub subject: String,
     pub memo: String,
     pub max_amount_per_note: u64,
+    /// Send the whole spendable balance minus the other recipients and the fee
+    #[serde(default)]
+    pub max: bool,
+    /// Pay a share of the fee when the fee is deducted from the recipients
+    #[serde(default)]
+    pub pays_fee: bool,
 }
 
 pub struct RecipientMemo {
@@ -180,6 +334,8 @@ pub struct RecipientMemo {
     pub amount: u64,
     pub memo: Memo,
     pub max_amount_per_note: u64,
+    pub max: bool,
+    pub pays_fee: bool,
 }
 
 impl RecipientMemo {
@@ -194,6 +350,8 @@ impl RecipientMemo {
             amount: r.amount,
             memo: Memo::from_str(&memo).unwrap(),
             max_amount_per_note: r.max_amount_per_note,
+            max: r.max,
+            pays_fee: r.pays_fee,
         }
     }
 }
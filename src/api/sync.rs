@@ -2,56 +2,411 @@
 
 use crate::coinconfig::CoinConfig;
 use crate::scan::AMProgressCallback;
-use crate::{BlockId, CTree, CompactTxStreamerClient, DbAdapter};
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+pub use crate::scan::{PhaseTimings, SyncControl, SyncPhase, SyncProgress};
+use crate::servers::with_failover;
+use crate::transaction::retrieve_tx_info;
+use crate::{
+    connect_lightwalletd_with, BlockId, ChainError, CompactTxStreamerClient, DbAdapter,
+    ServerReport, ServerStatus,
+};
+use anyhow::anyhow;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
 use tonic::transport::Channel;
-use tonic::Request;
+use zcash_primitives::consensus::{NetworkUpgrade, Parameters};
 
 const DEFAULT_CHUNK_SIZE: u32 = 100_000;
+const TX_DETAILS_BATCH_SIZE: usize = 100;
 
+lazy_static! {
+    static ref SYNC_HANDLES: StdMutex<HashMap<u8, SyncHandle>> = StdMutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum SyncStatus {
+    Running,
+    Paused,
+    Canceled,
+    Completed,
+    Failed(String),
+}
+
+impl SyncStatus {
+    fn is_active(&self) -> bool {
+        matches!(self, SyncStatus::Running | SyncStatus::Paused)
+    }
+}
+
+struct SyncState {
+    control: Arc<SyncControl>,
+    status_tx: watch::Sender<SyncStatus>,
+    status_rx: watch::Receiver<SyncStatus>,
+    progress: StdMutex<Option<SyncProgress>>,
+    task: Mutex<Option<JoinHandle<anyhow::Result<()>>>>,
+}
+
+/// Controls the sync of a coin started by [start_sync]
+#[derive(Clone)]
+pub struct SyncHandle {
+    coin: u8,
+    state: Arc<SyncState>,
+}
+
+impl SyncHandle {
+    pub fn coin(&self) -> u8 {
+        self.coin
+    }
+
+    pub fn cancel(&self) {
+        log::info!("Sync canceled");
+        self.state.control.cancel();
+        self.state.control.resume();
+    }
+
+    pub fn pause(&self) {
+        if self.status() == SyncStatus::Running {
+            self.state.control.pause();
+            let _ = self.state.status_tx.send(SyncStatus::Paused);
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.status() == SyncStatus::Paused {
+            self.state.control.resume();
+            let _ = self.state.status_tx.send(SyncStatus::Running);
+        }
+    }
+
+    pub fn status(&self) -> SyncStatus {
+        self.state.status_rx.borrow().clone()
+    }
+
+    /// Latest progress reported by the sync
+    pub fn progress(&self) -> Option<SyncProgress> {
+        self.state.progress.lock().unwrap().clone()
+    }
+
+    /// Wait for the sync to finish
+    ///
+    /// The first caller gets the error that stopped the sync, other callers
+    /// get its message
+    pub async fn wait(&self) -> anyhow::Result<()> {
+        let task = self.state.task.lock().await.take();
+        if let Some(task) = task {
+            return task.await?;
+        }
+        let mut status_rx = self.state.status_rx.clone();
+        loop {
+            let status = status_rx.borrow().clone();
+            match status {
+                SyncStatus::Completed | SyncStatus::Canceled => return Ok(()),
+                SyncStatus::Failed(err) => anyhow::bail!(err),
+                _ => {}
+            }
+            status_rx.changed().await?;
+        }
+    }
+}
+
+/// Start syncing a coin in the background
+///
+/// Each coin has at most one sync running. Must be called from a tokio runtime
+pub fn start_sync(
+    coin: u8,
+    get_tx: bool,
+    anchor_offset: u32,
+    progress_callback: impl Fn(SyncProgress) + Send + 'static,
+) -> anyhow::Result<SyncHandle> {
+    start_task(coin, progress_callback, move |callback, control| {
+        coin_sync(coin, get_tx, anchor_offset, callback, control)
+    })
+}
+
+/// Rescan the blocks from `from_height` (or the account birthday) for a single account
+///
+/// It runs in place of the sync of the coin and is controlled by the same handle
+pub fn start_rescan(
+    coin: u8,
+    id_account: u32,
+    from_height: Option<u32>,
+    progress_callback: impl Fn(SyncProgress) + Send + 'static,
+) -> anyhow::Result<SyncHandle> {
+    let c = CoinConfig::get(coin);
+    let from_height = match from_height {
+        Some(height) => height,
+        None => c
+            .db()?
+            .get_birthday(id_account)?
+            .ok_or_else(|| anyhow!("Account has no birthday"))?,
+    };
+    start_task(
+        coin,
+        progress_callback,
+        move |callback, control| async move {
+            let cb: AMProgressCallback = Arc::new(Mutex::new(move |p| callback(p)));
+            crate::scan::rescan_account(
+                c.coin_type,
+                true,
+                c.db_path.as_ref().unwrap(),
+                id_account,
+                from_height,
+                cb,
+                control,
+                c.block_cache.clone(),
+                c.lwd_url.as_ref().unwrap(),
+                &c.connection_settings,
+            )
+            .await
+        },
+    )
+}
+
+fn start_task<F, R>(
+    coin: u8,
+    progress_callback: impl Fn(SyncProgress) + Send + 'static,
+    run: F,
+) -> anyhow::Result<SyncHandle>
+where
+    F: FnOnce(Box<dyn Fn(SyncProgress) + Send>, Arc<SyncControl>) -> R,
+    R: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut handles = SYNC_HANDLES.lock().unwrap();
+    if let Some(handle) = handles.get(&coin) {
+        if handle.status().is_active() {
+            anyhow::bail!(ChainError::Busy);
+        }
+    }
+    let (status_tx, status_rx) = watch::channel(SyncStatus::Running);
+    let state = Arc::new(SyncState {
+        control: Arc::new(SyncControl::default()),
+        status_tx,
+        status_rx,
+        progress: StdMutex::new(None),
+        task: Mutex::new(None),
+    });
+    let state2 = state.clone();
+    let callback = Box::new(move |progress: SyncProgress| {
+        *state2.progress.lock().unwrap() = Some(progress.clone());
+        progress_callback(progress);
+    });
+    let future = run(callback, state.control.clone());
+    let state2 = state.clone();
+    let task = tokio::spawn(async move {
+        let res = future.await;
+        let status = match &res {
+            Ok(_) if state2.control.is_canceled() => SyncStatus::Canceled,
+            Ok(_) => SyncStatus::Completed,
+            Err(err) => SyncStatus::Failed(err.to_string()),
+        };
+        let _ = state2.status_tx.send(status);
+        res
+    });
+    *state.task.try_lock().unwrap() = Some(task);
+    let handle = SyncHandle { coin, state };
+    handles.insert(coin, handle.clone());
+    Ok(handle)
+}
+
+/// Handle of the current or last sync of a coin
+pub fn get_sync_handle(coin: u8) -> Option<SyncHandle> {
+    SYNC_HANDLES.lock().unwrap().get(&coin).cloned()
+}
+
+/// Sync a coin, reporting the phase, heights and speed of the scan to `progress_callback`
+///
+/// Prefer [start_sync], which can run several coins at once and control them
 pub async fn coin_sync(
     coin: u8,
     get_tx: bool,
     anchor_offset: u32,
-    progress_callback: impl Fn(u32) + Send + 'static,
-    cancel: &'static AtomicBool,
+    progress_callback: impl Fn(SyncProgress) + Send + 'static,
+    control: Arc<SyncControl>,
 ) -> anyhow::Result<()> {
     let cb = Arc::new(Mutex::new(progress_callback));
+    let servers = sync_servers(coin).await?;
     coin_sync_impl(
         coin,
         get_tx,
         DEFAULT_CHUNK_SIZE,
         anchor_offset,
         cb.clone(),
-        cancel,
+        control.clone(),
+        &servers,
     )
     .await?;
-    coin_sync_impl(coin, get_tx, DEFAULT_CHUNK_SIZE, 0, cb.clone(), cancel).await?;
+    if control.is_canceled() {
+        return Ok(());
+    }
+    let url = coin_sync_impl(
+        coin,
+        get_tx,
+        DEFAULT_CHUNK_SIZE,
+        0,
+        cb.clone(),
+        control.clone(),
+        &servers,
+    )
+    .await?;
+    if control.is_canceled() {
+        return Ok(());
+    }
+    if get_tx {
+        retrieve_pending_tx_details(coin, &url, true).await?;
+    }
+    sync_transparent(coin, &url).await?;
     Ok(())
 }
 
+/// Compare the chains of the servers of a coin
+pub async fn check_coin_servers(coin: u8) -> anyhow::Result<Vec<ServerReport>> {
+    let c = CoinConfig::get(coin);
+    let servers = c.servers();
+    if servers.is_empty() {
+        anyhow::bail!("LWD URL Not set");
+    }
+    let activation_height = c
+        .chain
+        .network()
+        .activation_height(NetworkUpgrade::Sapling)
+        .unwrap();
+    let reports = crate::check_servers(
+        &servers,
+        &c.connection_settings,
+        u32::from(activation_height),
+        c.server_check_samples,
+    )
+    .await;
+    Ok(reports)
+}
+
+/// Servers to sync from, in order of preference: the lwd url alone, or
+/// the servers of the coin that agree on the chain
+async fn sync_servers(coin: u8) -> anyhow::Result<Vec<String>> {
+    let c = CoinConfig::get(coin);
+    if c.lwd_servers.is_empty() {
+        let lwd_url = c.lwd_url.ok_or_else(|| anyhow!("LWD URL Not set"))?;
+        return Ok(vec![lwd_url]);
+    }
+    let servers: Vec<_> = check_coin_servers(coin)
+        .await?
+        .into_iter()
+        .filter(|r| r.status == ServerStatus::Agree)
+        .map(|r| r.url)
+        .collect();
+    if servers.is_empty() {
+        anyhow::bail!(ChainError::NoConsensus);
+    }
+    Ok(servers)
+}
+
+/// Retrieve the memo and address of the transactions that miss them,
+/// for example after syncing with `get_tx` off
+///
+/// Returns the number of transactions whose details are still missing
+pub async fn backfill_tx_details() -> anyhow::Result<u32> {
+    let coin = CoinConfig::get_active().coin;
+    let servers = sync_servers(coin).await?;
+    // the details are stored by batch, so the next server only gets those still missing
+    let (_, pending) = with_failover(&servers, |url| async move {
+        retrieve_pending_tx_details(coin, &url, false).await
+    })
+    .await?;
+    Ok(pending)
+}
+
+/// Retrieve the details of the pending transactions, only those due for a retry
+/// if `due_only` is set
+async fn retrieve_pending_tx_details(coin: u8, url: &str, due_only: bool) -> anyhow::Result<u32> {
+    let c = CoinConfig::get(coin);
+    let db_path = c.db_path.as_ref().unwrap();
+    let ids = {
+        let db = DbAdapter::new(c.coin_type, db_path)?;
+        let time = if due_only {
+            Some(Utc::now().timestamp() as u32)
+        } else {
+            None
+        };
+        db.get_pending_tx_details(time)?
+    };
+    if !ids.is_empty() {
+        log::info!("Retrieving details of {} transactions", ids.len());
+        let mut client = connect_lightwalletd_with(url, &c.connection_settings).await?;
+        for ids in ids.chunks(TX_DETAILS_BATCH_SIZE) {
+            retrieve_tx_info(c.coin_type, &mut client, db_path, ids).await?;
+        }
+    }
+    let db = DbAdapter::new(c.coin_type, db_path)?;
+    let pending = db.get_pending_tx_details(None)?.len();
+    Ok(pending as u32)
+}
+
+/// Update the transparent transactions and utxos up to the synced height
+async fn sync_transparent(coin: u8, url: &str) -> anyhow::Result<()> {
+    let c = CoinConfig::get(coin);
+    let mut client = connect_lightwalletd_with(url, &c.connection_settings).await?;
+    let mut db = DbAdapter::new(c.coin_type, c.db_path.as_ref().unwrap())?;
+    let height = db.get_db_height()?;
+    crate::taddr::sync_transparent(c.chain.network(), &mut client, &mut db, height).await?;
+    Ok(())
+}
+
+/// Sync from the first server that works and return it
 async fn coin_sync_impl(
     coin: u8,
     get_tx: bool,
     chunk_size: u32,
     target_height_offset: u32,
     progress_callback: AMProgressCallback,
-    cancel: &'static AtomicBool,
-) -> anyhow::Result<()> {
+    control: Arc<SyncControl>,
+    servers: &[String],
+) -> anyhow::Result<String> {
     let c = CoinConfig::get(coin);
-    crate::scan::sync_async(
-        c.coin_type,
-        chunk_size,
-        get_tx,
-        c.db_path.as_ref().unwrap(),
-        target_height_offset,
-        progress_callback,
-        cancel,
-        c.lwd_url.as_ref().unwrap(),
-    )
+    // blocks are committed by chunk, so the next server resumes where the
+    // failed one stopped
+    let (url, _) = with_failover(servers, |url| {
+        let c = c.clone();
+        let progress_callback = progress_callback.clone();
+        let control = control.clone();
+        async move {
+            crate::scan::sync_async(
+                c.coin_type,
+                chunk_size,
+                get_tx,
+                c.db_path.as_ref().unwrap(),
+                target_height_offset,
+                progress_callback,
+                control,
+                c.block_cache.clone(),
+                c.tree_check_interval,
+                &url,
+                &c.connection_settings,
+            )
+            .await
+        }
+    })
     .await?;
+    let mut db = DbAdapter::new(c.coin_type, c.db_path.as_ref().unwrap())?;
+    let height = db.get_db_height()?;
+    let retention = c.witness_retention;
+    db.purge_old_witnesses(
+        height.saturating_sub(retention.recent_blocks),
+        retention.checkpoint_interval,
+    )?;
+    Ok(url)
+}
+
+/// Delete the blocks kept in the block cache of a coin
+pub fn clear_block_cache(coin: u8) -> anyhow::Result<()> {
+    let c = CoinConfig::get(coin);
+    if let Some(block_cache) = &c.block_cache {
+        block_cache.clear()?;
+    }
     Ok(())
 }
 
@@ -76,11 +431,31 @@ pub async fn skip_to_last_height(coin: u8) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Rewind to `height`, from a local checkpoint if there is one.
+/// Otherwise, the tree state is fetched from the server and the witnesses of
+/// the existing notes are lost
 pub async fn rewind_to_height(height: u32) -> anyhow::Result<()> {
     let c = CoinConfig::get_active();
-    let mut client = c.connect_lwd().await?;
-    c.db()?.trim_to_height(height)?;
-    fetch_and_store_tree_state(c.coin, &mut client, height).await?;
+    if let Some(handle) = get_sync_handle(c.coin) {
+        if handle.status().is_active() {
+            anyhow::bail!(ChainError::Busy);
+        }
+    }
+    let rewound = crate::scan::rewind_to_checkpoint(
+        c.coin_type,
+        true,
+        c.db_path.as_ref().unwrap(),
+        height,
+        c.block_cache.clone(),
+        c.lwd_url.as_ref().unwrap(),
+        &c.connection_settings,
+    )
+    .await?;
+    if !rewound {
+        let mut client = c.connect_lwd().await?;
+        c.db()?.trim_to_height(height)?;
+        fetch_and_store_tree_state(c.coin, &mut client, height).await?;
+    }
     Ok(())
 }
 
@@ -94,26 +469,47 @@ async fn fetch_and_store_tree_state(
         height: height as u64,
         hash: vec![],
     };
-    let block = client.get_block(block_id.clone()).await?.into_inner();
-    let tree_state = client
-        .get_tree_state(Request::new(block_id))
-        .await?
-        .into_inner();
-    let tree = CTree::read(&*hex::decode(&tree_state.sapling_tree)?)?;
+    let block = client.get_block(block_id).await?.into_inner();
+    let (tree, orchard_tree) = crate::chain::get_tree_states(client, height).await?;
     let db = c.db()?;
-    DbAdapter::store_block(&db.connection, height, &block.hash, block.time, &tree)?;
+    DbAdapter::store_block(
+        &db.connection,
+        height,
+        &block.hash,
+        block.time,
+        &tree,
+        &orchard_tree,
+    )?;
     Ok(())
 }
 
+/// Time of the Sapling activation block, from the database if it was fetched before
 pub async fn get_activation_date() -> anyhow::Result<u32> {
     let c = CoinConfig::get_active();
+    let height = u32::from(
+        c.chain
+            .network()
+            .activation_height(NetworkUpgrade::Sapling)
+            .unwrap(),
+    );
+    let time = c.db()?.get_block_time(height)?;
+    if let Some(time) = time {
+        return Ok(time);
+    }
     let mut client = c.connect_lwd().await?;
     let date_time = crate::chain::get_activation_date(c.chain.network(), &mut client).await?;
+    DbAdapter::store_block_times(&c.db()?.connection, &[(height, date_time)])?;
     Ok(date_time)
 }
 
+/// Height of the block mined at `time`, estimated from the block times recorded
+/// during sync when possible
 pub async fn get_block_by_time(time: u32) -> anyhow::Result<u32> {
     let c = CoinConfig::get_active();
+    let height = c.db()?.get_height_by_time(time)?;
+    if let Some(height) = height {
+        return Ok(height);
+    }
     let mut client = c.connect_lwd().await?;
     let date_time = crate::chain::get_block_by_time(c.chain.network(), &mut client, time).await?;
     Ok(date_time)
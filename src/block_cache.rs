@@ -0,0 +1,242 @@
+use crate::CompactBlock;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use prost::Message;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of blocks per cache file
+pub const SEGMENT_SIZE: u32 = 1_000;
+
+/*
+On-disk cache of compact blocks.
+
+Blocks are grouped by segments of SEGMENT_SIZE consecutive heights and each
+segment is stored in its own file as a sequence of length prefixed protobuf
+messages. Only complete segments are written, so a segment file is either
+absent or holds every block of its range. A segment is written as soon as it is
+complete, even near the tip, so it may hold blocks that get reorged out: sync
+calls `truncate` from the fork height when it rewinds the database.
+
+When the cache grows over `max_size` bytes, the segments with the lowest heights
+are removed first.
+ */
+pub struct BlockCache {
+    dir: PathBuf,
+    max_size: u64,
+    pending: Mutex<Vec<CompactBlock>>,
+}
+
+impl BlockCache {
+    /// `max_size` is in bytes. 0 means unlimited
+    pub fn new(dir: &str, max_size: u64) -> anyhow::Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+        Ok(BlockCache {
+            dir,
+            max_size,
+            pending: Mutex::new(vec![]),
+        })
+    }
+
+    fn segment_path(&self, segment: u32) -> PathBuf {
+        self.dir.join(format!("blocks-{:08}.bin", segment))
+    }
+
+    pub fn has_segment(&self, height: u32) -> bool {
+        self.segment_path(height / SEGMENT_SIZE).exists()
+    }
+
+    /// Returns the blocks of the segment that contains `height`,
+    /// from `height` to the end of the segment
+    pub fn get_blocks_from(&self, height: u32) -> anyhow::Result<Option<Vec<CompactBlock>>> {
+        let path = self.segment_path(height / SEGMENT_SIZE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let blocks = match read_segment(BufReader::new(file)) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                log::warn!("Dropping corrupted block cache file {:?}: {}", path, e);
+                let _ = fs::remove_file(&path);
+                return Ok(None);
+            }
+        };
+        let blocks = blocks
+            .into_iter()
+            .filter(|b| b.height >= height as u64)
+            .collect();
+        Ok(Some(blocks))
+    }
+
+    /// Returns the start height of the first cached segment in (height, end_height]
+    pub fn next_cached_height(&self, height: u32, end_height: u32) -> Option<u32> {
+        let mut segment = height / SEGMENT_SIZE + 1;
+        while segment * SEGMENT_SIZE <= end_height {
+            if self.segment_path(segment).exists() {
+                return Some(segment * SEGMENT_SIZE);
+            }
+            segment += 1;
+        }
+        None
+    }
+
+    /// Add a downloaded block. Blocks must be added in height order
+    ///
+    /// The segment is written once its last block is added
+    pub fn add_block(&self, block: &CompactBlock) -> anyhow::Result<()> {
+        let height = block.height as u32;
+        let mut pending = self.pending.lock().unwrap();
+        let expected = pending.last().map(|b| b.height as u32 + 1);
+        if height % SEGMENT_SIZE == 0 || expected != Some(height) {
+            pending.clear();
+        }
+        if !pending.is_empty() || height % SEGMENT_SIZE == 0 {
+            pending.push(block.clone());
+        }
+        if pending.len() == SEGMENT_SIZE as usize {
+            self.write_segment(height / SEGMENT_SIZE, &pending)?;
+            pending.clear();
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: u32, blocks: &[CompactBlock]) -> anyhow::Result<()> {
+        let path = self.segment_path(segment);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for b in blocks.iter() {
+                let data = b.encode_to_vec();
+                writer.write_u32::<LittleEndian>(data.len() as u32)?;
+                writer.write_all(&data)?;
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn segments(&self) -> anyhow::Result<Vec<(u32, PathBuf, u64)>> {
+        let mut segments = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if let Some(segment) = parse_segment_name(&path) {
+                segments.push((segment, path, entry.metadata()?.len()));
+            }
+        }
+        segments.sort_by_key(|s| s.0);
+        Ok(segments)
+    }
+
+    fn prune(&self) -> anyhow::Result<()> {
+        if self.max_size == 0 {
+            return Ok(());
+        }
+        let segments = self.segments()?;
+        let mut size: u64 = segments.iter().map(|s| s.2).sum();
+        for (_, path, len) in segments.iter() {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(path)?;
+            size -= len;
+        }
+        Ok(())
+    }
+
+    /// Remove the segments that have blocks at or above `height`
+    pub fn truncate(&self, height: u32) -> anyhow::Result<()> {
+        self.pending.lock().unwrap().clear();
+        for (segment, path, _) in self.segments()? {
+            if (segment + 1) * SEGMENT_SIZE > height {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        self.truncate(0)
+    }
+
+    /// Total size of the cache files in bytes
+    pub fn size(&self) -> anyhow::Result<u64> {
+        Ok(self.segments()?.iter().map(|s| s.2).sum())
+    }
+}
+
+fn parse_segment_name(path: &Path) -> Option<u32> {
+    if path.extension()? != "bin" {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?;
+    name.strip_prefix("blocks-")?.parse().ok()
+}
+
+fn read_segment<R: Read>(mut reader: R) -> anyhow::Result<Vec<CompactBlock>> {
+    let mut blocks = vec![];
+    loop {
+        let len = match reader.read_u32::<LittleEndian>() {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        let mut data = vec![0u8; len as usize];
+        reader.read_exact(&mut data)?;
+        blocks.push(CompactBlock::decode(&*data)?);
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block_cache::{BlockCache, SEGMENT_SIZE};
+    use crate::CompactBlock;
+
+    fn block(height: u32) -> CompactBlock {
+        CompactBlock {
+            height: height as u64,
+            hash: vec![height as u8; 32],
+            ..CompactBlock::default()
+        }
+    }
+
+    #[test]
+    fn test_block_cache() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("block_cache_{}", std::process::id()));
+        let cache = BlockCache::new(dir.to_str().unwrap(), 0)?;
+
+        // incomplete segments are not stored
+        for h in SEGMENT_SIZE + 10..2 * SEGMENT_SIZE {
+            cache.add_block(&block(h))?;
+        }
+        assert!(!cache.has_segment(SEGMENT_SIZE));
+
+        for h in 2 * SEGMENT_SIZE..3 * SEGMENT_SIZE + 10 {
+            cache.add_block(&block(h))?;
+        }
+        assert!(cache.has_segment(2 * SEGMENT_SIZE));
+        assert!(!cache.has_segment(3 * SEGMENT_SIZE));
+        assert_eq!(
+            cache.next_cached_height(0, 5 * SEGMENT_SIZE),
+            Some(2 * SEGMENT_SIZE)
+        );
+        assert_eq!(cache.next_cached_height(0, 2 * SEGMENT_SIZE - 1), None);
+
+        let blocks = cache.get_blocks_from(2 * SEGMENT_SIZE + 500)?.unwrap();
+        assert_eq!(blocks.len(), 500);
+        assert_eq!(blocks[0], block(2 * SEGMENT_SIZE + 500));
+
+        cache.truncate(3 * SEGMENT_SIZE - 1)?;
+        assert!(!cache.has_segment(2 * SEGMENT_SIZE));
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
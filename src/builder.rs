@@ -1,10 +1,12 @@
-use crate::commitment::{CTree, Witness};
+use crate::commitment::{CTree, OrchardTree, OrchardWitness, Witness};
 use crate::hash::{pedersen_hash, pedersen_hash_inner};
 use ff::PrimeField;
 use group::Curve;
 use jubjub::{AffinePoint, ExtendedPoint};
+use orchard::tree::MerkleHashOrchard;
 use rayon::prelude::IntoParallelIterator;
 use rayon::prelude::*;
+use zcash_primitives::merkle_tree::IncrementalWitness;
 use zcash_primitives::sapling::Node;
 
 #[inline(always)]
@@ -396,6 +398,39 @@ impl BlockProcessor {
     }
 }
 
+pub struct OrchardBlockProcessor {
+    tree: OrchardTree,
+    witnesses: Vec<OrchardWitness>,
+}
+
+impl OrchardBlockProcessor {
+    pub fn new(prev_tree: &OrchardTree, prev_witnesses: &[OrchardWitness]) -> Self {
+        OrchardBlockProcessor {
+            tree: prev_tree.clone(),
+            witnesses: prev_witnesses.to_vec(),
+        }
+    }
+
+    /// Append a note commitment and start tracking a witness for it
+    /// if it belongs to one of our notes
+    pub fn add_node(&mut self, node: MerkleHashOrchard, id_note: Option<u32>) {
+        self.tree.append(node).unwrap();
+        for w in self.witnesses.iter_mut() {
+            w.witness.append(node).unwrap();
+        }
+        if let Some(id_note) = id_note {
+            self.witnesses.push(OrchardWitness {
+                id_note,
+                witness: IncrementalWitness::from_tree(&self.tree),
+            });
+        }
+    }
+
+    pub fn finalize(self) -> (OrchardTree, Vec<OrchardWitness>) {
+        (self.tree, self.witnesses)
+    }
+}
+
 #[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
@@ -1,23 +1,28 @@
 use crate::advance_tree;
-use crate::commitment::{CTree, Witness};
+use crate::block_cache::BlockCache;
+use crate::commitment::{CTree, OrchardTree, Witness};
 use crate::db::AccountViewKey;
 use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
 use crate::lw_rpc::*;
-use crate::scan::{Blocks, MAX_OUTPUTS_PER_CHUNK};
+use crate::proxy::Proxy;
+use crate::scan::{Blocks, SyncControl, MAX_OUTPUTS_PER_CHUNK};
+use crate::tls::TlsSettings;
 use ff::PrimeField;
-use futures::{future, FutureExt};
 use log::info;
+use orchard::keys::{FullViewingKey, IncomingViewingKey, Scope};
+use orchard::note::{ExtractedNoteCommitment, Nullifier};
+use orchard::note_encryption::{CompactAction, OrchardDomain};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::mpsc::Sender;
 use tokio::time::timeout;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Uri};
 use tonic::Request;
+use tower::service_fn;
 use zcash_note_encryption::batch::try_compact_note_decryption;
 use zcash_note_encryption::{Domain, EphemeralKeyBytes, ShieldedOutput, COMPACT_NOTE_SIZE};
 use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
@@ -59,6 +64,28 @@ pub async fn get_block_date(
     Ok(block.time)
 }
 
+pub async fn get_block_hash(
+    client: &mut CompactTxStreamerClient<Channel>,
+    height: u32,
+) -> anyhow::Result<[u8; 32]> {
+    let block = client
+        .get_block(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        }))
+        .await?
+        .into_inner();
+    let mut hash = [0u8; 32];
+    if block.hash.len() != 32 {
+        anyhow::bail!(ChainError::InvalidData {
+            height,
+            reason: "invalid block hash".to_string(),
+        });
+    }
+    hash.copy_from_slice(&block.hash);
+    Ok(hash)
+}
+
 pub async fn get_block_by_time(
     network: &Network,
     client: &mut CompactTxStreamerClient<Channel>,
@@ -93,66 +120,214 @@ pub enum ChainError {
     Reorg,
     #[error("Synchronizer busy")]
     Busy,
+    #[error("Commitment tree mismatch at height {height}")]
+    TreeMismatch { height: u32 },
+    #[error("Lightwalletd servers disagree on the chain")]
+    NoConsensus,
+    #[error("Invalid block {height}: {reason}")]
+    InvalidData { height: u32, reason: String },
+}
+
+/// Upper bound of the number of outputs and actions of a block.
+/// A block has at most 2 MB and an Orchard action takes more than 800 bytes
+pub const MAX_OUTPUTS_PER_BLOCK: usize = 2_000_000 / 800;
+
+fn invalid_data(height: u32, reason: &str) -> anyhow::Error {
+    anyhow::anyhow!(ChainError::InvalidData {
+        height,
+        reason: reason.to_string(),
+    })
 }
 
+/// Check that the fields of a compact transaction from the server have the
+/// sizes the scanner expects. `height` is only used in the error
+pub fn check_compact_tx(tx: &CompactTx, height: u32) -> anyhow::Result<()> {
+    if tx.spends.iter().any(|cs| cs.nf.len() != 32) {
+        return Err(invalid_data(height, "invalid nullifier"));
+    }
+    for co in tx.outputs.iter() {
+        if co.cmu.len() != 32 || co.epk.len() != 32 {
+            return Err(invalid_data(height, "invalid output"));
+        }
+        if co.ciphertext.len() != COMPACT_NOTE_SIZE {
+            return Err(invalid_data(height, "invalid output ciphertext"));
+        }
+    }
+    for ca in tx.actions.iter() {
+        if ca.nullifier.len() != 32 || ca.cmx.len() != 32 || ca.ephemeral_key.len() != 32 {
+            return Err(invalid_data(height, "invalid action"));
+        }
+        if ca.ciphertext.len() != COMPACT_NOTE_SIZE {
+            return Err(invalid_data(height, "invalid action ciphertext"));
+        }
+    }
+    if tx.outputs.len() + tx.actions.len() > MAX_OUTPUTS_PER_BLOCK {
+        return Err(invalid_data(height, "too many outputs"));
+    }
+    Ok(())
+}
+
+/// Check that a compact block from the server is at `height` and that its
+/// fields have the sizes the scanner expects
+pub fn check_compact_block(block: &CompactBlock, height: u32) -> anyhow::Result<()> {
+    if block.height != height as u64 {
+        return Err(invalid_data(
+            height,
+            &format!("unexpected height {}", block.height),
+        ));
+    }
+    if block.hash.len() != 32 || block.prev_hash.len() != 32 {
+        return Err(invalid_data(height, "invalid block hash"));
+    }
+    let mut output_count = 0;
+    for tx in block.vtx.iter() {
+        check_compact_tx(tx, height)?;
+        output_count += tx.outputs.len() + tx.actions.len();
+    }
+    if output_count > MAX_OUTPUTS_PER_BLOCK {
+        return Err(invalid_data(height, "too many outputs"));
+    }
+    Ok(())
+}
+
+/// Groups blocks into chunks for the processor and checks that they are valid
+/// and link up
+struct BlockChunker {
+    /// Height of the next block
+    height: u32,
+    prev_hash: Option<[u8; 32]>,
+    cbs: Vec<CompactBlock>,
+    output_count: usize,
+    blocks_tx: Sender<Blocks>,
+}
+
+impl BlockChunker {
+    fn check(&self, block: &CompactBlock) -> anyhow::Result<()> {
+        check_compact_block(block, self.height)
+    }
+
+    async fn push(&mut self, block: CompactBlock) -> anyhow::Result<()> {
+        self.check(&block)?;
+        if let Some(prev_hash) = self.prev_hash {
+            if block.prev_hash.as_slice() != prev_hash {
+                log::warn!(
+                    "Reorg: {} != {}",
+                    hex::encode(block.prev_hash.as_slice()),
+                    hex::encode(prev_hash)
+                );
+                anyhow::bail!(ChainError::Reorg);
+            }
+        }
+        let mut ph = [0u8; 32];
+        ph.copy_from_slice(&block.hash);
+        self.prev_hash = Some(ph);
+        self.height += 1;
+
+        let block_output_count: usize = block
+            .vtx
+            .iter()
+            .map(|tx| tx.outputs.len() + tx.actions.len())
+            .sum();
+        if self.output_count + block_output_count > MAX_OUTPUTS_PER_CHUNK {
+            // output
+            let out = std::mem::take(&mut self.cbs);
+            self.blocks_tx.send(Blocks(out)).await.unwrap();
+            self.output_count = 0;
+        }
+
+        self.cbs.push(block);
+        self.output_count += block_output_count;
+        Ok(())
+    }
+
+    async fn flush(self) {
+        let _ = self.blocks_tx.send(Blocks(self.cbs)).await;
+    }
+}
+
+/// Longest wait for the next block of a range before giving up on the server
+const BLOCK_STREAM_TIMEOUT: Duration = Duration::from_secs(60);
+
 /* download [start_height+1, end_height] inclusive */
+// Blocks found in the cache are not downloaded again
 pub async fn download_chain(
     client: &mut CompactTxStreamerClient<Channel>,
     start_height: u32,
     end_height: u32,
-    mut prev_hash: Option<[u8; 32]>,
+    prev_hash: Option<[u8; 32]>,
     blocks_tx: Sender<Blocks>,
-    cancel: &'static AtomicBool,
+    control: &SyncControl,
+    cache: Option<&BlockCache>,
 ) -> anyhow::Result<()> {
-    let mut output_count = 0;
-    let mut cbs: Vec<CompactBlock> = Vec::new();
-    let range = BlockRange {
-        start: Some(BlockId {
-            height: (start_height + 1) as u64,
-            hash: vec![],
-        }),
-        end: Some(BlockId {
-            height: end_height as u64,
-            hash: vec![],
-        }),
+    let mut chunker = BlockChunker {
+        height: start_height + 1,
+        prev_hash,
+        cbs: Vec::new(),
+        output_count: 0,
+        blocks_tx,
     };
-    let mut block_stream = client
-        .get_block_range(Request::new(range))
-        .await?
-        .into_inner();
-    while let Some(mut block) = block_stream.message().await? {
-        if cancel.load(Ordering::Acquire) {
-            log::info!("Canceling download");
-            break;
-        }
-        if prev_hash.is_some() && block.prev_hash.as_slice() != prev_hash.unwrap() {
-            log::warn!(
-                "Reorg: {} != {}",
-                hex::encode(block.prev_hash.as_slice()),
-                hex::encode(prev_hash.unwrap())
-            );
-            anyhow::bail!(ChainError::Reorg);
-        }
-        let mut ph = [0u8; 32];
-        ph.copy_from_slice(&block.hash);
-        prev_hash = Some(ph);
-        for b in block.vtx.iter_mut() {
-            b.actions.clear(); // don't need Orchard actions
+    let mut height = start_height + 1;
+    'ranges: while height <= end_height {
+        if let Some(cache) = cache {
+            if let Some(blocks) = cache.get_blocks_from(height)? {
+                log::info!("Reading blocks from cache at {}", height);
+                let from_height = height;
+                for block in blocks {
+                    if block.height as u32 > end_height {
+                        break;
+                    }
+                    if !control.proceed().await {
+                        log::info!("Canceling download");
+                        break 'ranges;
+                    }
+                    height = block.height as u32 + 1;
+                    chunker.push(block).await?;
+                }
+                if height > from_height {
+                    continue;
+                }
+            }
         }
 
-        let block_output_count: usize = block.vtx.iter().map(|tx| tx.outputs.len()).sum();
-        if output_count + block_output_count > MAX_OUTPUTS_PER_CHUNK {
-            // output
-            let out = cbs;
-            cbs = Vec::new();
-            blocks_tx.send(Blocks(out)).await.unwrap();
-            output_count = 0;
+        let range_end = cache
+            .and_then(|c| c.next_cached_height(height, end_height))
+            .map(|h| h - 1)
+            .unwrap_or(end_height);
+        let range = BlockRange {
+            start: Some(BlockId {
+                height: height as u64,
+                hash: vec![],
+            }),
+            end: Some(BlockId {
+                height: range_end as u64,
+                hash: vec![],
+            }),
+        };
+        let mut block_stream = client
+            .get_block_range(Request::new(range))
+            .await?
+            .into_inner();
+        while let Some(block) = timeout(BLOCK_STREAM_TIMEOUT, block_stream.message()).await?? {
+            if !control.proceed().await {
+                log::info!("Canceling download");
+                break 'ranges;
+            }
+            if let Some(cache) = cache {
+                // do not cache bad blocks
+                chunker.check(&block)?;
+                cache.add_block(&block)?;
+            }
+            chunker.push(block).await?;
         }
-
-        cbs.push(block);
-        output_count += block_output_count;
+        if chunker.height != range_end + 1 {
+            anyhow::bail!(ChainError::InvalidData {
+                height: chunker.height,
+                reason: "missing block".to_string(),
+            });
+        }
+        height = range_end + 1;
     }
-    let _ = blocks_tx.send(Blocks(cbs)).await;
+    chunker.flush().await;
     Ok(())
 }
 
@@ -174,6 +349,9 @@ pub struct DecryptedBlock<'a> {
     pub notes: Vec<DecryptedNote>,
     pub count_outputs: u32,
     pub spends: Vec<Nf>,
+    pub orchard_notes: Vec<DecryptedOrchardNote>,
+    pub count_actions: u32,
+    pub orchard_spends: Vec<Nf>,
     pub compact_block: &'a CompactBlock,
     pub elapsed: usize,
 }
@@ -193,6 +371,19 @@ pub struct DecryptedNote {
     pub output_index: usize,
 }
 
+#[derive(Clone)]
+pub struct DecryptedOrchardNote {
+    pub account: u32,
+    pub fvk: FullViewingKey,
+    pub note: orchard::Note,
+    pub position_in_block: usize,
+
+    pub height: u32,
+    pub txid: Vec<u8>,
+    pub tx_index: usize,
+    pub output_index: usize,
+}
+
 pub fn to_output_description(co: &CompactSaplingOutput) -> CompactOutputDescription {
     let mut cmu = [0u8; 32];
     cmu.copy_from_slice(&co.cmu);
@@ -267,6 +458,86 @@ impl<'a, N: Parameters> ShieldedOutput<SaplingDomain<N>, COMPACT_NOTE_SIZE>
     }
 }
 
+fn to_compact_action(ca: &CompactOrchardAction) -> Option<CompactAction> {
+    let mut nf = [0u8; 32];
+    nf.copy_from_slice(&ca.nullifier);
+    let nf = Option::from(Nullifier::from_bytes(&nf))?;
+    let mut cmx = [0u8; 32];
+    cmx.copy_from_slice(&ca.cmx);
+    let cmx = Option::from(ExtractedNoteCommitment::from_bytes(&cmx))?;
+    let mut epk = [0u8; 32];
+    epk.copy_from_slice(&ca.ephemeral_key);
+    let mut enc_ciphertext = [0u8; COMPACT_NOTE_SIZE];
+    enc_ciphertext.copy_from_slice(&ca.ciphertext);
+    Some(CompactAction::from_parts(
+        nf,
+        cmx,
+        EphemeralKeyBytes::from(epk),
+        enc_ciphertext,
+    ))
+}
+
+fn decrypt_orchard_notes(
+    block: &CompactBlock,
+    vks: &[(&u32, &AccountViewKey)],
+) -> (Vec<DecryptedOrchardNote>, Vec<Nf>, u32) {
+    let mut count_actions = 0u32;
+    let mut spends: Vec<Nf> = vec![];
+    let mut notes: Vec<DecryptedOrchardNote> = vec![];
+    // change notes are sent to the internal address
+    let mut ivk_owners: Vec<(u32, &FullViewingKey)> = vec![];
+    let mut ivks: Vec<IncomingViewingKey> = vec![];
+    for (&account, vk) in vks.iter() {
+        if let Some(fvk) = &vk.orchard_fvk {
+            for scope in [Scope::External, Scope::Internal].iter() {
+                ivk_owners.push((account, fvk));
+                ivks.push(fvk.to_ivk(*scope));
+            }
+        }
+    }
+    let mut actions: Vec<(OrchardDomain, CompactAction)> = vec![];
+    let mut positions: Vec<(usize, usize, usize)> = vec![];
+    for (tx_index, vtx) in block.vtx.iter().enumerate() {
+        for (action_index, ca) in vtx.actions.iter().enumerate() {
+            let mut nf = [0u8; 32];
+            nf.copy_from_slice(&ca.nullifier);
+            spends.push(Nf(nf));
+
+            if let Some(action) = to_compact_action(ca) {
+                let domain = OrchardDomain::for_nullifier(action.nullifier());
+                actions.push((domain, action));
+                positions.push((tx_index, action_index, count_actions as usize));
+            } else {
+                log::warn!("Invalid orchard action at {}", block.height);
+            }
+            count_actions += 1;
+        }
+    }
+
+    if !ivks.is_empty() && !actions.is_empty() {
+        let notes_decrypted =
+            try_compact_note_decryption::<OrchardDomain, CompactAction>(&ivks, &actions);
+        for (pos, opt_note) in notes_decrypted.iter().enumerate() {
+            if let Some((note, _address)) = opt_note {
+                let (account, fvk) = ivk_owners[pos / actions.len()];
+                let (tx_index, action_index, position_in_block) = positions[pos % actions.len()];
+                notes.push(DecryptedOrchardNote {
+                    account,
+                    fvk: fvk.clone(),
+                    note: *note,
+                    position_in_block,
+                    height: block.height as u32,
+                    txid: block.vtx[tx_index].hash.clone(),
+                    tx_index,
+                    output_index: action_index,
+                });
+            }
+        }
+    }
+
+    (notes, spends, count_actions)
+}
+
 fn decrypt_notes<'a, N: Parameters>(
     network: &N,
     block: &'a CompactBlock,
@@ -344,11 +615,16 @@ fn decrypt_notes<'a, N: Parameters>(
         }
     }
 
+    let (orchard_notes, orchard_spends, count_actions) = decrypt_orchard_notes(block, vks);
+
     DecryptedBlock {
         height: block.height as u32,
         spends,
         notes,
         count_outputs,
+        orchard_notes,
+        count_actions,
+        orchard_spends,
         compact_block: block,
         elapsed,
     }
@@ -374,6 +650,27 @@ impl DecryptNode {
     }
 }
 
+/// Sapling and Orchard commitment trees after the block at `height`
+pub async fn get_tree_states(
+    client: &mut CompactTxStreamerClient<Channel>,
+    height: u32,
+) -> anyhow::Result<(CTree, OrchardTree)> {
+    let tree_state = client
+        .get_tree_state(Request::new(BlockId {
+            height: height as u64,
+            hash: vec![],
+        }))
+        .await?
+        .into_inner();
+    let tree = CTree::read(&*hex::decode(&tree_state.sapling_tree)?)?;
+    let orchard_tree = if tree_state.orchard_tree.is_empty() {
+        OrchardTree::empty() // before NU5
+    } else {
+        OrchardTree::read(&*hex::decode(&tree_state.orchard_tree)?)?
+    };
+    Ok((tree, orchard_tree))
+}
+
 #[allow(dead_code)]
 async fn get_tree_state(client: &mut CompactTxStreamerClient<Channel>, height: u32) -> String {
     let block_id = BlockId {
@@ -480,38 +777,44 @@ pub fn calculate_tree_state_v2(cbs: &[CompactBlock], blocks: &[DecryptedBlock])
 }
 
 pub async fn connect_lightwalletd(url: &str) -> anyhow::Result<CompactTxStreamerClient<Channel>> {
-    let mut channel = tonic::transport::Channel::from_shared(url.to_owned())?;
-    if url.starts_with("https") {
-        let pem = include_bytes!("ca.pem");
-        let ca = Certificate::from_pem(pem);
-        let tls = ClientTlsConfig::new().ca_certificate(ca);
-        channel = channel.tls_config(tls)?;
-    }
-    let client = CompactTxStreamerClient::connect(channel).await?;
-    Ok(client)
+    connect_lightwalletd_with(url, &ConnectionSettings::default()).await
 }
 
-async fn get_height(server: String) -> Option<(String, u32)> {
-    let mut client = connect_lightwalletd(&server).await.ok()?;
-    let height = get_latest_height(&mut client).await.ok()?;
-    log::info!("{} {}", server, height);
-    Some((server, height))
+/// How to connect to the lightwalletd servers of a coin
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionSettings {
+    pub proxy: Option<Proxy>,
+    /// TLS settings by server URL. Other servers only trust the built-in certificate
+    pub tls: HashMap<String, TlsSettings>,
 }
 
-pub async fn get_best_server(servers: &[String]) -> Option<String> {
-    let mut server_heights = vec![];
-    for s in servers.iter() {
-        let server_height =
-            tokio::spawn(timeout(Duration::from_secs(1), get_height(s.to_string()))).boxed();
-        server_heights.push(server_height);
+/// Connect to a lightwalletd server, through the proxy if there is one
+pub async fn connect_lightwalletd_with(
+    url: &str,
+    settings: &ConnectionSettings,
+) -> anyhow::Result<CompactTxStreamerClient<Channel>> {
+    let mut channel = tonic::transport::Channel::from_shared(url.to_owned())?;
+    if url.starts_with("https") {
+        let tls = match settings.tls.get(url) {
+            Some(tls) => tls.client_config()?,
+            None => {
+                let pem = include_bytes!("ca.pem");
+                let ca = Certificate::from_pem(pem);
+                ClientTlsConfig::new().ca_certificate(ca)
+            }
+        };
+        channel = channel.tls_config(tls)?;
     }
-    let server_heights = future::try_join_all(server_heights).await.ok()?;
-
-    server_heights
-        .into_iter()
-        .filter_map(|h| h.unwrap_or(None))
-        .max_by_key(|(_, h)| *h)
-        .map(|x| x.0)
+    let channel = match &settings.proxy {
+        Some(proxy) => {
+            let connector = proxy.connector();
+            channel
+                .connect_with_connector(service_fn(move |uri: Uri| connector.clone().connect(uri)))
+                .await?
+        }
+        None => channel.connect().await?,
+    };
+    Ok(CompactTxStreamerClient::new(channel))
 }
 
 // pub async fn sync(
@@ -558,75 +861,178 @@ mod tests {
         calculate_tree_state_v1, calculate_tree_state_v2, download_chain, get_latest_height,
         get_tree_state, DecryptNode,
     };
+    use crate::chain::{check_compact_block, check_compact_tx, ChainError, MAX_OUTPUTS_PER_BLOCK};
     use crate::db::AccountViewKey;
-    use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
-    use crate::LWD_URL;
+    use crate::scan::{Blocks, SyncControl};
+    use crate::synthetic::fixtures::{fvk, make_chain, TestChain};
+    use crate::{
+        connect_lightwalletd, CompactBlock, CompactOrchardAction, CompactSaplingOutput,
+        CompactSaplingSpend, CompactTx, MockLightwalletd,
+    };
 
     use std::collections::HashMap;
-    use std::time::Instant;
-    use zcash_client_backend::encoding::decode_extended_full_viewing_key;
-    use zcash_primitives::consensus::{Network, NetworkUpgrade, Parameters};
+    use tokio::sync::mpsc;
+    use zcash_primitives::consensus::Network;
+    use zcash_primitives::merkle_tree::IncrementalWitness;
+    use zcash_primitives::sapling::Node;
 
     const NETWORK: &Network = &Network::MainNetwork;
 
     #[tokio::test]
     async fn test_get_latest_height() -> anyhow::Result<()> {
-        let mut client = CompactTxStreamerClient::connect(LWD_URL).await?;
+        let lwd = MockLightwalletd::new("main", 419_200);
+        lwd.mine_blocks(100);
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
         let height = get_latest_height(&mut client).await?;
-        assert!(height > 1288000);
+        assert_eq!(height, 419_299);
+        Ok(())
+    }
+
+    fn compact_block(height: u32) -> CompactBlock {
+        let tx = CompactTx {
+            spends: vec![CompactSaplingSpend { nf: vec![1; 32] }],
+            outputs: vec![CompactSaplingOutput {
+                cmu: vec![2; 32],
+                epk: vec![3; 32],
+                ciphertext: vec![4; 52],
+            }],
+            actions: vec![CompactOrchardAction {
+                nullifier: vec![5; 32],
+                cmx: vec![6; 32],
+                ephemeral_key: vec![7; 32],
+                ciphertext: vec![8; 52],
+            }],
+            ..CompactTx::default()
+        };
+        CompactBlock {
+            height: height as u64,
+            hash: vec![9; 32],
+            prev_hash: vec![10; 32],
+            vtx: vec![tx],
+            ..CompactBlock::default()
+        }
+    }
+
+    #[test]
+    fn test_check_compact_block() {
+        check_compact_block(&compact_block(1000), 1000).unwrap();
+
+        let mut bad_blocks = vec![];
+        bad_blocks.push(compact_block(1001));
+        let mut b = compact_block(1000);
+        b.hash.pop();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.prev_hash.clear();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.vtx[0].spends[0].nf.pop();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.vtx[0].outputs[0].cmu.pop();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.vtx[0].outputs[0].ciphertext.push(0);
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.vtx[0].actions[0].ephemeral_key.clear();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        b.vtx[0].actions[0].ciphertext.pop();
+        bad_blocks.push(b);
+        let mut b = compact_block(1000);
+        let output = b.vtx[0].outputs[0].clone();
+        b.vtx[0].outputs = vec![output; MAX_OUTPUTS_PER_BLOCK];
+        bad_blocks.push(b);
+
+        for b in bad_blocks.iter() {
+            let err = check_compact_block(b, 1000).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<ChainError>(),
+                Some(ChainError::InvalidData { height: 1000, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_check_compact_tx() {
+        let tx = &compact_block(1000).vtx[0];
+        check_compact_tx(tx, 1000).unwrap();
+        let mut bad_tx = tx.clone();
+        bad_tx.outputs[0].epk.pop();
+        let err = check_compact_tx(&bad_tx, 1000).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ChainError>(),
+            Some(ChainError::InvalidData { height: 1000, .. })
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_block() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let lwd = tc.chain.to_mock();
+        // a server that sends a truncated note commitment
+        let mut block = tc.chain.blocks()[4].clone();
+        let bad_height = block.height as u32;
+        block.vtx[0].outputs[0].cmu.truncate(16);
+        lwd.add_block(block);
+        let server = lwd.start().await?;
+
+        let test_db = tc.create_db("invalid_block", 1)?;
+        let err = test_db.sync(server.url(), 0).await.unwrap_err();
+        match err.downcast_ref::<ChainError>() {
+            Some(ChainError::InvalidData { height, .. }) => assert_eq!(*height, bad_height),
+            _ => panic!("unexpected error {}", err),
+        }
+        let db = test_db.open()?;
+        assert!(db.get_db_height()? < bad_height);
+        assert_eq!(db.get_balance(test_db.accounts[0])?, 0);
         Ok(())
     }
 
     #[tokio::test]
     async fn test_download_chain() -> anyhow::Result<()> {
-        dotenv::dotenv().unwrap();
-        let fvk = dotenv::var("FVK").unwrap();
+        let fvk1 = fvk(1);
+        let fvk2 = fvk(2);
+        let chain = make_chain(&fvk1, &fvk2);
+        let server = chain.to_mock().start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
 
         let mut fvks: HashMap<u32, AccountViewKey> = HashMap::new();
-        let fvk =
-            decode_extended_full_viewing_key(NETWORK.hrp_sapling_extended_full_viewing_key(), &fvk)
-                .unwrap()
-                .unwrap();
-        fvks.insert(1, AccountViewKey::from_fvk(&fvk));
+        fvks.insert(1, AccountViewKey::from_fvk(&fvk1));
         let decrypter = DecryptNode::new(fvks);
-        let mut client = CompactTxStreamerClient::connect(LWD_URL).await?;
-        let start_height: u32 = NETWORK
-            .activation_height(NetworkUpgrade::Sapling)
-            .unwrap()
-            .into();
+        let start_height = chain.blocks()[0].height as u32 - 1;
         let end_height = get_latest_height(&mut client).await?;
+        assert_eq!(end_height, chain.height());
 
-        let start = Instant::now();
-        let cbs = download_chain(&mut client, start_height, end_height, None).await?;
-        eprintln!("Download chain: {} ms", start.elapsed().as_millis());
-
-        let start = Instant::now();
-        let blocks = decrypter.decrypt_blocks(&Network::MainNetwork, &cbs);
-        eprintln!("Decrypt Notes: {} ms", start.elapsed().as_millis());
-
-        // no need to calculate tree before the first note if we can
-        // get it from the server
-        // disabled because I want to see the performance of a complete scan
-
-        // let first_block = blocks.iter().find(|b| !b.notes.is_empty()).unwrap();
-        // let height = first_block.height - 1;
-        // let tree_state = get_tree_state(&mut client, height).await;
-        // let tree_state = hex::decode(tree_state).unwrap();
-        // let tree_state = CommitmentTree::<Node>::read(&*tree_state).unwrap();
-
-        // let witnesses = calculate_tree_state(&cbs, &blocks, 0, tree_state);
+        let (blocks_tx, mut blocks_rx) = mpsc::channel(4);
+        download_chain(
+            &mut client,
+            start_height,
+            end_height,
+            None,
+            blocks_tx,
+            &SyncControl::default(),
+            None,
+        )
+        .await?;
+        let mut cbs = vec![];
+        while let Some(Blocks(blocks)) = blocks_rx.recv().await {
+            cbs.extend(blocks);
+        }
+        assert_eq!(cbs, chain.blocks());
 
-        let start = Instant::now();
+        let blocks = decrypter.decrypt_blocks(NETWORK, &cbs);
         let witnesses = calculate_tree_state_v2(&cbs, &blocks);
-        eprintln!("Tree State & Witnesses: {} ms", start.elapsed().as_millis());
-
-        eprintln!("# Witnesses {}", witnesses.len());
+        // the spent note is witnessed too
+        assert_eq!(witnesses.len(), 3);
+        let root = chain.tree().to_commitment_tree().root();
         for w in witnesses.iter() {
             let mut bb: Vec<u8> = vec![];
-            w.write(&mut bb).unwrap();
-            log::info!("{}", hex::encode(&bb));
+            w.write(&mut bb)?;
+            assert_eq!(IncrementalWitness::<Node>::read(&*bb)?.root(), root);
         }
-
         Ok(())
     }
 }
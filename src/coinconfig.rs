@@ -1,4 +1,7 @@
-use crate::{connect_lightwalletd, CompactTxStreamerClient, DbAdapter, FountainCodes, MemPool};
+use crate::{
+    connect_lightwalletd_with, BlockCache, CompactTxStreamerClient, ConnectionSettings, DbAdapter,
+    FountainCodes, MemPool, Proxy, TlsSettings,
+};
 use anyhow::anyhow;
 use lazy_static::lazy_static;
 use lazycell::AtomicLazyCell;
@@ -44,12 +47,79 @@ pub fn get_coin_lwd_url(coin: u8) -> String {
     c.lwd_url.clone().unwrap_or_default()
 }
 
+/// Other servers to cross check the chain of the lwd url against, at the tip
+/// and at `samples` random heights, and to fail over to (empty to disable)
+pub fn set_coin_lwd_servers(coin: u8, servers: &[String], samples: u32) {
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    c.lwd_servers = servers.to_vec();
+    c.server_check_samples = samples;
+}
+
+/// Send the traffic of the coin through a SOCKS5 proxy, None to connect directly
+pub fn set_coin_proxy(coin: u8, proxy: Option<Proxy>) {
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    c.connection_settings.proxy = proxy;
+}
+
+/// TLS settings of the server at `url`, None to only trust the built-in certificate
+pub fn set_coin_tls_settings(coin: u8, url: &str, tls: Option<TlsSettings>) {
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    match tls {
+        Some(tls) => {
+            c.connection_settings.tls.insert(url.to_string(), tls);
+        }
+        None => {
+            c.connection_settings.tls.remove(url);
+        }
+    }
+}
+
+/// Keep downloaded blocks in `dir`, up to `max_size` bytes (0 for no limit)
+pub fn set_coin_block_cache(coin: u8, dir: &str, max_size: u64) -> anyhow::Result<()> {
+    let cache = BlockCache::new(dir, max_size)?;
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    c.block_cache = Some(Arc::new(cache));
+    Ok(())
+}
+
+/// Check the Sapling tree against the server every `interval` blocks (0 to disable)
+pub fn set_coin_tree_check_interval(coin: u8, interval: u32) {
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    c.tree_check_interval = interval;
+}
+
+/// Keep the checkpoints of the last `recent_blocks` blocks and one checkpoint
+/// every `checkpoint_interval` blocks before them (0 for none)
+pub fn set_coin_witness_retention(coin: u8, recent_blocks: u32, checkpoint_interval: u32) {
+    let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
+    c.witness_retention = WitnessRetention {
+        recent_blocks,
+        checkpoint_interval,
+    };
+}
+
 pub fn init_coin(coin: u8, db_path: &str) -> anyhow::Result<()> {
     let mut c = COIN_CONFIG[coin as usize].lock().unwrap();
     c.set_db_path(db_path)?;
     Ok(())
 }
 
+/// Tree states and witnesses kept after a sync, used to rewind without the server
+#[derive(Clone, Copy, Debug)]
+pub struct WitnessRetention {
+    pub recent_blocks: u32,
+    pub checkpoint_interval: u32,
+}
+
+impl Default for WitnessRetention {
+    fn default() -> Self {
+        WitnessRetention {
+            recent_blocks: 100,
+            checkpoint_interval: 10_000,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct CoinConfig {
     pub coin: u8,
@@ -57,9 +127,15 @@ pub struct CoinConfig {
     pub id_account: u32,
     pub height: u32,
     pub lwd_url: Option<String>,
+    pub lwd_servers: Vec<String>,
+    pub server_check_samples: u32,
+    pub connection_settings: ConnectionSettings,
     pub db_path: Option<String>,
     pub mempool: Arc<Mutex<MemPool>>,
     pub db: Option<Arc<Mutex<DbAdapter>>>,
+    pub block_cache: Option<Arc<BlockCache>>,
+    pub tree_check_interval: u32,
+    pub witness_retention: WitnessRetention,
     pub chain: &'static (dyn CoinChain + Send),
 }
 
@@ -72,8 +148,14 @@ impl CoinConfig {
             id_account: 0,
             height: 0,
             lwd_url: None,
+            lwd_servers: vec![],
+            server_check_samples: 0,
+            connection_settings: ConnectionSettings::default(),
             db_path: None,
             db: None,
+            block_cache: None,
+            tree_check_interval: 0,
+            witness_retention: WitnessRetention::default(),
             mempool: Arc::new(Mutex::new(MemPool::new(coin))),
             chain,
         }
@@ -114,9 +196,20 @@ impl CoinConfig {
         Ok(db)
     }
 
+    /// The lwd url followed by the other servers
+    pub fn servers(&self) -> Vec<String> {
+        let mut servers: Vec<String> = self.lwd_url.iter().cloned().collect();
+        for s in self.lwd_servers.iter() {
+            if !servers.contains(s) {
+                servers.push(s.clone());
+            }
+        }
+        servers
+    }
+
     pub async fn connect_lwd(&self) -> anyhow::Result<CompactTxStreamerClient<Channel>> {
         if let Some(lwd_url) = &self.lwd_url {
-            connect_lightwalletd(lwd_url).await
+            connect_lightwalletd_with(lwd_url, &self.connection_settings).await
         } else {
             Err(anyhow!("LWD URL Not set"))
         }
@@ -1,8 +1,9 @@
 use crate::chain::DecryptedNote;
 use byteorder::WriteBytesExt;
+use orchard::tree::MerkleHashOrchard;
 use std::io::{Read, Write};
 use zcash_encoding::{Optional, Vector};
-use zcash_primitives::merkle_tree::{CommitmentTree, Hashable};
+use zcash_primitives::merkle_tree::{CommitmentTree, Hashable, IncrementalWitness};
 use zcash_primitives::sapling::Node;
 
 /*
@@ -129,6 +130,30 @@ impl Witness {
     }
 }
 
+/*
+Orchard has far fewer outputs than Sapling, so its tree and witnesses are kept
+with the regular incremental structures from librustzcash and updated one
+commitment at a time.
+ */
+pub type OrchardTree = CommitmentTree<MerkleHashOrchard>;
+
+#[derive(Clone)]
+pub struct OrchardWitness {
+    pub id_note: u32,
+    pub witness: IncrementalWitness<MerkleHashOrchard>,
+}
+
+impl OrchardWitness {
+    pub fn read<R: Read>(id_note: u32, reader: R) -> std::io::Result<Self> {
+        let witness = IncrementalWitness::<MerkleHashOrchard>::read(reader)?;
+        Ok(OrchardWitness { id_note, witness })
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        self.witness.write(writer)
+    }
+}
+
 impl CTree {
     pub fn new() -> CTree {
         CTree {
@@ -1,12 +1,18 @@
 use crate::chain::{Nf, NfRef};
+use crate::commitment::{OrchardTree, OrchardWitness};
 use crate::contact::Contact;
+use crate::key2::derive_orchard_keys;
 use crate::prices::Quote;
 use crate::taddr::{derive_tkeys, TBalance};
 use crate::transaction::TransactionInfo;
 use crate::{CTree, Witness};
+use orchard::keys::{Diversifier as OrchardDiversifier, FullViewingKey, Scope, SpendingKey};
+use orchard::note::{Nullifier, RandomSeed};
+use orchard::tree::MerkleHashOrchard;
+use orchard::value::NoteValue;
 use rusqlite::{params, Connection, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use zcash_client_backend::encoding::decode_extended_full_viewing_key;
 use zcash_params::coin::{get_coin_chain, get_coin_id, CoinType};
 use zcash_primitives::consensus::{Network, NetworkUpgrade, Parameters};
@@ -19,6 +25,9 @@ mod migration;
 #[allow(dead_code)]
 pub const DEFAULT_DB_PATH: &str = "zec.db";
 
+/// Distance between the heights of the block times kept for good
+pub const BLOCK_TIME_INTERVAL: u32 = 1_000;
+
 pub struct DbAdapter {
     pub coin_type: CoinType,
     pub connection: Connection,
@@ -35,6 +44,17 @@ pub struct ReceivedNote {
     pub spent: Option<u32>,
 }
 
+pub struct ReceivedOrchardNote {
+    pub account: u32,
+    pub height: u32,
+    pub output_index: u32,
+    pub diversifier: Vec<u8>,
+    pub value: u64,
+    pub rho: Vec<u8>,
+    pub rseed: Vec<u8>,
+    pub nf: Vec<u8>,
+}
+
 #[derive(Clone)]
 pub struct SpendableNote {
     pub id: u32,
@@ -43,9 +63,40 @@ pub struct SpendableNote {
     pub witness: IncrementalWitness<Node>,
 }
 
+pub struct SpendableOrchardNote {
+    pub id: u32,
+    pub note: orchard::Note,
+    pub witness: IncrementalWitness<MerkleHashOrchard>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Pool {
+    Sapling,
+    Orchard,
+}
+
+/// Identifies a received note. Note ids are only unique within a pool
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NoteId {
+    pub pool: Pool,
+    pub id_note: u32,
+}
+
+pub struct NoteInfo {
+    pub id: NoteId,
+    pub height: u32,
+    pub value: u64,
+    pub diversifier: Vec<u8>,
+    /// Scope of the address that received an Orchard note. None for Sapling notes
+    pub scope: Option<Scope>,
+    pub spent: bool,
+    pub excluded: bool,
+}
+
 pub struct AccountViewKey {
     pub fvk: ExtendedFullViewingKey,
     pub ivk: SaplingIvk,
+    pub orchard_fvk: Option<FullViewingKey>,
     pub viewonly: bool,
 }
 
@@ -54,6 +105,7 @@ impl AccountViewKey {
         AccountViewKey {
             fvk: fvk.clone(),
             ivk: fvk.fvk.vk.ivk(),
+            orchard_fvk: None,
             viewonly: false,
         }
     }
@@ -95,6 +147,7 @@ impl DbAdapter {
     //
     pub fn init_db(&self) -> anyhow::Result<()> {
         migration::init_db(&self.connection)?;
+        self.create_missing_orchard_keys()?;
         Ok(())
     }
 
@@ -154,14 +207,52 @@ impl DbAdapter {
         Ok(())
     }
 
+    pub fn set_birthday(&self, account: u32, birthday: Option<u32>) -> anyhow::Result<()> {
+        self.connection.execute(
+            "UPDATE accounts SET birthday = ?1 WHERE id_account = ?2",
+            params![birthday, account],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_birthday(&self, account: u32) -> anyhow::Result<Option<u32>> {
+        let birthday = self.connection.query_row(
+            "SELECT birthday FROM accounts WHERE id_account = ?1",
+            params![account],
+            |row| row.get(0),
+        )?;
+        Ok(birthday)
+    }
+
+    /// Nullifiers of every note received by the account, spent or not
+    pub fn get_account_nullifiers(&self, account: u32) -> anyhow::Result<HashSet<Nf>> {
+        let mut nfs = HashSet::new();
+        for table in ["received_notes", "received_orchard_notes"].iter() {
+            let mut statement = self
+                .connection
+                .prepare(&format!("SELECT nf FROM {} WHERE account = ?1", table))?;
+            let rows = statement.query_map(params![account], |row| {
+                let nf: Vec<u8> = row.get(0)?;
+                Ok(nf)
+            })?;
+            for r in rows {
+                let mut nf = [0u8; 32];
+                nf.copy_from_slice(&r?);
+                nfs.insert(Nf(nf));
+            }
+        }
+        Ok(nfs)
+    }
+
     pub fn get_fvks(&self) -> anyhow::Result<HashMap<u32, AccountViewKey>> {
-        let mut statement = self
-            .connection
-            .prepare("SELECT id_account, ivk, sk FROM accounts")?;
+        let mut statement = self.connection.prepare(
+            "SELECT id_account, ivk, a.sk, o.fvk FROM accounts a LEFT JOIN orchard_addrs o ON a.id_account = o.account",
+        )?;
         let rows = statement.query_map([], |row| {
             let account: u32 = row.get(0)?;
             let ivk: String = row.get(1)?;
             let sk: Option<String> = row.get(2)?;
+            let orchard_fvk: Option<Vec<u8>> = row.get(3)?;
             let fvk = decode_extended_full_viewing_key(
                 self.network().hrp_sapling_extended_full_viewing_key(),
                 &ivk,
@@ -169,11 +260,13 @@ impl DbAdapter {
             .unwrap()
             .unwrap();
             let ivk = fvk.fvk.vk.ivk();
+            let orchard_fvk = orchard_fvk.and_then(|fvk| decode_orchard_fvk(&fvk));
             Ok((
                 account,
                 AccountViewKey {
                     fvk,
                     ivk,
+                    orchard_fvk,
                     viewonly: sk.is_none(),
                 },
             ))
@@ -189,6 +282,10 @@ impl DbAdapter {
     pub fn trim_to_height(&mut self, height: u32) -> anyhow::Result<()> {
         let tx = self.connection.transaction()?;
         tx.execute("DELETE FROM blocks WHERE height >= ?1", params![height])?;
+        tx.execute(
+            "DELETE FROM block_times WHERE height >= ?1",
+            params![height],
+        )?;
         tx.execute(
             "DELETE FROM sapling_witnesses WHERE height >= ?1",
             params![height],
@@ -201,11 +298,48 @@ impl DbAdapter {
             "UPDATE received_notes SET spent = NULL WHERE spent >= ?1",
             params![height],
         )?;
+        tx.execute(
+            "DELETE FROM orchard_witnesses WHERE height >= ?1",
+            params![height],
+        )?;
+        tx.execute(
+            "DELETE FROM received_orchard_notes WHERE height >= ?1",
+            params![height],
+        )?;
+        tx.execute(
+            "UPDATE received_orchard_notes SET spent = NULL WHERE spent >= ?1",
+            params![height],
+        )?;
         tx.execute(
             "DELETE FROM transactions WHERE height >= ?1",
             params![height],
         )?;
         tx.execute("DELETE FROM messages WHERE height >= ?1", params![height])?;
+        tx.execute("DELETE FROM utxos WHERE height >= ?1", params![height])?;
+        tx.execute(
+            "UPDATE utxos SET spent = NULL WHERE spent >= ?1",
+            params![height],
+        )?;
+        tx.execute(
+            "DELETE FROM transparent_txs WHERE height >= ?1",
+            params![height],
+        )?;
+        tx.execute(
+            "UPDATE pending_txs SET confirmed = NULL WHERE confirmed >= ?1",
+            params![height],
+        )?;
+        // notes of transactions that are pending again stay locked
+        for (pool, table) in [(0u8, "received_notes"), (1u8, "received_orchard_notes")].iter() {
+            tx.execute(
+                &format!(
+                    "UPDATE {} SET spent = 0 WHERE spent IS NULL AND id_note IN
+                    (SELECT p.note FROM pending_tx_notes p JOIN pending_txs t ON p.pending_tx = t.id_tx
+                    WHERE p.pool = ?1 AND t.confirmed IS NULL)",
+                    table
+                ),
+                params![pool],
+            )?;
+        }
         tx.commit()?;
 
         Ok(())
@@ -233,15 +367,18 @@ impl DbAdapter {
         hash: &[u8],
         timestamp: u32,
         tree: &CTree,
+        orchard_tree: &OrchardTree,
     ) -> anyhow::Result<()> {
         log::debug!("+block");
         let mut bb: Vec<u8> = vec![];
         tree.write(&mut bb)?;
+        let mut orchard_bb: Vec<u8> = vec![];
+        orchard_tree.write(&mut orchard_bb)?;
         connection.execute(
-            "INSERT INTO blocks(height, hash, timestamp, sapling_tree)
-        VALUES (?1, ?2, ?3, ?4)
+            "INSERT INTO blocks(height, hash, timestamp, sapling_tree, orchard_tree)
+        VALUES (?1, ?2, ?3, ?4, ?5)
         ON CONFLICT DO NOTHING",
-            params![height, hash, timestamp, &bb],
+            params![height, hash, timestamp, &bb, &orchard_bb],
         )?;
         log::debug!("-block");
         Ok(())
@@ -257,8 +394,8 @@ impl DbAdapter {
     ) -> anyhow::Result<u32> {
         log::debug!("+transaction");
         db_tx.execute(
-            "INSERT INTO transactions(account, txid, height, timestamp, tx_index, value)
-        VALUES (?1, ?2, ?3, ?4, ?5, 0)
+            "INSERT INTO transactions(account, txid, height, timestamp, tx_index, value, details_pending)
+        VALUES (?1, ?2, ?3, ?4, ?5, 0, 1)
         ON CONFLICT DO NOTHING",
             params![account, txid, height, timestamp, tx_index],
         )?;
@@ -290,6 +427,25 @@ impl DbAdapter {
         Ok(id_note)
     }
 
+    pub fn store_received_orchard_note(
+        note: &ReceivedOrchardNote,
+        id_tx: u32,
+        position: usize,
+        db_tx: &Transaction,
+    ) -> anyhow::Result<u32> {
+        log::debug!("+received_orchard_note {}", id_tx);
+        db_tx.execute("INSERT INTO received_orchard_notes(account, tx, height, position, output_index, diversifier, value, rho, rseed, nf)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+        ON CONFLICT DO NOTHING", params![note.account, id_tx, note.height, position as u32, note.output_index, note.diversifier, note.value as i64, note.rho, note.rseed, note.nf])?;
+        let id_note: u32 = db_tx.query_row(
+            "SELECT id_note FROM received_orchard_notes WHERE tx = ?1 AND output_index = ?2",
+            params![id_tx, note.output_index],
+            |row| row.get(0),
+        )?;
+        log::debug!("-received_orchard_note");
+        Ok(id_note)
+    }
+
     pub fn store_witnesses(
         connection: &Connection,
         witness: &Witness,
@@ -308,14 +464,56 @@ impl DbAdapter {
         Ok(())
     }
 
+    pub fn store_orchard_witnesses(
+        connection: &Connection,
+        witness: &OrchardWitness,
+        height: u32,
+    ) -> anyhow::Result<()> {
+        let mut bb: Vec<u8> = vec![];
+        witness.write(&mut bb)?;
+        connection.execute(
+            "INSERT INTO orchard_witnesses(note, height, witness) VALUES (?1, ?2, ?3)
+        ON CONFLICT DO NOTHING",
+            params![witness.id_note, height, bb],
+        )?;
+        Ok(())
+    }
+
     pub fn store_tx_metadata(&self, id_tx: u32, tx_info: &TransactionInfo) -> anyhow::Result<()> {
         self.connection.execute(
-            "UPDATE transactions SET address = ?1, memo = ?2 WHERE id_tx = ?3",
+            "UPDATE transactions SET address = ?1, memo = ?2, details_pending = 0 WHERE id_tx = ?3",
             params![tx_info.address, &tx_info.memo, id_tx],
         )?;
         Ok(())
     }
 
+    /// Transactions that still miss their details, in block order.
+    /// If `time` is given, only those due for a retry at `time`
+    pub fn get_pending_tx_details(&self, time: Option<u32>) -> anyhow::Result<Vec<u32>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id_tx FROM transactions WHERE details_pending
+            AND (?1 IS NULL OR details_retry_time IS NULL OR details_retry_time <= ?1)
+            ORDER BY height, tx_index",
+        )?;
+        let rows = statement.query_map(params![time], |row| row.get(0))?;
+        let mut ids: Vec<u32> = vec![];
+        for r in rows {
+            ids.push(r?);
+        }
+        Ok(ids)
+    }
+
+    /// Schedule the next retrieval of the details of a transaction after a failure.
+    /// The delay doubles with each attempt
+    pub fn set_tx_details_failed(&self, id_tx: u32, time: u32, delay: u32) -> anyhow::Result<()> {
+        self.connection.execute(
+            "UPDATE transactions SET details_attempts = details_attempts + 1,
+            details_retry_time = ?2 + (?3 << MIN(details_attempts, 10)) WHERE id_tx = ?1",
+            params![id_tx, time, delay],
+        )?;
+        Ok(())
+    }
+
     pub fn add_value(id_tx: u32, value: i64, db_tx: &Transaction) -> anyhow::Result<()> {
         db_tx.execute(
             "UPDATE transactions SET value = value + ?2 WHERE id_tx = ?1",
@@ -337,13 +535,34 @@ impl DbAdapter {
         Ok((account, value))
     }
 
+    pub fn get_received_orchard_note_value(
+        nf: &Nf,
+        db_tx: &Transaction,
+    ) -> anyhow::Result<(u32, i64)> {
+        let (account, value) = db_tx.query_row(
+            "SELECT account, value FROM received_orchard_notes WHERE nf = ?1",
+            params![nf.0.to_vec()],
+            |row| {
+                let account: u32 = row.get(0)?;
+                let value: i64 = row.get(1)?;
+                Ok((account, value))
+            },
+        )?;
+        Ok((account, value))
+    }
+
     pub fn get_balance(&self, account: u32) -> anyhow::Result<u64> {
         let balance: Option<i64> = self.connection.query_row(
             "SELECT SUM(value) FROM received_notes WHERE (spent IS NULL OR spent = 0) AND account = ?1",
             params![account],
             |row| row.get(0),
         )?;
-        Ok(balance.unwrap_or(0) as u64)
+        let orchard_balance: Option<i64> = self.connection.query_row(
+            "SELECT SUM(value) FROM received_orchard_notes WHERE (spent IS NULL OR spent = 0) AND account = ?1",
+            params![account],
+            |row| row.get(0),
+        )?;
+        Ok((balance.unwrap_or(0) + orchard_balance.unwrap_or(0)) as u64)
     }
 
     pub fn get_last_sync_height(&self) -> anyhow::Result<Option<u32>> {
@@ -379,6 +598,94 @@ impl DbAdapter {
         }))
     }
 
+    /// Height of the most recent block with a stored tree state at or below `max_height`
+    pub fn get_checkpoint_height(&self, max_height: u32) -> anyhow::Result<Option<u32>> {
+        let height: Option<u32> = self.connection.query_row(
+            "SELECT MAX(height) FROM blocks WHERE height <= ?1",
+            params![max_height],
+            |row| row.get(0),
+        )?;
+        Ok(height)
+    }
+
+    /// Heights of the blocks with a stored tree state at or above `min_height`
+    pub fn get_checkpoint_heights(&self, min_height: u32) -> anyhow::Result<Vec<u32>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT height FROM blocks WHERE height >= ?1 ORDER BY height")?;
+        let rows = statement.query_map(params![min_height], |row| row.get(0))?;
+        let mut heights: Vec<u32> = vec![];
+        for height in rows {
+            heights.push(height?);
+        }
+        Ok(heights)
+    }
+
+    /// Record the time of blocks. Between two multiples of [BLOCK_TIME_INTERVAL],
+    /// only the time of the latest block is kept
+    pub fn store_block_times(connection: &Connection, times: &[(u32, u32)]) -> anyhow::Result<()> {
+        for (height, timestamp) in times.iter() {
+            connection.execute(
+                "INSERT OR REPLACE INTO block_times(height, timestamp) VALUES (?1, ?2)",
+                params![height, timestamp],
+            )?;
+        }
+        if let Some((height, _)) = times.last() {
+            connection.execute(
+                "DELETE FROM block_times WHERE height > ?1 / ?2 * ?2 AND height < ?1",
+                params![height, BLOCK_TIME_INTERVAL],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get_block_time(&self, height: u32) -> anyhow::Result<Option<u32>> {
+        let timestamp = self
+            .connection
+            .query_row(
+                "SELECT timestamp FROM block_times WHERE height = ?1",
+                params![height],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(timestamp)
+    }
+
+    /// Estimate the height of the block mined at `time` by interpolating between
+    /// the recorded block times. None if `time` is outside of the recorded range
+    /// or if the closest block times are more than [BLOCK_TIME_INTERVAL] apart
+    pub fn get_height_by_time(&self, time: u32) -> anyhow::Result<Option<u32>> {
+        let before: Option<(u32, u32)> = self
+            .connection
+            .query_row(
+                "SELECT height, timestamp FROM block_times WHERE timestamp <= ?1 ORDER BY height DESC LIMIT 1",
+                params![time],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let after: Option<(u32, u32)> = self
+            .connection
+            .query_row(
+                "SELECT height, timestamp FROM block_times WHERE timestamp > ?1 ORDER BY height LIMIT 1",
+                params![time],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let height = match (before, after) {
+            (Some((h0, t0)), _) if t0 == time => Some(h0),
+            (Some((h0, t0)), Some((h1, t1))) if h1 > h0 && h1 - h0 <= BLOCK_TIME_INTERVAL => {
+                let offset = (time - t0) as u64 * (h1 - h0) as u64 / (t1 - t0) as u64;
+                Some(h0 + offset as u32)
+            }
+            // block times are not always increasing
+            (Some((h0, _)), Some((h1, _))) if h1 <= h0 && h0 - h1 <= BLOCK_TIME_INTERVAL => {
+                Some(h0)
+            }
+            _ => None,
+        };
+        Ok(height)
+    }
+
     pub fn get_tree(&self) -> anyhow::Result<(CTree, Vec<Witness>)> {
         let res = self.connection.query_row(
             "SELECT height, sapling_tree FROM blocks WHERE height = (SELECT MAX(height) FROM blocks)",
@@ -407,6 +714,65 @@ impl DbAdapter {
         })
     }
 
+    pub fn get_orchard_tree(&self) -> anyhow::Result<(OrchardTree, Vec<OrchardWitness>)> {
+        let res = self.connection.query_row(
+            "SELECT height, orchard_tree FROM blocks WHERE height = (SELECT MAX(height) FROM blocks)",
+            [], |row| {
+                let height: u32 = row.get(0)?;
+                let tree: Option<Vec<u8>> = row.get(1)?;
+                Ok((height, tree))
+            }).optional()?;
+        Ok(match res {
+            Some((height, Some(tree))) => {
+                let tree = OrchardTree::read(&*tree)?;
+                let mut statement = self.connection.prepare(
+                    "SELECT id_note, witness FROM orchard_witnesses w, received_orchard_notes n WHERE w.height = ?1 AND w.note = n.id_note AND (n.spent IS NULL OR n.spent = 0)")?;
+                let ws = statement.query_map(params![height], |row| {
+                    let id_note: u32 = row.get(0)?;
+                    let witness: Vec<u8> = row.get(1)?;
+                    Ok(OrchardWitness::read(id_note, &*witness).unwrap())
+                })?;
+                let mut witnesses: Vec<OrchardWitness> = vec![];
+                for w in ws {
+                    witnesses.push(w?);
+                }
+                (tree, witnesses)
+            }
+            Some((height, None)) => {
+                anyhow::bail!("Orchard tree of block {} is missing", height)
+            }
+            None => (OrchardTree::empty(), vec![]),
+        })
+    }
+
+    /// Height of the last block if it was stored before the Orchard migration,
+    /// without an Orchard tree
+    pub fn get_missing_orchard_tree_height(&self) -> anyhow::Result<Option<u32>> {
+        let height = self
+            .connection
+            .query_row(
+                "SELECT height FROM blocks WHERE height = (SELECT MAX(height) FROM blocks) AND orchard_tree IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(height)
+    }
+
+    pub fn store_orchard_tree(
+        &self,
+        height: u32,
+        orchard_tree: &OrchardTree,
+    ) -> anyhow::Result<()> {
+        let mut bb: Vec<u8> = vec![];
+        orchard_tree.write(&mut bb)?;
+        self.connection.execute(
+            "UPDATE blocks SET orchard_tree = ?2 WHERE height = ?1",
+            params![height, &bb],
+        )?;
+        Ok(())
+    }
+
     pub fn get_nullifiers(&self) -> anyhow::Result<HashMap<Nf, NfRef>> {
         let mut statement = self.connection.prepare(
             "SELECT id_note, account, nf FROM received_notes WHERE spent IS NULL OR spent = 0",
@@ -429,6 +795,28 @@ impl DbAdapter {
         Ok(nfs)
     }
 
+    pub fn get_orchard_nullifiers(&self) -> anyhow::Result<HashMap<Nf, NfRef>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id_note, account, nf FROM received_orchard_notes WHERE spent IS NULL OR spent = 0",
+        )?;
+        let nfs_res = statement.query_map([], |row| {
+            let id_note: u32 = row.get(0)?;
+            let account: u32 = row.get(1)?;
+            let nf_vec: Vec<u8> = row.get(2)?;
+            let mut nf = [0u8; 32];
+            nf.clone_from_slice(&nf_vec);
+            let nf_ref = NfRef { id_note, account };
+            Ok((nf_ref, nf))
+        })?;
+        let mut nfs: HashMap<Nf, NfRef> = HashMap::new();
+        for n in nfs_res {
+            let n = n?;
+            nfs.insert(Nf(n.1), n.0);
+        }
+
+        Ok(nfs)
+    }
+
     pub fn get_nullifier_amounts(
         &self,
         account: u32,
@@ -489,37 +877,314 @@ impl DbAdapter {
             let rcm: Vec<u8> = row.get(3)?;
             let witness: Vec<u8> = row.get(4)?;
 
-            let mut diversifer_bytes = [0u8; 11];
-            diversifer_bytes.copy_from_slice(&diversifier);
-            let diversifier = Diversifier(diversifer_bytes);
-            let mut rcm_bytes = [0u8; 32];
-            rcm_bytes.copy_from_slice(&rcm);
-            let rcm = jubjub::Fr::from_bytes(&rcm_bytes).unwrap();
-            let rseed = Rseed::BeforeZip212(rcm);
-            let witness = IncrementalWitness::<Node>::read(&*witness).unwrap();
+            let mut diversifer_bytes = [0u8; 11];
+            diversifer_bytes.copy_from_slice(&diversifier);
+            let diversifier = Diversifier(diversifer_bytes);
+            let mut rcm_bytes = [0u8; 32];
+            rcm_bytes.copy_from_slice(&rcm);
+            let rcm = jubjub::Fr::from_bytes(&rcm_bytes).unwrap();
+            let rseed = Rseed::BeforeZip212(rcm);
+            let witness = IncrementalWitness::<Node>::read(&*witness).unwrap();
+
+            let pa = fvk.fvk.vk.to_payment_address(diversifier).unwrap();
+            let note = pa.create_note(value as u64, rseed).unwrap();
+            Ok(SpendableNote {
+                id: id_note,
+                note,
+                diversifier,
+                witness,
+            })
+        })?;
+        let mut spendable_notes: Vec<SpendableNote> = vec![];
+        for n in notes {
+            spendable_notes.push(n?);
+        }
+
+        Ok(spendable_notes)
+    }
+
+    pub fn get_spendable_orchard_notes(
+        &self,
+        account: u32,
+        anchor_height: u32,
+        fvk: &FullViewingKey,
+    ) -> anyhow::Result<Vec<SpendableOrchardNote>> {
+        let mut statement = self.connection.prepare(
+            "SELECT id_note, diversifier, value, rho, rseed, nf, witness FROM received_orchard_notes r, orchard_witnesses w WHERE spent IS NULL AND account = ?2
+            AND (r.excluded IS NULL OR NOT r.excluded) AND w.height = (
+	            SELECT MAX(height) FROM orchard_witnesses WHERE height <= ?1
+            ) AND r.id_note = w.note")?;
+        let rows = statement.query_map(params![anchor_height, account], |row| {
+            let id_note: u32 = row.get(0)?;
+            let diversifier: Vec<u8> = row.get(1)?;
+            let value: i64 = row.get(2)?;
+            let rho: Vec<u8> = row.get(3)?;
+            let rseed: Vec<u8> = row.get(4)?;
+            let nf: Vec<u8> = row.get(5)?;
+            let witness: Vec<u8> = row.get(6)?;
+            Ok((id_note, diversifier, value, rho, rseed, nf, witness))
+        })?;
+        let mut spendable_notes: Vec<SpendableOrchardNote> = vec![];
+        for r in rows {
+            let (id_note, diversifier, value, rho, rseed, nf, witness) = r?;
+            let note = decode_orchard_note(fvk, &diversifier, value as u64, &rho, &rseed, &nf)
+                .ok_or_else(|| anyhow::anyhow!("Invalid Orchard note {}", id_note))?;
+            let witness = IncrementalWitness::<MerkleHashOrchard>::read(&*witness)?;
+            spendable_notes.push(SpendableOrchardNote {
+                id: id_note,
+                note,
+                witness,
+            });
+        }
+
+        Ok(spendable_notes)
+    }
+
+    pub fn get_notes(&self, account: u32) -> anyhow::Result<Vec<NoteInfo>> {
+        let orchard_fvk = self.get_orchard_fvk(account)?;
+        let mut notes: Vec<NoteInfo> = vec![];
+        for (pool, table, orchard_columns) in [
+            (Pool::Sapling, "received_notes", "NULL, NULL"),
+            (Pool::Orchard, "received_orchard_notes", "rho, rseed"),
+        ]
+        .iter()
+        {
+            let mut statement = self.connection.prepare(&format!(
+                "SELECT id_note, height, value, diversifier, spent, excluded, nf, {} FROM {} WHERE account = ?1 ORDER BY height",
+                orchard_columns, table
+            ))?;
+            let rows = statement.query_map(params![account], |row| {
+                let id_note: u32 = row.get(0)?;
+                let height: u32 = row.get(1)?;
+                let value: i64 = row.get(2)?;
+                let diversifier: Vec<u8> = row.get(3)?;
+                let spent: Option<u32> = row.get(4)?;
+                let excluded: Option<bool> = row.get(5)?;
+                let nf: Vec<u8> = row.get(6)?;
+                let rho: Option<Vec<u8>> = row.get(7)?;
+                let rseed: Option<Vec<u8>> = row.get(8)?;
+                let scope = match (&orchard_fvk, rho, rseed) {
+                    (Some(fvk), Some(rho), Some(rseed)) => decode_orchard_note_with_scope(
+                        fvk,
+                        &diversifier,
+                        value as u64,
+                        &rho,
+                        &rseed,
+                        &nf,
+                    )
+                    .map(|(_, scope)| scope),
+                    _ => None,
+                };
+                Ok(NoteInfo {
+                    id: NoteId {
+                        pool: *pool,
+                        id_note,
+                    },
+                    height,
+                    value: value as u64,
+                    diversifier,
+                    scope,
+                    spent: spent.is_some(),
+                    excluded: excluded.unwrap_or(false),
+                })
+            })?;
+            for n in rows {
+                notes.push(n?);
+            }
+        }
+        Ok(notes)
+    }
+
+    /// Notes of other accounts are left untouched
+    pub fn exclude_notes(
+        &mut self,
+        account: u32,
+        notes: &[NoteId],
+        excluded: bool,
+    ) -> anyhow::Result<()> {
+        let db_tx = self.begin_transaction()?;
+        for n in notes.iter() {
+            let table = match n.pool {
+                Pool::Sapling => "received_notes",
+                Pool::Orchard => "received_orchard_notes",
+            };
+            db_tx.execute(
+                &format!(
+                    "UPDATE {} SET excluded = ?1 WHERE id_note = ?2 AND account = ?3",
+                    table
+                ),
+                params![excluded, n.id_note, account],
+            )?;
+        }
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    pub fn tx_mark_spend(
+        &mut self,
+        selected_notes: &[u32],
+        selected_orchard_notes: &[u32],
+    ) -> anyhow::Result<()> {
+        let db_tx = self.begin_transaction()?;
+        for id_note in selected_notes.iter() {
+            DbAdapter::mark_spent(*id_note, 0, &db_tx)?;
+        }
+        for id_note in selected_orchard_notes.iter() {
+            DbAdapter::mark_orchard_spent(*id_note, 0, &db_tx)?;
+        }
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    /// Record a broadcast transaction and mark the notes it spends
+    #[allow(clippy::too_many_arguments)]
+    pub fn store_pending_tx(
+        &mut self,
+        account: u32,
+        txid: &[u8],
+        raw_tx: &[u8],
+        height: u32,
+        expiry_height: u32,
+        selected_notes: &[u32],
+        selected_orchard_notes: &[u32],
+    ) -> anyhow::Result<u32> {
+        let db_tx = self.begin_transaction()?;
+        db_tx.execute(
+            "INSERT INTO pending_txs(account, txid, raw_tx, height, expiry_height, confirmed)
+            VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
+            params![account, txid, raw_tx, height, expiry_height],
+        )?;
+        let id_tx = db_tx.last_insert_rowid() as u32;
+        for (pool, notes) in [(0u8, selected_notes), (1u8, selected_orchard_notes)].iter() {
+            for id_note in notes.iter() {
+                db_tx.execute(
+                    "INSERT INTO pending_tx_notes(pending_tx, pool, note) VALUES (?1, ?2, ?3)",
+                    params![id_tx, pool, id_note],
+                )?;
+            }
+        }
+        for id_note in selected_notes.iter() {
+            DbAdapter::mark_spent(*id_note, 0, &db_tx)?;
+        }
+        for id_note in selected_orchard_notes.iter() {
+            DbAdapter::mark_orchard_spent(*id_note, 0, &db_tx)?;
+        }
+        db_tx.commit()?;
+        Ok(id_tx)
+    }
+
+    /// Confirm the pending transactions that made it into the chain and release
+    /// the notes of those that expired by `height`
+    pub fn update_pending_txs(tx: &Transaction, height: u32) -> anyhow::Result<()> {
+        // A pending tx is confirmed when we see its txid or one of its nullifiers
+        tx.execute(
+            "UPDATE pending_txs SET confirmed = COALESCE(
+            (SELECT MAX(t.height) FROM transactions t WHERE t.txid = pending_txs.txid),
+            (SELECT MAX(n.spent) FROM pending_tx_notes p JOIN received_notes n ON p.note = n.id_note
+                WHERE p.pending_tx = pending_txs.id_tx AND p.pool = 0 AND n.spent > 0),
+            (SELECT MAX(n.spent) FROM pending_tx_notes p JOIN received_orchard_notes n ON p.note = n.id_note
+                WHERE p.pending_tx = pending_txs.id_tx AND p.pool = 1 AND n.spent > 0))
+            WHERE confirmed IS NULL",
+            [],
+        )?;
+        DbAdapter::release_pending_txs(
+            tx,
+            "confirmed IS NULL AND expiry_height > 0 AND expiry_height <= ?1",
+            height,
+        )?;
+        // Keep confirmed entries while they can still be reorged out
+        tx.execute(
+            "DELETE FROM pending_tx_notes WHERE pending_tx IN
+            (SELECT id_tx FROM pending_txs WHERE confirmed < ?1)",
+            params![height.saturating_sub(100)],
+        )?;
+        tx.execute(
+            "DELETE FROM pending_txs WHERE confirmed < ?1",
+            params![height.saturating_sub(100)],
+        )?;
+        Ok(())
+    }
+
+    /// Drop the pending transactions that match `filter` and make their
+    /// notes spendable again
+    fn release_pending_txs(tx: &Transaction, filter: &str, param: u32) -> anyhow::Result<()> {
+        for (pool, table) in [(0u8, "received_notes"), (1u8, "received_orchard_notes")].iter() {
+            tx.execute(
+                &format!(
+                    "UPDATE {} SET spent = NULL WHERE spent = 0 AND id_note IN
+                    (SELECT p.note FROM pending_tx_notes p JOIN pending_txs t ON p.pending_tx = t.id_tx
+                    WHERE p.pool = ?2 AND {})",
+                    table, filter
+                ),
+                params![param, pool],
+            )?;
+        }
+        tx.execute(
+            &format!(
+                "DELETE FROM pending_tx_notes WHERE pending_tx IN
+                (SELECT id_tx FROM pending_txs WHERE {})",
+                filter
+            ),
+            params![param],
+        )?;
+        tx.execute(
+            &format!("DELETE FROM pending_txs WHERE {}", filter),
+            params![param],
+        )?;
+        Ok(())
+    }
 
-            let pa = fvk.fvk.vk.to_payment_address(diversifier).unwrap();
-            let note = pa.create_note(value as u64, rseed).unwrap();
-            Ok(SpendableNote {
-                id: id_note,
-                note,
-                diversifier,
-                witness,
+    pub fn get_pending_txs(&self, account: u32) -> anyhow::Result<Vec<PendingTxRec>> {
+        let mut s = self.connection.prepare(
+            "SELECT id_tx, txid, height, expiry_height, confirmed FROM pending_txs WHERE account = ?1",
+        )?;
+        let rows = s.query_map(params![account], |row| {
+            let id: u32 = row.get(0)?;
+            let mut txid: Vec<u8> = row.get(1)?;
+            txid.reverse();
+            let txid = hex::encode(txid);
+            let height: u32 = row.get(2)?;
+            let expiry_height: u32 = row.get(3)?;
+            let confirmed: Option<u32> = row.get(4)?;
+            Ok(PendingTxRec {
+                id,
+                txid,
+                height,
+                expiry_height,
+                confirmed,
             })
         })?;
-        let mut spendable_notes: Vec<SpendableNote> = vec![];
-        for n in notes {
-            spendable_notes.push(n?);
+        let mut txs = vec![];
+        for r in rows {
+            txs.push(r?);
         }
+        Ok(txs)
+    }
 
-        Ok(spendable_notes)
+    /// Returns the raw transaction, its expiry height and its confirmation height
+    pub fn get_pending_tx(
+        &self,
+        id_tx: u32,
+    ) -> anyhow::Result<Option<(Vec<u8>, u32, Option<u32>)>> {
+        let tx = self
+            .connection
+            .query_row(
+                "SELECT raw_tx, expiry_height, confirmed FROM pending_txs WHERE id_tx = ?1",
+                params![id_tx],
+                |row| {
+                    let raw_tx: Vec<u8> = row.get(0)?;
+                    let expiry_height: u32 = row.get(1)?;
+                    let confirmed: Option<u32> = row.get(2)?;
+                    Ok((raw_tx, expiry_height, confirmed))
+                },
+            )
+            .optional()?;
+        Ok(tx)
     }
 
-    pub fn tx_mark_spend(&mut self, selected_notes: &[u32]) -> anyhow::Result<()> {
+    pub fn abandon_pending_tx(&mut self, id_tx: u32) -> anyhow::Result<()> {
         let db_tx = self.begin_transaction()?;
-        for id_note in selected_notes.iter() {
-            DbAdapter::mark_spent(*id_note, 0, &db_tx)?;
-        }
+        DbAdapter::release_pending_txs(&db_tx, "confirmed IS NULL AND id_tx = ?1", id_tx)?;
         db_tx.commit()?;
         Ok(())
     }
@@ -534,7 +1199,21 @@ impl DbAdapter {
         Ok(())
     }
 
-    pub fn purge_old_witnesses(&self, height: u32) -> anyhow::Result<()> {
+    pub fn mark_orchard_spent(id: u32, height: u32, tx: &Transaction) -> anyhow::Result<()> {
+        tx.execute(
+            "UPDATE received_orchard_notes SET spent = ?1 WHERE id_note = ?2",
+            params![height, id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete the tree states and witnesses older than `height`, except for
+    /// the last one of every `checkpoint_interval` blocks (0 for none)
+    pub fn purge_old_witnesses(
+        &mut self,
+        height: u32,
+        checkpoint_interval: u32,
+    ) -> anyhow::Result<()> {
         log::debug!("+purge_old_witnesses");
         let min_height: Option<u32> = self.connection.query_row(
             "SELECT MAX(height) FROM sapling_witnesses WHERE height <= ?1",
@@ -545,12 +1224,18 @@ impl DbAdapter {
         // Leave at least one sapling witness
         if let Some(min_height) = min_height {
             log::debug!("Purging witnesses older than {}", min_height);
-            self.connection.execute(
-                "DELETE FROM sapling_witnesses WHERE height < ?1",
-                params![min_height],
-            )?;
-            self.connection
-                .execute("DELETE FROM blocks WHERE height < ?1", params![min_height])?;
+            let db_tx = self.connection.transaction()?;
+            for table in ["sapling_witnesses", "orchard_witnesses", "blocks"].iter() {
+                db_tx.execute(
+                    &format!(
+                        "DELETE FROM {} WHERE height < ?1 AND height NOT IN
+                        (SELECT MAX(height) FROM blocks WHERE height < ?1 AND ?2 > 0 GROUP BY height / ?2)",
+                        table
+                    ),
+                    params![min_height, checkpoint_interval],
+                )?;
+            }
+            db_tx.commit()?;
         }
         log::debug!("-purge_old_witnesses");
         Ok(())
@@ -745,6 +1430,78 @@ impl DbAdapter {
         Ok(())
     }
 
+    pub fn create_orchard(&self, account: u32) -> anyhow::Result<()> {
+        let (seed, index) = self.get_seed(account)?;
+        if let Some(seed) = seed {
+            let (sk, fvk) = derive_orchard_keys(self.network(), &seed, index)?;
+            self.connection.execute(
+                "INSERT INTO orchard_addrs(account, sk, fvk) VALUES (?1, ?2, ?3) \
+            ON CONFLICT DO NOTHING",
+                params![account, &sk.to_bytes()[..], &fvk.to_bytes()[..]],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Derive the Orchard keys of the seed accounts created before the Orchard
+    /// migration
+    fn create_missing_orchard_keys(&self) -> anyhow::Result<()> {
+        let mut statement = self.connection.prepare(
+            "SELECT id_account FROM accounts a WHERE seed IS NOT NULL \
+            AND NOT EXISTS (SELECT 1 FROM orchard_addrs o WHERE o.account = a.id_account)",
+        )?;
+        let rows = statement.query_map([], |row| row.get::<_, u32>(0))?;
+        let mut accounts = vec![];
+        for row in rows {
+            accounts.push(row?);
+        }
+        for account in accounts {
+            if let Err(err) = self.create_orchard(account) {
+                log::warn!("No Orchard keys for account {}: {}", account, err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_orchard_fvk(&self, account: u32) -> anyhow::Result<Option<FullViewingKey>> {
+        let fvk = self
+            .connection
+            .query_row(
+                "SELECT fvk FROM orchard_addrs WHERE account = ?1",
+                params![account],
+                |row| {
+                    let fvk: Vec<u8> = row.get(0)?;
+                    Ok(fvk)
+                },
+            )
+            .optional()?;
+        Ok(fvk.and_then(|fvk| decode_orchard_fvk(&fvk)))
+    }
+
+    pub fn get_orchard_sk(&self, account: u32) -> anyhow::Result<Option<SpendingKey>> {
+        let sk = self
+            .connection
+            .query_row(
+                "SELECT sk FROM orchard_addrs WHERE account = ?1",
+                params![account],
+                |row| {
+                    let sk: Option<Vec<u8>> = row.get(0)?;
+                    Ok(sk)
+                },
+            )
+            .optional()?
+            .flatten();
+        let sk = sk.and_then(|sk| {
+            let mut sk_bytes = [0u8; 32];
+            if sk.len() != sk_bytes.len() {
+                return None;
+            }
+            sk_bytes.copy_from_slice(&sk);
+            SpendingKey::from_bytes(sk_bytes).into()
+        });
+        Ok(sk)
+    }
+
     pub fn store_historical_prices(
         &mut self,
         prices: &[Quote],
@@ -815,8 +1572,17 @@ impl DbAdapter {
         self.connection.execute("DELETE FROM received_notes", [])?;
         self.connection
             .execute("DELETE FROM sapling_witnesses", [])?;
+        self.connection
+            .execute("DELETE FROM received_orchard_notes", [])?;
+        self.connection
+            .execute("DELETE FROM orchard_witnesses", [])?;
         self.connection.execute("DELETE FROM transactions", [])?;
         self.connection.execute("DELETE FROM messages", [])?;
+        self.connection
+            .execute("DELETE FROM pending_tx_notes", [])?;
+        self.connection.execute("DELETE FROM pending_txs", [])?;
+        self.connection.execute("DELETE FROM utxos", [])?;
+        self.connection.execute("DELETE FROM transparent_txs", [])?;
         Ok(())
     }
 
@@ -825,6 +1591,14 @@ impl DbAdapter {
             "DELETE FROM received_notes WHERE account = ?1",
             params![account],
         )?;
+        self.connection.execute(
+            "DELETE FROM received_orchard_notes WHERE account = ?1",
+            params![account],
+        )?;
+        self.connection.execute(
+            "DELETE FROM orchard_addrs WHERE account = ?1",
+            params![account],
+        )?;
         self.connection.execute(
             "DELETE FROM transactions WHERE account = ?1",
             params![account],
@@ -841,10 +1615,28 @@ impl DbAdapter {
             .execute("DELETE FROM taddrs WHERE account = ?1", params![account])?;
         self.connection
             .execute("DELETE FROM messages WHERE account = ?1", params![account])?;
+        self.connection.execute(
+            "DELETE FROM pending_tx_notes WHERE pending_tx IN (SELECT id_tx FROM pending_txs WHERE account = ?1)",
+            params![account],
+        )?;
+        self.connection.execute(
+            "DELETE FROM pending_txs WHERE account = ?1",
+            params![account],
+        )?;
         self.connection.execute(
             "DELETE FROM secret_shares WHERE account = ?1",
             params![account],
         )?;
+        self.connection
+            .execute("DELETE FROM utxos WHERE account = ?1", params![account])?;
+        self.connection.execute(
+            "DELETE FROM transparent_txs WHERE account = ?1",
+            params![account],
+        )?;
+        self.connection.execute(
+            "DELETE FROM taddr_scan WHERE account = ?1",
+            params![account],
+        )?;
         Ok(())
     }
 
@@ -933,24 +1725,142 @@ impl DbAdapter {
         Ok(())
     }
 
-    pub fn store_t_scan(&self, addresses: &[TBalance]) -> anyhow::Result<()> {
-        self.connection.execute(
-            "CREATE TABLE IF NOT EXISTS taddr_scan(\
-            id INTEGER NOT NULL PRIMARY KEY,
-            address TEXT NOT NULL,
-            value INTEGER NOT NULL,
-            aindex INTEGER NOT NULL)",
-            [],
-        )?;
+    pub fn store_t_scan(&self, account: u32, addresses: &[TBalance]) -> anyhow::Result<()> {
         for addr in addresses.iter() {
             self.connection.execute(
-                "INSERT INTO taddr_scan(address, value, aindex) VALUES (?1, ?2, ?3)",
-                params![addr.address, addr.balance, addr.index],
+                "INSERT INTO taddr_scan(address, value, aindex, account) VALUES (?1, ?2, ?3, ?4)",
+                params![addr.address, addr.balance, addr.index, account],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Transparent addresses of every account: the main one and those found by `taddr_scan`
+    pub fn get_transparent_addresses(&self) -> anyhow::Result<Vec<(u32, String)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT account, address FROM taddrs UNION
+            SELECT account, address FROM taddr_scan WHERE account IS NOT NULL",
+        )?;
+        let rows = statement.query_map([], |row| {
+            let account: u32 = row.get(0)?;
+            let address: String = row.get(1)?;
+            Ok((account, address))
+        })?;
+        let mut addresses = vec![];
+        for r in rows {
+            addresses.push(r?);
+        }
+        Ok(addresses)
+    }
+
+    /// Height of the last transaction seen on a transparent address
+    pub fn get_transparent_sync_height(
+        &self,
+        account: u32,
+        address: &str,
+    ) -> anyhow::Result<Option<u32>> {
+        let height = self.connection.query_row(
+            "SELECT MAX(MAX(height), COALESCE(MAX(spent), 0)) FROM utxos
+            WHERE account = ?1 AND address = ?2",
+            params![account, address],
+            |row| row.get(0),
+        )?;
+        Ok(height)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn store_utxo(
+        account: u32,
+        txid: &[u8],
+        vout: u32,
+        address: &str,
+        script: &[u8],
+        value: i64,
+        height: u32,
+        db_tx: &Transaction,
+    ) -> anyhow::Result<()> {
+        db_tx.execute(
+            "INSERT INTO utxos(account, txid, vout, address, script, value, height)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) ON CONFLICT DO NOTHING",
+            params![account, txid, vout, address, script, value, height],
+        )?;
+        Ok(())
+    }
+
+    /// Mark the utxo of `address` as spent at `height` and return its value, or None if it is not ours
+    pub fn spend_utxo(
+        account: u32,
+        address: &str,
+        txid: &[u8],
+        vout: u32,
+        height: u32,
+        db_tx: &Transaction,
+    ) -> anyhow::Result<Option<i64>> {
+        let value = db_tx
+            .query_row(
+                "SELECT value FROM utxos WHERE account = ?1 AND address = ?2 AND txid = ?3 AND vout = ?4",
+                params![account, address, txid, vout],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if value.is_some() {
+            db_tx.execute(
+                "UPDATE utxos SET spent = ?5 WHERE account = ?1 AND address = ?2 AND txid = ?3 AND vout = ?4",
+                params![account, address, txid, vout, height],
             )?;
         }
+        Ok(value)
+    }
+
+    /// Add the transparent value of a transaction to the account history
+    ///
+    /// Does nothing if the transaction was already recorded
+    pub fn store_transparent_tx(
+        account: u32,
+        txid: &[u8],
+        height: u32,
+        timestamp: u32,
+        value: i64,
+        address: &str,
+        db_tx: &Transaction,
+    ) -> anyhow::Result<()> {
+        let inserted = db_tx.execute(
+            "INSERT INTO transparent_txs(account, txid, height, value) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT DO NOTHING",
+            params![account, txid, height, value],
+        )?;
+        if inserted == 0 {
+            return Ok(());
+        }
+        let id_tx: Option<u32> = db_tx
+            .query_row(
+                "SELECT id_tx FROM transactions WHERE account = ?1 AND txid = ?2",
+                params![account, txid],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match id_tx {
+            Some(id_tx) => Self::add_value(id_tx, value, db_tx)?,
+            None => {
+                db_tx.execute(
+                    "INSERT INTO transactions(account, txid, height, timestamp, value, address, memo)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, '')",
+                    params![account, txid, height, timestamp, value, address],
+                )?;
+            }
+        }
         Ok(())
     }
 
+    pub fn get_transparent_balance(&self, account: u32) -> anyhow::Result<u64> {
+        let balance: Option<i64> = self.connection.query_row(
+            "SELECT SUM(value) FROM utxos WHERE spent IS NULL AND account = ?1",
+            params![account],
+            |row| row.get(0),
+        )?;
+        Ok(balance.unwrap_or(0) as u64)
+    }
+
     pub fn get_accounts(&self) -> anyhow::Result<Vec<AccountRec>> {
         let mut s = self
             .connection
@@ -1005,6 +1915,63 @@ impl DbAdapter {
     }
 }
 
+fn decode_orchard_fvk(fvk: &[u8]) -> Option<FullViewingKey> {
+    let mut fvk_bytes = [0u8; 96];
+    if fvk.len() != fvk_bytes.len() {
+        return None;
+    }
+    fvk_bytes.copy_from_slice(fvk);
+    FullViewingKey::from_bytes(&fvk_bytes)
+}
+
+/// Rebuild an Orchard note from its stored parts
+fn decode_orchard_note(
+    fvk: &FullViewingKey,
+    diversifier: &[u8],
+    value: u64,
+    rho: &[u8],
+    rseed: &[u8],
+    nf: &[u8],
+) -> Option<orchard::Note> {
+    decode_orchard_note_with_scope(fvk, diversifier, value, rho, rseed, nf).map(|(note, _)| note)
+}
+
+/// Rebuild an Orchard note and find the scope of its receiver
+///
+/// The scope of the receiver is not stored. Change notes are received
+/// on the internal scope and are recognized by their nullifier.
+fn decode_orchard_note_with_scope(
+    fvk: &FullViewingKey,
+    diversifier: &[u8],
+    value: u64,
+    rho: &[u8],
+    rseed: &[u8],
+    nf: &[u8],
+) -> Option<(orchard::Note, Scope)> {
+    let mut diversifier_bytes = [0u8; 11];
+    let mut rho_bytes = [0u8; 32];
+    let mut rseed_bytes = [0u8; 32];
+    if diversifier.len() != 11 || rho.len() != 32 || rseed.len() != 32 {
+        return None;
+    }
+    diversifier_bytes.copy_from_slice(diversifier);
+    rho_bytes.copy_from_slice(rho);
+    rseed_bytes.copy_from_slice(rseed);
+    let diversifier = OrchardDiversifier::from_bytes(diversifier_bytes);
+    let rho: Option<Nullifier> = Nullifier::from_bytes(&rho_bytes).into();
+    let rho = rho?;
+    let rseed: Option<RandomSeed> = RandomSeed::from_bytes(rseed_bytes, &rho).into();
+    let rseed = rseed?;
+    [Scope::External, Scope::Internal]
+        .iter()
+        .map(|scope| {
+            let recipient = fvk.address(diversifier, *scope);
+            let note = orchard::Note::from_parts(recipient, NoteValue::from_raw(value), rho, rseed);
+            (note, *scope)
+        })
+        .find(|(note, _)| note.nullifier(fvk).to_bytes()[..] == *nf)
+}
+
 fn get_coin_id_by_address(address: &str) -> u8 {
     if address.starts_with("ys") {
         1
@@ -1038,6 +2005,15 @@ pub struct TxRec {
     memo: String,
 }
 
+#[derive(Serialize)]
+pub struct PendingTxRec {
+    id: u32,
+    txid: String,
+    height: u32,
+    expiry_height: u32,
+    confirmed: Option<u32>,
+}
+
 #[derive(Serialize)]
 pub struct AccountRec {
     id_account: u32,
@@ -1047,8 +2023,16 @@ pub struct AccountRec {
 
 #[cfg(test)]
 mod tests {
-    use crate::db::{DbAdapter, ReceivedNote, DEFAULT_DB_PATH};
-    use crate::{CTree, Witness};
+    use crate::commitment::OrchardTree;
+    use crate::db::{DbAdapter, NoteId, Pool, ReceivedNote, ReceivedOrchardNote, DEFAULT_DB_PATH};
+    use crate::synthetic::fixtures::{fvk, TestChain, TestDb};
+    use crate::transaction::retrieve_tx_info;
+    use crate::{connect_lightwalletd, CTree, Witness};
+    use chrono::Utc;
+    use orchard::keys::Scope;
+    use orchard::note::{Nullifier, RandomSeed};
+    use orchard::value::NoteValue;
+    use rusqlite::params;
     use zcash_params::coin::CoinType;
 
     #[test]
@@ -1094,4 +2078,157 @@ mod tests {
         let balance = db.get_balance(1).unwrap();
         println!("{}", balance);
     }
+
+    #[test]
+    fn test_orchard_migration() -> anyhow::Result<()> {
+        let test_db = TestDb::new("orchard_migration", &[&fvk(1)])?;
+        let db = test_db.open()?;
+        let account = test_db.accounts[0];
+        // a seed account and a block stored before the Orchard migration
+        let seed = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        db.connection.execute(
+            "UPDATE accounts SET seed = ?1 WHERE id_account = ?2",
+            params![seed, account],
+        )?;
+        db.connection.execute(
+            "INSERT INTO blocks(height, hash, timestamp, sapling_tree) VALUES (1, X'00', 0, X'00')",
+            [],
+        )?;
+        assert!(db.get_orchard_fvk(account)?.is_none());
+        assert!(db.get_orchard_tree().is_err());
+        assert_eq!(db.get_missing_orchard_tree_height()?, Some(1));
+
+        db.init_db()?;
+        assert!(db.get_orchard_fvk(account)?.is_some());
+        db.store_orchard_tree(1, &OrchardTree::empty())?;
+        assert_eq!(db.get_missing_orchard_tree_height()?, None);
+        assert!(db.get_orchard_tree().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_notes_of_account() -> anyhow::Result<()> {
+        let test_db = TestDb::new("notes_of_account", &[&fvk(1), &fvk(2)])?;
+        let mut db = test_db.open()?;
+        let (account1, account2) = (test_db.accounts[0], test_db.accounts[1]);
+        let seed = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        db.connection.execute(
+            "UPDATE accounts SET seed = ?1 WHERE id_account = ?2",
+            params![seed, account1],
+        )?;
+        db.init_db()?;
+        let orchard_fvk = db.get_orchard_fvk(account1)?.unwrap();
+
+        // an Orchard change note of the first account
+        let rho = Nullifier::from_bytes(&[0u8; 32]).unwrap();
+        let rseed = (0..=255u8)
+            .find_map(|i| Option::from(RandomSeed::from_bytes([i; 32], &rho)))
+            .unwrap();
+        let recipient = orchard_fvk.address_at(0u32, Scope::Internal);
+        let note = orchard::Note::from_parts(recipient, NoteValue::from_raw(1_000), rho, rseed);
+        let db_tx = db.begin_transaction()?;
+        let id_tx = DbAdapter::store_transaction(&[1; 32], account1, 1, 0, 0, &db_tx)?;
+        let id_orchard_note = DbAdapter::store_received_orchard_note(
+            &ReceivedOrchardNote {
+                account: account1,
+                height: 1,
+                output_index: 0,
+                diversifier: recipient.diversifier().as_array().to_vec(),
+                value: 1_000,
+                rho: rho.to_bytes().to_vec(),
+                rseed: rseed.as_bytes().to_vec(),
+                nf: note.nullifier(&orchard_fvk).to_bytes().to_vec(),
+            },
+            id_tx,
+            0,
+            &db_tx,
+        )?;
+        // a Sapling note of the second account
+        let id_tx = DbAdapter::store_transaction(&[2; 32], account2, 1, 0, 1, &db_tx)?;
+        let id_note = DbAdapter::store_received_note(
+            &ReceivedNote {
+                account: account2,
+                height: 1,
+                output_index: 0,
+                diversifier: vec![0; 11],
+                value: 2_000,
+                rcm: vec![],
+                nf: vec![2; 32],
+                spent: None,
+            },
+            id_tx,
+            1,
+            &db_tx,
+        )?;
+        db_tx.commit()?;
+
+        let notes = db.get_notes(account1)?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].scope, Some(Scope::Internal));
+
+        // the notes of another account cannot be frozen
+        let ids = [
+            NoteId {
+                pool: Pool::Orchard,
+                id_note: id_orchard_note,
+            },
+            NoteId {
+                pool: Pool::Sapling,
+                id_note,
+            },
+        ];
+        db.exclude_notes(account1, &ids, true)?;
+        assert!(db.get_notes(account1)?[0].excluded);
+        assert!(!db.get_notes(account2)?[0].excluded);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_pending_tx_details() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let server = tc.start().await?;
+        let test_db = tc.create_db("pending_tx_details", 1)?;
+        test_db.sync(server.url(), 0).await?;
+        let db = test_db.open()?;
+        let pending = db.get_pending_tx_details(None)?;
+        assert_eq!(pending.len(), 3);
+
+        // the server does not have the raw transactions: they stay pending
+        let mut client = connect_lightwalletd(server.url()).await?;
+        retrieve_tx_info(db.coin_type, &mut client, &test_db.path, &pending).await?;
+        assert_eq!(db.get_pending_tx_details(None)?, pending);
+        let now = Utc::now().timestamp() as u32;
+        assert!(db.get_pending_tx_details(Some(now))?.is_empty());
+        assert_eq!(db.get_pending_tx_details(Some(now + 60))?, pending);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_times() -> anyhow::Result<()> {
+        let mut db = DbAdapter::new(CoinType::Zcash, ":memory:")?;
+        db.init_db()?;
+        DbAdapter::store_block_times(&db.connection, &[(1_000, 100_000), (1_500, 137_500)])?;
+        DbAdapter::store_block_times(&db.connection, &[(2_000, 175_000), (2_100, 182_500)])?;
+        assert_eq!(db.get_height_by_time(100_000)?, Some(1_000));
+        assert_eq!(db.get_height_by_time(118_750)?, Some(1_250));
+        assert_eq!(db.get_height_by_time(176_000)?, Some(2_013));
+        assert_eq!(db.get_height_by_time(182_500)?, Some(2_100));
+        assert_eq!(db.get_height_by_time(50_000)?, None);
+        assert_eq!(db.get_height_by_time(200_000)?, None);
+
+        // only the latest block time is kept after a multiple of the interval
+        DbAdapter::store_block_times(&db.connection, &[(2_200, 190_000)])?;
+        assert_eq!(db.get_block_time(2_100)?, None);
+        assert_eq!(db.get_block_time(1_500)?, Some(137_500));
+
+        // no estimate across a gap in the recorded block times
+        DbAdapter::store_block_times(&db.connection, &[(5_000, 400_000)])?;
+        assert_eq!(db.get_height_by_time(300_000)?, None);
+        assert_eq!(db.get_height_by_time(190_000)?, Some(2_200));
+
+        db.trim_to_height(2_000)?;
+        assert_eq!(db.get_block_time(2_200)?, None);
+        assert_eq!(db.get_height_by_time(176_000)?, None);
+        Ok(())
+    }
 }
@@ -28,6 +28,12 @@ pub fn reset_db(connection: &Connection) -> anyhow::Result<()> {
     connection.execute("DROP TABLE sapling_witnesses", [])?;
     connection.execute("DROP TABLE diversifiers", [])?;
     connection.execute("DROP TABLE historical_prices", [])?;
+    connection.execute("DROP TABLE received_orchard_notes", [])?;
+    connection.execute("DROP TABLE orchard_witnesses", [])?;
+    connection.execute("DROP TABLE pending_txs", [])?;
+    connection.execute("DROP TABLE pending_tx_notes", [])?;
+    connection.execute("DROP TABLE utxos", [])?;
+    connection.execute("DROP TABLE transparent_txs", [])?;
     update_schema_version(connection, 0)?;
     Ok(())
 }
@@ -173,8 +179,161 @@ pub fn init_db(connection: &Connection) -> anyhow::Result<()> {
         // )?;
     }
 
-    if version != 3 {
-        update_schema_version(connection, 3)?;
+    if version < 4 {
+        // Orchard keys are derived from the seed and only exist for seed based accounts
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS orchard_addrs (
+            account INTEGER PRIMARY KEY NOT NULL,
+            sk BLOB,
+            fvk BLOB NOT NULL)",
+            [],
+        )?;
+
+        connection.execute("ALTER TABLE blocks ADD COLUMN orchard_tree BLOB", [])?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS received_orchard_notes (
+            id_note INTEGER PRIMARY KEY,
+            account INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            tx INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            output_index INTEGER NOT NULL,
+            diversifier BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            rho BLOB NOT NULL,
+            rseed BLOB NOT NULL,
+            nf BLOB NOT NULL UNIQUE,
+            spent INTEGER,
+            excluded BOOL,
+            CONSTRAINT tx_output UNIQUE (tx, output_index))",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS orchard_witnesses (
+            id_witness INTEGER PRIMARY KEY,
+            note INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            witness BLOB NOT NULL,
+            CONSTRAINT witness_height UNIQUE (note, height))",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS i_received_orchard_notes ON received_orchard_notes(account)",
+            [],
+        )?;
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS i_orchard_witness ON orchard_witnesses(height)",
+            [],
+        )?;
+    }
+
+    if version < 5 {
+        // Outgoing transactions that have not been seen in a block yet
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS pending_txs (
+            id_tx INTEGER PRIMARY KEY,
+            account INTEGER NOT NULL,
+            txid BLOB NOT NULL UNIQUE,
+            raw_tx BLOB NOT NULL,
+            height INTEGER NOT NULL,
+            expiry_height INTEGER NOT NULL,
+            confirmed INTEGER)",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS pending_tx_notes (
+            pending_tx INTEGER NOT NULL,
+            pool INTEGER NOT NULL,
+            note INTEGER NOT NULL,
+            PRIMARY KEY (pending_tx, pool, note))",
+            [],
+        )?;
+    }
+
+    if version < 6 {
+        // Height at which an account may have received its first note
+        connection.execute("ALTER TABLE accounts ADD COLUMN birthday INTEGER", [])?;
+    }
+
+    if version < 7 {
+        // Outputs received by the transparent addresses of the accounts
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS utxos (
+            id_utxo INTEGER PRIMARY KEY,
+            account INTEGER NOT NULL,
+            txid BLOB NOT NULL,
+            vout INTEGER NOT NULL,
+            address TEXT NOT NULL,
+            script BLOB NOT NULL,
+            value INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            spent INTEGER,
+            CONSTRAINT utxo_outpoint UNIQUE (account, txid, vout))",
+            [],
+        )?;
+
+        // Net transparent value of a transaction, already added to `transactions`
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS transparent_txs (
+            account INTEGER NOT NULL,
+            txid BLOB NOT NULL,
+            height INTEGER NOT NULL,
+            value INTEGER NOT NULL,
+            PRIMARY KEY (account, txid))",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS taddr_scan(
+            id INTEGER NOT NULL PRIMARY KEY,
+            address TEXT NOT NULL,
+            value INTEGER NOT NULL,
+            aindex INTEGER NOT NULL)",
+            [],
+        )?;
+        connection.execute("ALTER TABLE taddr_scan ADD COLUMN account INTEGER", [])?;
+    }
+
+    if version < 8 {
+        // Transactions whose memo and address have not been retrieved yet
+        connection.execute(
+            "ALTER TABLE transactions ADD COLUMN details_pending INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+        connection.execute(
+            "ALTER TABLE transactions ADD COLUMN details_attempts INTEGER NOT NULL DEFAULT 0",
+            [],
+        )?;
+        connection.execute(
+            "ALTER TABLE transactions ADD COLUMN details_retry_time INTEGER",
+            [],
+        )?;
+        connection.execute(
+            "UPDATE transactions SET details_pending = 1 WHERE memo IS NULL",
+            [],
+        )?;
+    }
+
+    if version < 9 {
+        // Time of some blocks, to find the height of a date without the server
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS block_times(
+            height INTEGER PRIMARY KEY,
+            timestamp INTEGER NOT NULL)",
+            [],
+        )?;
+        connection.execute(
+            "INSERT INTO block_times(height, timestamp) SELECT height, timestamp FROM blocks",
+            [],
+        )?;
+    }
+
+    if version != 9 {
+        update_schema_version(connection, 9)?;
         log::info!("Database migrated");
     }
 
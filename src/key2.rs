@@ -1,6 +1,7 @@
 use crate::coinconfig::CoinConfig;
 use bech32::{ToBase32, Variant};
 use bip39::{Language, Mnemonic, Seed};
+use orchard::keys::{FullViewingKey, SpendingKey};
 use rand::rngs::OsRng;
 use rand::RngCore;
 use zcash_client_backend::address::RecipientAddress;
@@ -82,6 +83,19 @@ fn derive_secret_key(
     Ok((sk, fvk, pa))
 }
 
+pub fn derive_orchard_keys(
+    network: &Network,
+    phrase: &str,
+    index: u32,
+) -> anyhow::Result<(SpendingKey, FullViewingKey)> {
+    let mnemonic = Mnemonic::from_phrase(phrase, Language::English)?;
+    let seed = Seed::new(&mnemonic, "");
+    let sk = SpendingKey::from_zip32_seed(seed.as_bytes(), network.coin_type(), index)
+        .map_err(|_| anyhow::anyhow!("Cannot derive Orchard key"))?;
+    let fvk = FullViewingKey::from(&sk);
+    Ok((sk, fvk))
+}
+
 fn derive_viewing_key(
     network: &Network,
     extsk: &ExtendedSpendingKey,
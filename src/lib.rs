@@ -19,6 +19,7 @@ pub const LWD_URL: &str = "https://mainnet.lightwalletd.com:9067";
 // YCash
 // pub const LWD_URL: &str = "https://lite.ycash.xyz:9067";
 
+mod block_cache;
 mod builder;
 mod chain;
 mod coinconfig;
@@ -29,13 +30,18 @@ mod fountain;
 mod hash;
 mod key;
 mod key2;
+mod lwd_mock;
 mod mempool;
 mod misc;
 mod pay;
 mod prices;
 mod print;
+mod proxy;
 mod scan;
+mod servers;
+mod synthetic;
 mod taddr;
+mod tls;
 mod transaction;
 mod ua;
 mod zip32;
@@ -62,26 +68,36 @@ pub fn hex_to_hash(hex: &str) -> anyhow::Result<[u8; 32]> {
     Ok(hash)
 }
 
+pub use crate::block_cache::BlockCache;
 pub use crate::builder::advance_tree;
 pub use crate::chain::{
-    calculate_tree_state_v2, connect_lightwalletd, download_chain, get_best_server,
-    get_latest_height, ChainError, DecryptNode,
+    calculate_tree_state_v2, connect_lightwalletd, connect_lightwalletd_with, download_chain,
+    get_latest_height, ChainError, ConnectionSettings, DecryptNode,
 };
 pub use crate::coinconfig::{
-    init_coin, set_active, set_active_account, set_coin_lwd_url, CoinConfig,
+    init_coin, set_active, set_active_account, set_coin_block_cache, set_coin_lwd_servers,
+    set_coin_lwd_url, set_coin_proxy, set_coin_tls_settings, set_coin_tree_check_interval,
+    set_coin_witness_retention, CoinConfig, WitnessRetention,
 };
 pub use crate::commitment::{CTree, Witness};
-pub use crate::db::{AccountRec, DbAdapter, TxRec};
+pub use crate::db::{AccountRec, AccountViewKey, DbAdapter, PendingTxRec, TxRec};
 pub use crate::fountain::{put_drop, FountainCodes, RaptorQDrops};
 pub use crate::hash::pedersen_hash;
 pub use crate::key::{generate_random_enc_key, KeyHelpers};
 pub use crate::lw_rpc::compact_tx_streamer_client::CompactTxStreamerClient;
 pub use crate::lw_rpc::*;
+pub use crate::lwd_mock::{MockLightwalletd, MockServer};
 pub use crate::mempool::MemPool;
 pub use crate::misc::read_zwl;
-pub use crate::pay::{broadcast_tx, get_tx_summary, Tx, TxIn, TxOut};
+pub use crate::pay::{broadcast_tx, get_tx_summary, OrchardTxIn, Tx, TxIn, TxOut};
 pub use crate::print::*;
-pub use crate::scan::{latest_height, sync_async};
+pub use crate::proxy::Proxy;
+pub use crate::scan::{
+    latest_height, sync_async, PhaseTimings, SyncControl, SyncPhase, SyncProgress,
+};
+pub use crate::servers::{check_servers, get_best_server, ServerReport, ServerStatus};
+pub use crate::synthetic::{SyntheticChain, SyntheticNote};
+pub use crate::tls::TlsSettings;
 pub use crate::ua::{get_sapling, get_ua};
 pub use zip32::{derive_zip32, KeyPack};
 // pub use crate::wallet::{decrypt_backup, encrypt_backup, RecipientMemo, Wallet, WalletBalance};
@@ -0,0 +1,759 @@
+use crate::lw_rpc::compact_tx_streamer_server::{CompactTxStreamer, CompactTxStreamerServer};
+use crate::lw_rpc::*;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+use zcash_primitives::consensus::{BlockHeight, BranchId, Network};
+use zcash_primitives::transaction::Transaction;
+
+type ResponseStream<T> = BoxStream<'static, Result<T, Status>>;
+
+struct MockTx {
+    raw: RawTransaction,
+    addresses: Vec<String>,
+}
+
+#[derive(Default)]
+struct MockChain {
+    chain_name: String,
+    sapling_activation_height: u32,
+    blocks: BTreeMap<u32, CompactBlock>,
+    tree_states: BTreeMap<u32, TreeState>,
+    transactions: HashMap<Vec<u8>, MockTx>,
+    utxos: Vec<GetAddressUtxosReply>,
+    mempool: Vec<CompactTx>,
+    sent: Vec<RawTransaction>,
+    nonce: u64,
+}
+
+impl MockChain {
+    fn find_block(&self, id: &BlockId) -> Option<&CompactBlock> {
+        if id.hash.is_empty() {
+            self.blocks.get(&(id.height as u32))
+        } else {
+            self.blocks.values().find(|b| b.hash == id.hash)
+        }
+    }
+
+    fn find_height(&self, id: &BlockId) -> Result<u32, Status> {
+        if id.hash.is_empty() {
+            return Ok(id.height as u32);
+        }
+        self.find_block(id)
+            .map(|b| b.height as u32)
+            .ok_or_else(|| Status::not_found("Unknown block hash"))
+    }
+
+    fn network(&self) -> Network {
+        if self.chain_name == "test" {
+            Network::TestNetwork
+        } else {
+            Network::MainNetwork
+        }
+    }
+
+    fn utxos(&self, addresses: &[String], start_height: u64) -> Vec<GetAddressUtxosReply> {
+        let mut utxos: Vec<_> = self
+            .utxos
+            .iter()
+            .filter(|u| addresses.contains(&u.address) && u.height >= start_height)
+            .cloned()
+            .collect();
+        utxos.sort_by_key(|u| u.height);
+        utxos
+    }
+
+    fn balance(&self, addresses: &[String]) -> i64 {
+        self.utxos(addresses, 0).iter().map(|u| u.value_zat).sum()
+    }
+}
+
+/*
+In-process lightwalletd for tests and offline development
+
+The chain is kept in memory and can be modified while the server runs,
+for instance to simulate a reorg with `rollback` followed by `mine_blocks`.
+It is filled programmatically or from a JSON fixture file where protobuf
+messages are hex encoded.
+ */
+#[derive(Clone)]
+pub struct MockLightwalletd {
+    chain: Arc<RwLock<MockChain>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct MockFixture {
+    chain_name: String,
+    sapling_activation_height: u32,
+    blocks: Vec<String>,
+    tree_states: Vec<String>,
+    transactions: Vec<MockFixtureTx>,
+    utxos: Vec<String>,
+    mempool: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MockFixtureTx {
+    txid: String,
+    height: u32,
+    data: String,
+    #[serde(default)]
+    addresses: Vec<String>,
+}
+
+fn decode_hex<M: Message + Default>(s: &str) -> anyhow::Result<M> {
+    let data = hex::decode(s)?;
+    Ok(M::decode(&*data)?)
+}
+
+fn encode_hex<M: Message>(m: &M) -> String {
+    hex::encode(m.encode_to_vec())
+}
+
+impl MockLightwalletd {
+    /// `chain_name` is "main" or "test", like the value returned by `GetLightdInfo`
+    pub fn new(chain_name: &str, sapling_activation_height: u32) -> Self {
+        let chain = MockChain {
+            chain_name: chain_name.to_string(),
+            sapling_activation_height,
+            ..MockChain::default()
+        };
+        MockLightwalletd {
+            chain: Arc::new(RwLock::new(chain)),
+        }
+    }
+
+    pub fn from_fixture(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)?;
+        let fixture: MockFixture = serde_json::from_str(&data)?;
+        let lwd = Self::new(&fixture.chain_name, fixture.sapling_activation_height);
+        for b in fixture.blocks.iter() {
+            lwd.add_block(decode_hex(b)?);
+        }
+        for ts in fixture.tree_states.iter() {
+            lwd.add_tree_state(decode_hex(ts)?);
+        }
+        for tx in fixture.transactions.iter() {
+            let txid = hex::decode(&tx.txid)?;
+            let data = hex::decode(&tx.data)?;
+            lwd.add_transaction(&txid, &data, tx.height);
+            for address in tx.addresses.iter() {
+                lwd.add_taddress_tx(address, &txid)?;
+            }
+        }
+        for u in fixture.utxos.iter() {
+            lwd.add_utxo(decode_hex(u)?);
+        }
+        for tx in fixture.mempool.iter() {
+            lwd.add_mempool_tx(decode_hex(tx)?);
+        }
+        Ok(lwd)
+    }
+
+    pub fn save_fixture(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let chain = self.chain.read().unwrap();
+        let mut transactions: Vec<_> = chain
+            .transactions
+            .iter()
+            .map(|(txid, tx)| MockFixtureTx {
+                txid: hex::encode(txid),
+                height: tx.raw.height as u32,
+                data: hex::encode(&tx.raw.data),
+                addresses: tx.addresses.clone(),
+            })
+            .collect();
+        transactions.sort_by(|a, b| (a.height, &a.txid).cmp(&(b.height, &b.txid)));
+        let fixture = MockFixture {
+            chain_name: chain.chain_name.clone(),
+            sapling_activation_height: chain.sapling_activation_height,
+            blocks: chain.blocks.values().map(encode_hex).collect(),
+            tree_states: chain.tree_states.values().map(encode_hex).collect(),
+            transactions,
+            utxos: chain.utxos.iter().map(encode_hex).collect(),
+            mempool: chain.mempool.iter().map(encode_hex).collect(),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&fixture)?)?;
+        Ok(())
+    }
+
+    /// Add or replace the block at its height
+    pub fn add_block(&self, block: CompactBlock) {
+        let mut chain = self.chain.write().unwrap();
+        chain.blocks.insert(block.height as u32, block);
+    }
+
+    /// Append `count` empty blocks linked to the current tip
+    ///
+    /// The tree state of the tip, if any, is carried over to the new blocks
+    pub fn mine_blocks(&self, count: u32) {
+        let mut chain = self.chain.write().unwrap();
+        for _ in 0..count {
+            let (height, prev_hash, time) = match chain.blocks.values().next_back() {
+                Some(b) => (b.height as u32 + 1, b.hash.clone(), b.time + 75),
+                None => (chain.sapling_activation_height, vec![0u8; 32], 0),
+            };
+            chain.nonce += 1;
+            let mut hasher = Sha256::new();
+            hasher.update(&prev_hash);
+            hasher.update(height.to_le_bytes());
+            hasher.update(chain.nonce.to_le_bytes());
+            let hash = hasher.finalize().to_vec();
+            if let Some(ts) = chain.tree_states.get(&(height - 1)).cloned() {
+                let mut block_hash = hash.clone();
+                block_hash.reverse();
+                let ts = TreeState {
+                    height: height as u64,
+                    hash: hex::encode(&block_hash),
+                    time,
+                    ..ts
+                };
+                chain.tree_states.insert(height, ts);
+            }
+            let block = CompactBlock {
+                height: height as u64,
+                hash,
+                prev_hash,
+                time,
+                ..CompactBlock::default()
+            };
+            chain.blocks.insert(height, block);
+        }
+    }
+
+    /// Remove the blocks and tree states above `height` and clear the mempool
+    pub fn rollback(&self, height: u32) {
+        let mut chain = self.chain.write().unwrap();
+        chain.blocks.retain(|&h, _| h <= height);
+        chain.tree_states.retain(|&h, _| h <= height);
+        chain.mempool.clear();
+    }
+
+    pub fn add_tree_state(&self, tree_state: TreeState) {
+        let mut chain = self.chain.write().unwrap();
+        chain
+            .tree_states
+            .insert(tree_state.height as u32, tree_state);
+    }
+
+    /// Make a raw transaction available to `GetTransaction`
+    pub fn add_transaction(&self, txid: &[u8], data: &[u8], height: u32) {
+        let mut chain = self.chain.write().unwrap();
+        let raw = RawTransaction {
+            data: data.to_vec(),
+            height: height as u64,
+        };
+        chain.transactions.insert(
+            txid.to_vec(),
+            MockTx {
+                raw,
+                addresses: vec![],
+            },
+        );
+    }
+
+    /// Associate a transaction with a transparent address for `GetTaddressTxids`
+    pub fn add_taddress_tx(&self, address: &str, txid: &[u8]) -> anyhow::Result<()> {
+        let mut chain = self.chain.write().unwrap();
+        let tx = chain
+            .transactions
+            .get_mut(txid)
+            .ok_or_else(|| anyhow::anyhow!("Unknown transaction {}", hex::encode(txid)))?;
+        tx.addresses.push(address.to_string());
+        Ok(())
+    }
+
+    pub fn add_utxo(&self, utxo: GetAddressUtxosReply) {
+        let mut chain = self.chain.write().unwrap();
+        chain.utxos.push(utxo);
+    }
+
+    pub fn add_mempool_tx(&self, tx: CompactTx) {
+        let mut chain = self.chain.write().unwrap();
+        chain.mempool.push(tx);
+    }
+
+    pub fn clear_mempool(&self) {
+        let mut chain = self.chain.write().unwrap();
+        chain.mempool.clear();
+    }
+
+    pub fn latest_height(&self) -> Option<u32> {
+        let chain = self.chain.read().unwrap();
+        chain.blocks.keys().next_back().copied()
+    }
+
+    /// Transactions received by `SendTransaction`
+    pub fn sent_transactions(&self) -> Vec<RawTransaction> {
+        let chain = self.chain.read().unwrap();
+        chain.sent.clone()
+    }
+
+    /// Serve the chain on a random local port. The server stops when the `MockServer` is dropped
+    pub async fn start(&self) -> anyhow::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let service = CompactTxStreamerServer::new(MockService {
+            chain: self.chain.clone(),
+        });
+        let task = tokio::spawn(async move {
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, async {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+        });
+        log::info!("Mock lightwalletd listening on {}", url);
+        Ok(MockServer {
+            url,
+            shutdown: Some(shutdown_tx),
+            task,
+        })
+    }
+}
+
+pub struct MockServer {
+    url: String,
+    shutdown: Option<oneshot::Sender<()>>,
+    task: JoinHandle<Result<(), tonic::transport::Error>>,
+}
+
+impl MockServer {
+    /// URL to use with `connect_lightwalletd`
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn stop(mut self) -> anyhow::Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        (&mut self.task).await??;
+        Ok(())
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+struct MockService {
+    chain: Arc<RwLock<MockChain>>,
+}
+
+fn stream_of<T: Send + 'static>(items: Vec<T>) -> ResponseStream<T> {
+    futures::stream::iter(items.into_iter().map(Ok)).boxed()
+}
+
+#[tonic::async_trait]
+impl CompactTxStreamer for MockService {
+    async fn get_latest_block(
+        &self,
+        _request: Request<ChainSpec>,
+    ) -> Result<Response<BlockId>, Status> {
+        let chain = self.chain.read().unwrap();
+        let block = chain
+            .blocks
+            .values()
+            .next_back()
+            .ok_or_else(|| Status::unavailable("No blocks"))?;
+        Ok(Response::new(BlockId {
+            height: block.height,
+            hash: block.hash.clone(),
+        }))
+    }
+
+    async fn get_block(&self, request: Request<BlockId>) -> Result<Response<CompactBlock>, Status> {
+        let chain = self.chain.read().unwrap();
+        let id = request.into_inner();
+        let block = chain
+            .find_block(&id)
+            .ok_or_else(|| Status::not_found(format!("Block {} not found", id.height)))?;
+        Ok(Response::new(block.clone()))
+    }
+
+    type GetBlockRangeStream = ResponseStream<CompactBlock>;
+
+    async fn get_block_range(
+        &self,
+        request: Request<BlockRange>,
+    ) -> Result<Response<Self::GetBlockRangeStream>, Status> {
+        let chain = self.chain.read().unwrap();
+        let range = request.into_inner();
+        let start = chain.find_height(&range.start.unwrap_or_default())?;
+        let end = chain.find_height(&range.end.unwrap_or_default())?;
+        let heights: Vec<u32> = if start <= end {
+            (start..=end).collect()
+        } else {
+            (end..=start).rev().collect()
+        };
+        let mut blocks = vec![];
+        for h in heights {
+            let block = chain
+                .blocks
+                .get(&h)
+                .ok_or_else(|| Status::out_of_range(format!("Block {} not found", h)))?;
+            blocks.push(block.clone());
+        }
+        Ok(Response::new(stream_of(blocks)))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<TxFilter>,
+    ) -> Result<Response<RawTransaction>, Status> {
+        let chain = self.chain.read().unwrap();
+        let filter = request.into_inner();
+        let tx = chain.transactions.get(&filter.hash).ok_or_else(|| {
+            Status::not_found(format!("Tx {} not found", hex::encode(&filter.hash)))
+        })?;
+        Ok(Response::new(tx.raw.clone()))
+    }
+
+    async fn send_transaction(
+        &self,
+        request: Request<RawTransaction>,
+    ) -> Result<Response<SendResponse>, Status> {
+        let mut chain = self.chain.write().unwrap();
+        let raw_tx = request.into_inner();
+        let branch_id = BranchId::for_height(
+            &chain.network(),
+            BlockHeight::from_u32(raw_tx.height as u32),
+        );
+        let rep = match Transaction::read(&*raw_tx.data, branch_id) {
+            Ok(tx) => {
+                let txid = tx.txid();
+                chain.transactions.insert(
+                    txid.as_ref().to_vec(),
+                    MockTx {
+                        raw: RawTransaction {
+                            data: raw_tx.data.clone(),
+                            height: 0,
+                        },
+                        addresses: vec![],
+                    },
+                );
+                chain.sent.push(raw_tx);
+                SendResponse {
+                    error_code: 0,
+                    error_message: txid.to_string(),
+                }
+            }
+            Err(_) => SendResponse {
+                error_code: -22,
+                error_message: "TX decode failed".to_string(),
+            },
+        };
+        Ok(Response::new(rep))
+    }
+
+    type GetTaddressTxidsStream = ResponseStream<RawTransaction>;
+
+    async fn get_taddress_txids(
+        &self,
+        request: Request<TransparentAddressBlockFilter>,
+    ) -> Result<Response<Self::GetTaddressTxidsStream>, Status> {
+        let chain = self.chain.read().unwrap();
+        let filter = request.into_inner();
+        let range = filter.range.unwrap_or_default();
+        let start = range.start.map(|b| b.height).unwrap_or(0);
+        let end = range.end.map(|b| b.height).unwrap_or(u64::MAX);
+        let mut txs: Vec<_> = chain
+            .transactions
+            .values()
+            .filter(|tx| {
+                tx.addresses.contains(&filter.address)
+                    && tx.raw.height >= start
+                    && tx.raw.height <= end
+            })
+            .map(|tx| tx.raw.clone())
+            .collect();
+        txs.sort_by_key(|tx| tx.height);
+        Ok(Response::new(stream_of(txs)))
+    }
+
+    async fn get_taddress_balance(
+        &self,
+        request: Request<AddressList>,
+    ) -> Result<Response<Balance>, Status> {
+        let chain = self.chain.read().unwrap();
+        let addresses = request.into_inner().addresses;
+        Ok(Response::new(Balance {
+            value_zat: chain.balance(&addresses),
+        }))
+    }
+
+    async fn get_taddress_balance_stream(
+        &self,
+        request: Request<Streaming<Address>>,
+    ) -> Result<Response<Balance>, Status> {
+        let mut stream = request.into_inner();
+        let mut addresses = vec![];
+        while let Some(address) = stream.message().await? {
+            addresses.push(address.address);
+        }
+        let chain = self.chain.read().unwrap();
+        Ok(Response::new(Balance {
+            value_zat: chain.balance(&addresses),
+        }))
+    }
+
+    type GetMempoolTxStream = ResponseStream<CompactTx>;
+
+    async fn get_mempool_tx(
+        &self,
+        request: Request<Exclude>,
+    ) -> Result<Response<Self::GetMempoolTxStream>, Status> {
+        let chain = self.chain.read().unwrap();
+        let exclude = request.into_inner().txid;
+        // a shortened txid only excludes a transaction if it matches no other
+        let excluded: Vec<&Vec<u8>> = exclude
+            .iter()
+            .filter_map(|prefix| {
+                let mut matches = chain
+                    .mempool
+                    .iter()
+                    .filter(|tx| tx.hash.starts_with(prefix));
+                match (matches.next(), matches.next()) {
+                    (Some(tx), None) => Some(&tx.hash),
+                    _ => None,
+                }
+            })
+            .collect();
+        let txs: Vec<_> = chain
+            .mempool
+            .iter()
+            .filter(|tx| !excluded.contains(&&tx.hash))
+            .cloned()
+            .collect();
+        Ok(Response::new(stream_of(txs)))
+    }
+
+    type GetMempoolStreamStream = ResponseStream<RawTransaction>;
+
+    async fn get_mempool_stream(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::GetMempoolStreamStream>, Status> {
+        let chain = self.chain.read().unwrap();
+        let txs: Vec<_> = chain
+            .mempool
+            .iter()
+            .filter_map(|tx| chain.transactions.get(&tx.hash))
+            .map(|tx| tx.raw.clone())
+            .collect();
+        Ok(Response::new(stream_of(txs)))
+    }
+
+    async fn get_tree_state(
+        &self,
+        request: Request<BlockId>,
+    ) -> Result<Response<TreeState>, Status> {
+        let chain = self.chain.read().unwrap();
+        let id = request.into_inner();
+        let height = chain.find_height(&id)?;
+        let tree_state = chain
+            .tree_states
+            .get(&height)
+            .ok_or_else(|| Status::not_found(format!("Tree state {} not found", height)))?;
+        Ok(Response::new(tree_state.clone()))
+    }
+
+    async fn get_address_utxos(
+        &self,
+        request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<GetAddressUtxosReplyList>, Status> {
+        let chain = self.chain.read().unwrap();
+        let arg = request.into_inner();
+        let mut address_utxos = chain.utxos(&arg.addresses, arg.start_height);
+        if arg.max_entries > 0 {
+            address_utxos.truncate(arg.max_entries as usize);
+        }
+        Ok(Response::new(GetAddressUtxosReplyList { address_utxos }))
+    }
+
+    type GetAddressUtxosStreamStream = ResponseStream<GetAddressUtxosReply>;
+
+    async fn get_address_utxos_stream(
+        &self,
+        request: Request<GetAddressUtxosArg>,
+    ) -> Result<Response<Self::GetAddressUtxosStreamStream>, Status> {
+        let chain = self.chain.read().unwrap();
+        let arg = request.into_inner();
+        let mut utxos = chain.utxos(&arg.addresses, arg.start_height);
+        if arg.max_entries > 0 {
+            utxos.truncate(arg.max_entries as usize);
+        }
+        Ok(Response::new(stream_of(utxos)))
+    }
+
+    async fn get_lightd_info(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<LightdInfo>, Status> {
+        let chain = self.chain.read().unwrap();
+        let height = chain.blocks.keys().next_back().copied().unwrap_or(0) as u64;
+        Ok(Response::new(LightdInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            vendor: "Mock".to_string(),
+            taddr_support: true,
+            chain_name: chain.chain_name.clone(),
+            sapling_activation_height: chain.sapling_activation_height as u64,
+            block_height: height,
+            estimated_height: height,
+            ..LightdInfo::default()
+        }))
+    }
+
+    async fn ping(&self, _request: Request<Duration>) -> Result<Response<PingResponse>, Status> {
+        Ok(Response::new(PingResponse { entry: 0, exit: 0 }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chain::{download_chain, get_latest_height};
+    use crate::lw_rpc::*;
+    use crate::lwd_mock::MockLightwalletd;
+    use crate::scan::SyncControl;
+    use crate::{connect_lightwalletd, ChainError};
+    use tonic::Request;
+
+    const START_HEIGHT: u32 = 419_200;
+
+    #[tokio::test]
+    async fn test_mock_lightwalletd() -> anyhow::Result<()> {
+        let lwd = MockLightwalletd::new("main", START_HEIGHT);
+        lwd.add_tree_state(TreeState {
+            network: "main".to_string(),
+            height: START_HEIGHT as u64 - 1,
+            sapling_tree: "000000".to_string(),
+            ..TreeState::default()
+        });
+        lwd.mine_blocks(20);
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
+
+        let height = get_latest_height(&mut client).await?;
+        assert_eq!(height, START_HEIGHT + 19);
+
+        let tree_state = client
+            .get_tree_state(Request::new(BlockId {
+                height: height as u64,
+                hash: vec![],
+            }))
+            .await?
+            .into_inner();
+        assert_eq!(tree_state.sapling_tree, "000000");
+
+        let (blocks_tx, mut blocks_rx) = tokio::sync::mpsc::channel(4);
+        let control = SyncControl::default();
+        download_chain(
+            &mut client,
+            START_HEIGHT,
+            height,
+            None,
+            blocks_tx,
+            &control,
+            None,
+        )
+        .await?;
+        let mut count = 0;
+        while let Some(blocks) = blocks_rx.recv().await {
+            count += blocks.0.len();
+        }
+        assert_eq!(count, 19);
+
+        server.stop().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mock_reorg() -> anyhow::Result<()> {
+        let lwd = MockLightwalletd::new("main", START_HEIGHT);
+        lwd.mine_blocks(10);
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
+
+        let tip = client
+            .get_block(Request::new(BlockId {
+                height: START_HEIGHT as u64 + 5,
+                hash: vec![],
+            }))
+            .await?
+            .into_inner();
+        let mut prev_hash = [0u8; 32];
+        prev_hash.copy_from_slice(&tip.hash);
+
+        lwd.rollback(START_HEIGHT + 2);
+        lwd.mine_blocks(10);
+        let (blocks_tx, mut blocks_rx) = tokio::sync::mpsc::channel(4);
+        tokio::spawn(async move { while blocks_rx.recv().await.is_some() {} });
+        let control = SyncControl::default();
+        let res = download_chain(
+            &mut client,
+            START_HEIGHT + 5,
+            START_HEIGHT + 10,
+            Some(prev_hash),
+            blocks_tx,
+            &control,
+            None,
+        )
+        .await;
+        let err = res.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ChainError>(),
+            Some(ChainError::Reorg)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_mock_fixture() -> anyhow::Result<()> {
+        let lwd = MockLightwalletd::new("test", 280_000);
+        lwd.mine_blocks(5);
+        lwd.add_transaction(&[1u8; 32], &[0u8; 16], 280_002);
+        lwd.add_taddress_tx("tmAddress", &[1u8; 32])?;
+        lwd.add_utxo(GetAddressUtxosReply {
+            address: "tmAddress".to_string(),
+            txid: vec![1u8; 32],
+            value_zat: 100_000,
+            height: 280_002,
+            ..GetAddressUtxosReply::default()
+        });
+
+        let path = std::env::temp_dir().join(format!("lwd_fixture_{}.json", std::process::id()));
+        lwd.save_fixture(&path)?;
+        let lwd2 = MockLightwalletd::from_fixture(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(lwd2.latest_height(), Some(280_004));
+        let chain = lwd2.chain.read().unwrap();
+        assert_eq!(chain.balance(&["tmAddress".to_string()]), 100_000);
+        assert_eq!(
+            chain.transactions[&vec![1u8; 32]].addresses,
+            vec!["tmAddress"]
+        );
+        Ok(())
+    }
+}
@@ -2,25 +2,22 @@
 extern crate rocket;
 
 use anyhow::anyhow;
-use lazy_static::lazy_static;
 use rocket::fairing::AdHoc;
 use rocket::http::Status;
 use rocket::response::Responder;
 use rocket::serde::{json::Json, Deserialize, Serialize};
 use rocket::{response, Request, Response, State};
 use std::collections::HashMap;
-use std::sync::atomic::AtomicBool;
 use thiserror::Error;
-use warp_api_ffi::api::payment::{Recipient, RecipientMemo};
+use warp_api_ffi::api::notes::{NoteId, NoteRec};
+use warp_api_ffi::api::payment::{NoteSelector, Recipient, RecipientMemo};
 use warp_api_ffi::api::payment_uri::PaymentURI;
+use warp_api_ffi::api::sync::{SyncProgress, SyncStatus};
 use warp_api_ffi::{
-    derive_zip32, get_best_server, AccountRec, CoinConfig, KeyPack, RaptorQDrops, Tx, TxRec,
+    derive_zip32, get_best_server, AccountRec, CoinConfig, KeyPack, PendingTxRec, Proxy,
+    RaptorQDrops, ServerReport, Tx, TxRec,
 };
 
-lazy_static! {
-    static ref SYNC_CANCELED: AtomicBool = AtomicBool::new(false);
-}
-
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
@@ -49,6 +46,48 @@ fn init(coin: u8, config: HashMap<String, String>) -> anyhow::Result<()> {
             .get("lwd_url")
             .ok_or(anyhow!("Missing configuration value"))?,
     );
+    if let Some(block_cache) = config.get("block_cache") {
+        let max_size_mb: u64 = config
+            .get("block_cache_size")
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or(0);
+        warp_api_ffi::set_coin_block_cache(coin, block_cache, max_size_mb * 1_000_000)?;
+    }
+    if let Some(tls) = config.get("tls") {
+        let lwd_url = config
+            .get("lwd_url")
+            .ok_or(anyhow!("Missing configuration value"))?;
+        warp_api_ffi::set_coin_tls_settings(coin, lwd_url, Some(serde_json::from_str(tls)?));
+    }
+    if let Some(proxy) = config.get("proxy") {
+        let isolate = config
+            .get("proxy_isolate")
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or(false);
+        warp_api_ffi::set_coin_proxy(coin, Some(Proxy::new(proxy, isolate)));
+    }
+    if let Some(servers) = config.get("lwd_servers") {
+        let servers: Vec<_> = servers.split(',').map(|s| s.trim().to_string()).collect();
+        let samples: u32 = config
+            .get("server_check_samples")
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or(3);
+        warp_api_ffi::set_coin_lwd_servers(coin, &servers, samples);
+    }
+    if let Some(interval) = config.get("tree_check_interval") {
+        warp_api_ffi::set_coin_tree_check_interval(coin, interval.parse()?);
+    }
+    if let Some(recent_blocks) = config.get("witness_recent_blocks") {
+        let checkpoint_interval: u32 = config
+            .get("witness_checkpoint_interval")
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or(10_000);
+        warp_api_ffi::set_coin_witness_retention(coin, recent_blocks.parse()?, checkpoint_interval);
+    }
     Ok(())
 }
 
@@ -57,15 +96,6 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let _ = dotenv::dotenv();
 
-    let server = get_best_server(&[
-        "https://lwdv3.zecwallet.co:443".to_string(),
-        "https://zuul.free2z.cash:9067".to_string(),
-        "https://mainnet.lightwalletd.com:9067".to_string(),
-    ])
-    .await
-    .unwrap();
-    log::info!("Best server = {}", server);
-
     let rocket = rocket::build();
     let figment = rocket.figment();
     let zec: HashMap<String, String> = figment.extract_inner("zec")?;
@@ -73,6 +103,18 @@ async fn main() -> anyhow::Result<()> {
     let yec: HashMap<String, String> = figment.extract_inner("yec")?;
     init(1, yec)?;
 
+    let server = get_best_server(
+        &[
+            "https://lwdv3.zecwallet.co:443".to_string(),
+            "https://zuul.free2z.cash:9067".to_string(),
+            "https://mainnet.lightwalletd.com:9067".to_string(),
+        ],
+        &CoinConfig::get(0).connection_settings,
+    )
+    .await
+    .unwrap();
+    log::info!("Best server = {}", server);
+
     let _ = rocket
         .mount(
             "/",
@@ -81,7 +123,15 @@ async fn main() -> anyhow::Result<()> {
                 new_account,
                 list_accounts,
                 sync,
+                get_sync_progress,
+                rescan_account,
+                get_sync_status,
+                cancel_sync,
+                pause_sync,
+                resume_sync,
                 rewind,
+                backfill_tx_details,
+                check_servers,
                 get_latest_height,
                 get_backup,
                 get_balance,
@@ -92,7 +142,14 @@ async fn main() -> anyhow::Result<()> {
                 create_offline_tx,
                 sign_offline_tx,
                 broadcast_tx,
+                get_pending_txs,
+                rebroadcast_pending_tx,
+                abandon_pending_tx,
                 new_diversified_address,
+                get_unified_address,
+                new_diversified_unified_address,
+                get_notes,
+                freeze_notes,
                 make_payment_uri,
                 parse_payment_uri,
                 split_data,
@@ -120,6 +177,7 @@ pub fn new_account(seed: Json<AccountSeed>) -> Result<String, Error> {
         &seed.name,
         seed.key.clone(),
         seed.index,
+        seed.birthday,
     )?;
     warp_api_ffi::set_active_account(seed.coin, id_account);
     Ok(id_account.to_string())
@@ -136,8 +194,54 @@ pub fn list_accounts() -> Result<Json<Vec<AccountRec>>, Error> {
 #[post("/sync?<offset>")]
 pub async fn sync(offset: Option<u32>) -> Result<(), Error> {
     let c = CoinConfig::get_active();
-    warp_api_ffi::api::sync::coin_sync(c.coin, true, offset.unwrap_or(0), |_| {}, &SYNC_CANCELED)
-        .await?;
+    let handle = warp_api_ffi::api::sync::start_sync(c.coin, true, offset.unwrap_or(0), |_| {})?;
+    handle.wait().await?;
+    Ok(())
+}
+
+#[get("/sync_progress")]
+pub fn get_sync_progress() -> Json<Option<SyncProgress>> {
+    let c = CoinConfig::get_active();
+    let progress = warp_api_ffi::api::sync::get_sync_handle(c.coin).and_then(|h| h.progress());
+    Json(progress)
+}
+
+#[get("/sync_status")]
+pub fn get_sync_status() -> Json<Option<SyncStatus>> {
+    let c = CoinConfig::get_active();
+    let status = warp_api_ffi::api::sync::get_sync_handle(c.coin).map(|h| h.status());
+    Json(status)
+}
+
+#[post("/cancel_sync")]
+pub fn cancel_sync() {
+    let c = CoinConfig::get_active();
+    if let Some(handle) = warp_api_ffi::api::sync::get_sync_handle(c.coin) {
+        handle.cancel();
+    }
+}
+
+#[post("/pause_sync")]
+pub fn pause_sync() {
+    let c = CoinConfig::get_active();
+    if let Some(handle) = warp_api_ffi::api::sync::get_sync_handle(c.coin) {
+        handle.pause();
+    }
+}
+
+#[post("/resume_sync")]
+pub fn resume_sync() {
+    let c = CoinConfig::get_active();
+    if let Some(handle) = warp_api_ffi::api::sync::get_sync_handle(c.coin) {
+        handle.resume();
+    }
+}
+
+#[post("/rescan_account?<id_account>&<height>")]
+pub async fn rescan_account(id_account: u32, height: Option<u32>) -> Result<(), Error> {
+    let c = CoinConfig::get_active();
+    let handle = warp_api_ffi::api::sync::start_rescan(c.coin, id_account, height, |_| {})?;
+    handle.wait().await?;
     Ok(())
 }
 
@@ -147,6 +251,19 @@ pub async fn rewind(height: u32) -> Result<(), Error> {
     Ok(())
 }
 
+#[post("/backfill_tx_details")]
+pub async fn backfill_tx_details() -> Result<Json<u32>, Error> {
+    let pending = warp_api_ffi::api::sync::backfill_tx_details().await?;
+    Ok(Json(pending))
+}
+
+#[get("/check_servers")]
+pub async fn check_servers() -> Result<Json<Vec<ServerReport>>, Error> {
+    let c = CoinConfig::get_active();
+    let reports = warp_api_ffi::api::sync::check_coin_servers(c.coin).await?;
+    Ok(Json(reports))
+}
+
 #[post("/mark_synced")]
 pub async fn mark_synced() -> Result<(), Error> {
     let c = CoinConfig::get_active();
@@ -210,13 +327,28 @@ pub async fn create_offline_tx(payment: Json<Payment>) -> Result<Json<Tx>, Error
         .iter()
         .map(|p| RecipientMemo::from_recipient(&from, p))
         .collect();
-    let tx = warp_api_ffi::api::payment::build_only_multi_payment(
-        latest,
-        &recipients,
-        false,
-        payment.confirmations,
-    )
-    .await?;
+    let tx = match &payment.notes {
+        Some(notes) => {
+            warp_api_ffi::api::notes::build_only_payment_with_notes(
+                latest,
+                &recipients,
+                notes,
+                payment.confirmations,
+            )
+            .await?
+        }
+        None => {
+            warp_api_ffi::api::payment::build_only_multi_payment(
+                latest,
+                &recipients,
+                false,
+                payment.confirmations,
+                payment.note_selector,
+                payment.fee_from_recipients,
+            )
+            .await?
+        }
+    };
     Ok(Json(tx))
 }
 
@@ -247,14 +379,30 @@ pub async fn pay(payment: Json<Payment>, config: &State<Config>) -> Result<Strin
             .iter()
             .map(|p| RecipientMemo::from_recipient(&from, p))
             .collect();
-        let txid = warp_api_ffi::api::payment::build_sign_send_multi_payment(
-            latest,
-            &recipients,
-            false,
-            payment.confirmations,
-            Box::new(|_| {}),
-        )
-        .await?;
+        let txid = match &payment.notes {
+            Some(notes) => {
+                warp_api_ffi::api::notes::build_sign_send_payment_with_notes(
+                    latest,
+                    &recipients,
+                    notes,
+                    payment.confirmations,
+                    Box::new(|_| {}),
+                )
+                .await?
+            }
+            None => {
+                warp_api_ffi::api::payment::build_sign_send_multi_payment(
+                    latest,
+                    &recipients,
+                    false,
+                    payment.confirmations,
+                    payment.note_selector,
+                    payment.fee_from_recipients,
+                    Box::new(|_| {}),
+                )
+                .await?
+            }
+        };
         Ok(txid)
     }
 }
@@ -266,12 +414,59 @@ pub async fn broadcast_tx(tx_hex: String) -> Result<String, Error> {
     Ok(tx_id)
 }
 
+#[get("/pending_txs")]
+pub fn get_pending_txs() -> Result<Json<Vec<PendingTxRec>>, Error> {
+    let txs = warp_api_ffi::api::payment::get_pending_txs()?;
+    Ok(Json(txs))
+}
+
+#[post("/rebroadcast_pending_tx?<id>")]
+pub async fn rebroadcast_pending_tx(id: u32) -> Result<String, Error> {
+    let tx_id = warp_api_ffi::api::payment::rebroadcast_pending_tx(id).await?;
+    Ok(tx_id)
+}
+
+#[post("/abandon_pending_tx?<id>")]
+pub fn abandon_pending_tx(id: u32) -> Result<(), Error> {
+    warp_api_ffi::api::payment::abandon_pending_tx(id)?;
+    Ok(())
+}
+
 #[get("/new_diversified_address")]
 pub fn new_diversified_address() -> Result<String, Error> {
     let address = warp_api_ffi::api::account::new_diversified_address()?;
     Ok(address)
 }
 
+#[get("/unified_address")]
+pub fn get_unified_address() -> Result<String, Error> {
+    let c = CoinConfig::get_active();
+    let address = warp_api_ffi::api::account::get_unified_address(c.coin, c.id_account)?;
+    Ok(address)
+}
+
+#[get("/new_diversified_unified_address")]
+pub fn new_diversified_unified_address() -> Result<String, Error> {
+    let address = warp_api_ffi::api::account::new_diversified_unified_address()?;
+    Ok(address)
+}
+
+#[get("/notes")]
+pub fn get_notes() -> Result<Json<Vec<NoteRec>>, Error> {
+    let notes = warp_api_ffi::api::notes::get_notes()?;
+    Ok(Json(notes))
+}
+
+#[post("/freeze_notes?<frozen>", data = "<notes>")]
+pub fn freeze_notes(notes: Json<Vec<NoteId>>, frozen: bool) -> Result<(), Error> {
+    if frozen {
+        warp_api_ffi::api::notes::freeze_notes(&notes)?;
+    } else {
+        warp_api_ffi::api::notes::unfreeze_notes(&notes)?;
+    }
+    Ok(())
+}
+
 #[post("/make_payment_uri", data = "<payment>")]
 pub fn make_payment_uri(payment: Json<PaymentURI>) -> Result<String, Error> {
     let uri = warp_api_ffi::api::payment_uri::make_payment_uri(
@@ -328,6 +523,7 @@ pub struct AccountSeed {
     name: String,
     key: Option<String>,
     index: Option<u32>,
+    birthday: Option<u32>,
 }
 
 #[derive(Serialize)]
@@ -350,4 +546,12 @@ pub struct Backup {
 pub struct Payment {
     recipients: Vec<Recipient>,
     confirmations: u32,
+    #[serde(default)]
+    note_selector: NoteSelector,
+    /// Spend exactly these notes instead of letting the wallet pick them
+    #[serde(default)]
+    notes: Option<Vec<NoteId>>,
+    /// Deduct the fee from the recipient amounts
+    #[serde(default)]
+    fee_from_recipients: bool,
 }
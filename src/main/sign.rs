@@ -55,7 +55,7 @@ async fn main() -> anyhow::Result<()> {
     let tx: Tx = serde_json::from_str(&s)?;
     let prover = LocalTxProver::with_default_location()
         .ok_or_else(|| anyhow::anyhow!("Cannot create prover. Missing zcash-params?"))?;
-    let raw_tx = tx.sign(None, &sk, &prover, |p| {
+    let raw_tx = tx.sign(None, &sk, None, &prover, |p| {
         println!("Progress {}", p.cur());
     })?;
 
@@ -1,4 +1,4 @@
-use crate::chain::to_output_description;
+use crate::chain::{check_compact_tx, to_output_description};
 use crate::{CompactTx, CompactTxStreamerClient, Exclude};
 use std::collections::HashMap;
 use tonic::transport::Channel;
@@ -73,7 +73,19 @@ impl MemPool {
                     tx.exclude_len += 1; // server sent us the same tx: make the filter more specific
                 }
                 None => {
-                    let balance = self.scan_transaction(height, &tx, ivk);
+                    // a bad transaction is kept with no balance so that the server
+                    // does not send it again
+                    let balance = match check_compact_tx(&tx, height) {
+                        Ok(()) => self.scan_transaction(height, &tx, ivk),
+                        Err(err) => {
+                            log::warn!(
+                                "Ignoring mempool transaction {}: {}",
+                                hex::encode(&tx.hash),
+                                err
+                            );
+                            0
+                        }
+                    };
                     let mempool_tx = MemPoolTransacton {
                         balance,
                         exclude_len: DEFAULT_EXCLUDE_LEN,
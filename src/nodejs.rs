@@ -1,7 +1,5 @@
 #![allow(non_snake_case)]
-use lazy_static::lazy_static;
 use node_bindgen::derive::node_bindgen;
-use std::sync::atomic::AtomicBool;
 
 #[node_bindgen]
 fn initCoin(coin: u32, db_path: String, lwd_url: String) {
@@ -13,18 +11,16 @@ fn initCoin(coin: u32, db_path: String, lwd_url: String) {
 
 #[node_bindgen]
 fn newAccount(coin: u32, name: String) {
-    crate::api::account::new_account(coin as u8, &name, None, None).unwrap();
-}
-
-lazy_static! {
-    static ref SYNC_CANCELED: AtomicBool = AtomicBool::new(false);
+    crate::api::account::new_account(coin as u8, &name, None, None, None).unwrap();
 }
 
 // Does not support tokio async executor atm
 #[tokio::main]
 #[node_bindgen]
 async fn warp(coin: u32) {
-    crate::api::sync::coin_sync(coin as u8, true, 0, move |height| {}, &SYNC_CANCELED)
+    crate::api::sync::start_sync(coin as u8, true, 0, move |_| {})
+        .unwrap()
+        .wait()
         .await
         .unwrap();
 }
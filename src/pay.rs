@@ -1,14 +1,25 @@
-use crate::db::SpendableNote;
+use crate::db::{SpendableNote, SpendableOrchardNote};
 // use crate::wallet::RecipientMemo;
 use crate::api::payment::RecipientMemo;
 use crate::coinconfig::CoinConfig;
+use crate::ua::{get_orchard, get_sapling};
 use crate::{get_latest_height, hex_to_hash, GetAddressUtxosReply, RawTransaction};
 use anyhow::anyhow;
 use jubjub::Fr;
+use orchard::keys::{
+    FullViewingKey as OrchardFullViewingKey, OutgoingViewingKey as OrchardOutgoingViewingKey,
+    Scope, SpendingKey as OrchardSpendingKey,
+};
+use orchard::note::{Nullifier, RandomSeed};
+use orchard::tree::{Anchor, MerkleHashOrchard, MerklePath as OrchardMerklePath};
+use orchard::value::NoteValue;
 use rand::prelude::SliceRandom;
 use rand::rngs::OsRng;
 use secp256k1::SecretKey;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::mpsc;
 use tonic::Request;
 use zcash_client_backend::address::RecipientAddress;
@@ -17,11 +28,11 @@ use zcash_client_backend::encoding::{
     encode_payment_address,
 };
 use zcash_params::coin::{get_coin_chain, CoinChain, CoinType};
-use zcash_primitives::consensus::{BlockHeight, Parameters};
+use zcash_primitives::consensus::{BlockHeight, Network, Parameters};
 use zcash_primitives::keys::OutgoingViewingKey;
-use zcash_primitives::legacy::Script;
+use zcash_primitives::legacy::{Script, TransparentAddress};
 use zcash_primitives::memo::{Memo, MemoBytes};
-use zcash_primitives::merkle_tree::IncrementalWitness;
+use zcash_primitives::merkle_tree::{Hashable, IncrementalWitness};
 use zcash_primitives::sapling::prover::TxProver;
 use zcash_primitives::sapling::{Diversifier, Node, PaymentAddress, Rseed};
 use zcash_primitives::transaction::builder::{Builder, Progress};
@@ -35,11 +46,30 @@ pub struct Tx {
     pub height: u32,
     pub t_inputs: Vec<TTxIn>,
     pub inputs: Vec<TxIn>,
+    #[serde(default)]
+    pub orchard_inputs: Vec<OrchardTxIn>,
     pub outputs: Vec<TxOut>,
     pub change: String,
     pub ovk: String,
+    /// Internal Orchard address of the account, as raw bytes in hex.
+    /// It receives the change when Orchard notes are spent
+    #[serde(default)]
+    pub orchard_change: String,
+    #[serde(default)]
+    pub orchard_ovk: String,
+    #[serde(default = "default_fee")]
+    pub fee: u64,
+}
+
+fn default_fee() -> u64 {
+    u64::from(DEFAULT_FEE)
 }
 
+/// ZIP-317 fee per logical action
+const MARGINAL_FEE: u64 = 5_000;
+/// ZIP-317 minimum number of logical actions charged
+const GRACE_ACTIONS: usize = 2;
+
 impl Tx {
     pub fn new(coin_type: CoinType, height: u32) -> Self {
         Tx {
@@ -47,11 +77,63 @@ impl Tx {
             height,
             t_inputs: vec![],
             inputs: vec![],
+            orchard_inputs: vec![],
             outputs: vec![],
             change: "".to_string(),
             ovk: "".to_string(),
+            orchard_change: "".to_string(),
+            orchard_ovk: "".to_string(),
+            fee: 0,
         }
     }
+
+    /// Whether the change goes to the Orchard pool, which is the case when
+    /// Orchard notes are spent. Otherwise it goes to the Sapling address
+    pub fn has_orchard_change(&self) -> bool {
+        !self.orchard_inputs.is_empty() && !self.orchard_change.is_empty()
+    }
+
+    /// Fee of the transaction following the ZIP-317 conventional fee rule
+    ///
+    /// `with_change` accounts for a change output in the pool of the change
+    pub fn conventional_fee(&self, with_change: bool) -> anyhow::Result<u64> {
+        let network = get_coin_chain(self.coin_type).network();
+        let mut t_outputs = 0;
+        let mut sapling_outputs = 0;
+        let mut orchard_outputs = 0;
+        if with_change {
+            if self.has_orchard_change() {
+                orchard_outputs += 1;
+            } else {
+                sapling_outputs += 1;
+            }
+        }
+        for txout in self.outputs.iter() {
+            match decode_destination(network, &txout.addr)
+                .ok_or_else(|| anyhow::anyhow!("Invalid address {}", txout.addr))?
+            {
+                Destination::Transparent(_) => t_outputs += 1,
+                Destination::Sapling(_) => sapling_outputs += 1,
+                Destination::Orchard(_) => orchard_outputs += 1,
+            }
+        }
+        // Sapling bundles are padded to at least 2 outputs
+        let sapling_actions = if !self.inputs.is_empty() || sapling_outputs > 0 {
+            self.inputs.len().max(sapling_outputs.max(2))
+        } else {
+            0
+        };
+        let orchard_actions = self.orchard_inputs.len().max(orchard_outputs);
+        // Orchard bundles are padded to at least 2 actions
+        let orchard_actions = if orchard_actions > 0 {
+            orchard_actions.max(2)
+        } else {
+            0
+        };
+        let logical_actions =
+            self.t_inputs.len().max(t_outputs) + sapling_actions + orchard_actions;
+        Ok(MARGINAL_FEE * logical_actions.max(GRACE_ACTIONS) as u64)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -63,6 +145,16 @@ pub struct TxIn {
     pub witness: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OrchardTxIn {
+    pub fvk: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub rho: String,
+    pub rseed: String,
+    pub witness: String,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct TTxIn {
     pub op: String,
@@ -77,22 +169,164 @@ pub struct TxOut {
     pub amount: u64,
     pub ovk: String,
     pub memo: String,
+    /// Part of the fee deducted from the amount
+    #[serde(default)]
+    pub fee: u64,
 }
 
 #[derive(Serialize, Debug)]
 pub struct TxSummary {
     pub recipients: Vec<RecipientSummary>,
+    pub fee: u64,
 }
 
 #[derive(Serialize, Debug)]
 pub struct RecipientSummary {
     pub address: String,
+    /// Amount received, after deduction of the fee
     pub amount: u64,
+    /// Part of the fee paid by the recipient
+    pub fee: u64,
+}
+
+/// Ids of the received notes spent by a transaction, per pool
+#[derive(Default, Debug)]
+pub struct SelectedNotes {
+    pub sapling: Vec<u32>,
+    pub orchard: Vec<u32>,
+}
+
+enum Destination {
+    Transparent(TransparentAddress),
+    Sapling(PaymentAddress),
+    Orchard(orchard::Address),
+}
+
+/// Decode the address of a recipient
+///
+/// Unified addresses pay to their Orchard receiver when they have one,
+/// to their Sapling receiver otherwise
+fn decode_destination(network: &Network, address: &str) -> Option<Destination> {
+    if let Ok(orchard_address) = get_orchard(address) {
+        return Some(Destination::Orchard(orchard_address));
+    }
+    let address = get_sapling(address)
+        .map(|a| a.to_string())
+        .unwrap_or_else(|_| address.to_string());
+    match RecipientAddress::decode(network, &address)? {
+        RecipientAddress::Shielded(pa) => Some(Destination::Sapling(pa)),
+        RecipientAddress::Transparent(ta) => Some(Destination::Transparent(ta)),
+    }
+}
+
+/// Strategy used to pick the notes spent by a transaction
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteSelector {
+    /// Random order
+    Random,
+    /// Spend the largest notes first
+    LargestFirst,
+    /// Spend the smallest notes first, which cleans up dust
+    SmallestFirst,
+    /// Spend as few notes as possible
+    FewestInputs,
+    /// Avoid spending together notes received at different diversified addresses
+    Privacy,
+}
+
+impl Default for NoteSelector {
+    fn default() -> Self {
+        NoteSelector::Random
+    }
+}
+
+impl From<u8> for NoteSelector {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => NoteSelector::LargestFirst,
+            2 => NoteSelector::SmallestFirst,
+            3 => NoteSelector::FewestInputs,
+            4 => NoteSelector::Privacy,
+            _ => NoteSelector::Random,
+        }
+    }
+}
+
+impl NoteSelector {
+    /// Order the candidate notes by spending preference
+    ///
+    /// `required` is the amount that the notes need to cover
+    fn order(&self, notes: &mut Vec<InputNote>, required: u64) {
+        match self {
+            NoteSelector::Random => notes.shuffle(&mut OsRng),
+            NoteSelector::LargestFirst => notes.sort_by(|a, b| b.value().cmp(&a.value())),
+            NoteSelector::SmallestFirst => notes.sort_by_key(|n| n.value()),
+            NoteSelector::FewestInputs => {
+                notes.sort_by(|a, b| b.value().cmp(&a.value()));
+                // If a single note is enough, take the smallest one that covers the amount
+                if let Some(i) = notes.iter().rposition(|n| n.value() >= required) {
+                    let n = notes.remove(i);
+                    notes.insert(0, n);
+                }
+            }
+            NoteSelector::Privacy => {
+                let mut groups: HashMap<(u8, [u8; 11]), Vec<InputNote>> = HashMap::new();
+                for n in notes.iter() {
+                    groups.entry(n.address()).or_default().push(*n);
+                }
+                let mut groups: Vec<(u64, Vec<InputNote>)> = groups
+                    .into_values()
+                    .map(|mut g| {
+                        g.sort_by(|a, b| b.value().cmp(&a.value()));
+                        (g.iter().map(|n| n.value()).sum(), g)
+                    })
+                    .collect();
+                // Prefer the smallest group that covers the amount by itself.
+                // Otherwise, link as few addresses as possible by taking the largest groups first
+                groups.sort_by(|(a, _), (b, _)| match (*a >= required, *b >= required) {
+                    (true, true) => a.cmp(b),
+                    (true, false) => Ordering::Less,
+                    (false, true) => Ordering::Greater,
+                    (false, false) => b.cmp(a),
+                });
+                *notes = groups.into_iter().flat_map(|(_, g)| g).collect();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum InputNote<'a> {
+    Sapling(&'a SpendableNote),
+    Orchard(&'a SpendableOrchardNote),
+}
+
+impl InputNote<'_> {
+    fn value(&self) -> u64 {
+        match self {
+            InputNote::Sapling(n) => n.note.value,
+            InputNote::Orchard(n) => n.note.value().inner(),
+        }
+    }
+
+    /// Pool and diversifier of the address that received the note
+    fn address(&self) -> (u8, [u8; 11]) {
+        match self {
+            InputNote::Sapling(n) => (0, n.diversifier.0),
+            InputNote::Orchard(n) => (1, *n.note.recipient().diversifier().as_array()),
+        }
+    }
 }
 
 pub struct TxBuilder {
     pub tx: Tx,
     coin_type: CoinType,
+    /// Index of the output that receives everything left after the fee
+    max_output: Option<usize>,
+    fee_from_recipients: bool,
+    /// Indices of the outputs that pay the fee when it is deducted from the recipients.
+    /// If empty, every output pays its share
+    fee_payers: Vec<usize>,
 }
 
 impl TxBuilder {
@@ -100,9 +334,19 @@ impl TxBuilder {
         TxBuilder {
             coin_type,
             tx: Tx::new(coin_type, height),
+            max_output: None,
+            fee_from_recipients: false,
+            fee_payers: vec![],
         }
     }
 
+    /// Deduct the fee from the recipient amounts instead of adding it on top
+    ///
+    /// The fee is split proportionally between the recipients that pay the fee
+    pub fn set_fee_from_recipients(&mut self, fee_from_recipients: bool) {
+        self.fee_from_recipients = fee_from_recipients;
+    }
+
     fn add_t_input(&mut self, op: OutPoint, amount: u64, script: &[u8]) {
         self.tx.t_inputs.push(TTxIn {
             op: hex::encode(op.hash()),
@@ -136,12 +380,31 @@ impl TxBuilder {
         Ok(())
     }
 
+    fn add_orchard_input(
+        &mut self,
+        fvk: &OrchardFullViewingKey,
+        note: &orchard::Note,
+        witness: &[u8],
+    ) -> anyhow::Result<()> {
+        let tx_in = OrchardTxIn {
+            fvk: hex::encode(fvk.to_bytes()),
+            recipient: hex::encode(note.recipient().to_raw_address_bytes()),
+            amount: note.value().inner(),
+            rho: hex::encode(note.rho().to_bytes()),
+            rseed: hex::encode(note.rseed().as_bytes()),
+            witness: hex::encode(witness),
+        };
+        self.tx.orchard_inputs.push(tx_in);
+        Ok(())
+    }
+
     fn add_t_output(&mut self, address: &str, amount: Amount) -> anyhow::Result<()> {
         let tx_out = TxOut {
             addr: address.to_string(),
             amount: u64::from(amount),
             ovk: String::new(),
             memo: String::new(),
+            fee: 0,
         };
         self.tx.outputs.push(tx_out);
         Ok(())
@@ -159,6 +422,25 @@ impl TxBuilder {
             amount: u64::from(amount),
             ovk: hex::encode(ovk.0),
             memo: hex::encode(MemoBytes::from(memo).as_slice()),
+            fee: 0,
+        };
+        self.tx.outputs.push(tx_out);
+        Ok(())
+    }
+
+    fn add_orchard_output(
+        &mut self,
+        address: &str,
+        ovk: Option<&OrchardOutgoingViewingKey>,
+        amount: Amount,
+        memo: &Memo,
+    ) -> anyhow::Result<()> {
+        let tx_out = TxOut {
+            addr: address.to_string(),
+            amount: u64::from(amount),
+            ovk: ovk.map(|ovk| hex::encode(ovk.as_ref())).unwrap_or_default(),
+            memo: hex::encode(MemoBytes::from(memo).as_slice()),
+            fee: 0,
         };
         self.tx.outputs.push(tx_out);
         Ok(())
@@ -177,22 +459,35 @@ impl TxBuilder {
         Ok(())
     }
 
+    /// Change of the transactions that spend Orchard notes, so that it stays in the pool
+    fn set_orchard_change(&mut self, fvk: &OrchardFullViewingKey) {
+        let address = fvk.address_at(0u32, Scope::Internal);
+        self.tx.orchard_change = hex::encode(address.to_raw_address_bytes());
+        self.tx.orchard_ovk = hex::encode(fvk.to_ovk(Scope::Internal).as_ref());
+    }
+
     /// Add inputs to the transaction
     ///
-    /// Select utxos and shielded notes and add them to
-    /// the transaction
+    /// Select utxos and shielded notes from both the Sapling
+    /// and the Orchard pools and add them to the transaction.
+    /// The outputs must be selected first because the fee
+    /// depends on the number of inputs and outputs.
+    /// The fee is recomputed after each note is added.
+    /// Shielded notes are picked in the order given by `selector`
     ///
-    /// Returns an array of received note ids
+    /// Returns the ids of the selected received notes
     pub fn select_inputs(
         &mut self,
         fvk: &ExtendedFullViewingKey,
+        orchard_fvk: Option<&OrchardFullViewingKey>,
         notes: &[SpendableNote],
+        orchard_notes: &[SpendableOrchardNote],
         utxos: &[GetAddressUtxosReply],
-        target_amount: u64,
-    ) -> anyhow::Result<Vec<u32>> {
-        let mut selected_notes: Vec<u32> = vec![];
-        let target_amount = Amount::from_u64(target_amount).unwrap();
-        let mut t_amount = Amount::zero();
+        selector: NoteSelector,
+    ) -> anyhow::Result<SelectedNotes> {
+        let mut selected_notes = SelectedNotes::default();
+        let target_amount: u64 = self.tx.outputs.iter().map(|o| o.amount).sum();
+        let mut amount = 0u64;
         // If we use the transparent address, we use all the utxos
         if !utxos.is_empty() {
             for utxo in utxos.iter() {
@@ -200,76 +495,257 @@ impl TxBuilder {
                 tx_hash.copy_from_slice(&utxo.txid);
                 let op = OutPoint::new(tx_hash, utxo.index as u32);
                 self.add_t_input(op, utxo.value_zat as u64, &utxo.script);
-                t_amount += Amount::from_i64(utxo.value_zat).unwrap();
+                amount += utxo.value_zat as u64;
             }
         }
-        let target_amount_with_fee =
-            (target_amount + DEFAULT_FEE).ok_or(anyhow!("Invalid amount"))?;
-        if target_amount_with_fee > t_amount {
-            // We need to use some shielded notes because the transparent balance is not enough
-            let mut amount = (target_amount_with_fee - t_amount).unwrap();
 
-            // Pick spendable notes until we exceed the target_amount_with_fee or we ran out of notes
-            let mut notes = notes.to_vec();
-            notes.shuffle(&mut OsRng);
+        let mut fee = self.tx.conventional_fee(true)?;
+        if self.max_output.is_some() {
+            // Sweep: spend every note
+            let notes = notes
+                .iter()
+                .map(InputNote::Sapling)
+                .chain(orchard_notes.iter().map(InputNote::Orchard));
+            for n in notes {
+                if self.add_input_note(&n, fvk, orchard_fvk, &mut selected_notes)? {
+                    amount += n.value();
+                }
+            }
+        } else if self.required_amount(target_amount, fee) > amount {
+            // We need to use some shielded notes because the transparent balance is not enough
+            // Pick spendable notes until we exceed the target amount + fee or we ran out of notes
+            // Orchard notes can only be spent if we have the Orchard keys of the account
+            let mut notes: Vec<InputNote> = notes.iter().map(InputNote::Sapling).collect();
+            if orchard_fvk.is_some() {
+                notes.extend(orchard_notes.iter().map(InputNote::Orchard));
+            }
+            selector.order(
+                &mut notes,
+                self.required_amount(target_amount, fee) - amount,
+            );
 
             for n in notes.iter() {
-                if amount.is_positive() {
-                    let a = amount.min(
-                        Amount::from_u64(n.note.value)
-                            .map_err(|_| anyhow::anyhow!("Invalid amount"))?,
-                    );
-                    amount -= a;
-                    let mut witness_bytes: Vec<u8> = vec![];
-                    n.witness.write(&mut witness_bytes)?;
-                    if let Rseed::BeforeZip212(rseed) = n.note.rseed {
-                        // rseed are stored as pre-zip212
-                        self.add_z_input(
-                            &n.diversifier,
-                            fvk,
-                            Amount::from_u64(n.note.value).unwrap(),
-                            &rseed.to_bytes(),
-                            &witness_bytes,
-                        )?;
-                        selected_notes.push(n.id);
-                    }
+                if amount >= self.required_amount(target_amount, fee) {
+                    break;
+                }
+                if self.add_input_note(n, fvk, orchard_fvk, &mut selected_notes)? {
+                    amount += n.value();
+                    fee = self.tx.conventional_fee(true)?;
                 }
             }
+        }
+
+        self.set_fee(target_amount, amount)?;
+        Ok(selected_notes)
+    }
 
-            if amount.is_positive() {
+    /// Add exactly the given notes to the transaction
+    ///
+    /// The outputs must be selected first.
+    /// Fails if the notes do not cover the outputs and the fee
+    ///
+    /// Returns the ids of the selected received notes
+    pub fn select_exact_inputs(
+        &mut self,
+        fvk: &ExtendedFullViewingKey,
+        orchard_fvk: Option<&OrchardFullViewingKey>,
+        notes: &[SpendableNote],
+        orchard_notes: &[SpendableOrchardNote],
+    ) -> anyhow::Result<SelectedNotes> {
+        if !orchard_notes.is_empty() && orchard_fvk.is_none() {
+            anyhow::bail!("Account has no Orchard key");
+        }
+        let mut selected_notes = SelectedNotes::default();
+        let target_amount: u64 = self.tx.outputs.iter().map(|o| o.amount).sum();
+        let mut amount = 0u64;
+        let notes = notes
+            .iter()
+            .map(InputNote::Sapling)
+            .chain(orchard_notes.iter().map(InputNote::Orchard));
+        for n in notes {
+            if self.add_input_note(&n, fvk, orchard_fvk, &mut selected_notes)? {
+                amount += n.value();
+            }
+        }
+
+        self.set_fee(target_amount, amount)?;
+        Ok(selected_notes)
+    }
+
+    /// Returns false if the note cannot be spent
+    fn add_input_note(
+        &mut self,
+        n: &InputNote,
+        fvk: &ExtendedFullViewingKey,
+        orchard_fvk: Option<&OrchardFullViewingKey>,
+        selected_notes: &mut SelectedNotes,
+    ) -> anyhow::Result<bool> {
+        match n {
+            InputNote::Sapling(n) => {
+                let mut witness_bytes: Vec<u8> = vec![];
+                n.witness.write(&mut witness_bytes)?;
+                if let Rseed::BeforeZip212(rseed) = n.note.rseed {
+                    // rseed are stored as pre-zip212
+                    self.add_z_input(
+                        &n.diversifier,
+                        fvk,
+                        Amount::from_u64(n.note.value).unwrap(),
+                        &rseed.to_bytes(),
+                        &witness_bytes,
+                    )?;
+                    selected_notes.sapling.push(n.id);
+                } else {
+                    return Ok(false);
+                }
+            }
+            InputNote::Orchard(n) => {
+                let orchard_fvk = match orchard_fvk {
+                    Some(orchard_fvk) => orchard_fvk,
+                    None => return Ok(false),
+                };
+                let mut witness_bytes: Vec<u8> = vec![];
+                n.witness.write(&mut witness_bytes)?;
+                self.add_orchard_input(orchard_fvk, &n.note, &witness_bytes)?;
+                selected_notes.orchard.push(n.id);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Set the fee once the inputs and outputs are known
+    ///
+    /// The inputs must cover the outputs and the fee.
+    /// If there is a max output, it gets the remaining amount and
+    /// the transaction has no change
+    fn set_fee(&mut self, target_amount: u64, amount: u64) -> anyhow::Result<()> {
+        if let Some(i) = self.max_output {
+            let fee = self.tx.conventional_fee(false)?;
+            let max_amount = amount.checked_sub(target_amount + fee).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Not enough balance, need {} zats, missing {} zats",
+                    target_amount + fee,
+                    target_amount + fee - amount
+                )
+            })?;
+            self.tx.outputs[i].amount = max_amount;
+            self.tx.fee = fee;
+            return Ok(());
+        }
+        if self.fee_from_recipients {
+            if amount < target_amount {
                 log::info!("Not enough balance");
                 anyhow::bail!(
                     "Not enough balance, need {} zats, missing {} zats",
-                    u64::from(target_amount_with_fee),
-                    u64::from(amount)
+                    target_amount,
+                    target_amount - amount
                 );
             }
+            // The change is what remains after the gross amounts
+            let fee = self.tx.conventional_fee(amount > target_amount)?;
+            self.deduct_fee(fee)?;
+            self.tx.fee = fee;
+            return Ok(());
         }
 
-        Ok(selected_notes)
+        // No change output is needed if the inputs match exactly
+        let fee_without_change = self.tx.conventional_fee(false)?;
+        let fee = self.tx.conventional_fee(true)?;
+        self.tx.fee = if amount == target_amount + fee_without_change {
+            fee_without_change
+        } else if amount >= target_amount + fee {
+            fee
+        } else {
+            log::info!("Not enough balance");
+            anyhow::bail!(
+                "Not enough balance, need {} zats, missing {} zats",
+                target_amount + fee,
+                target_amount + fee - amount
+            );
+        };
+        Ok(())
+    }
+
+    /// Amount the inputs must cover
+    fn required_amount(&self, target_amount: u64, fee: u64) -> u64 {
+        if self.fee_from_recipients {
+            target_amount
+        } else {
+            target_amount + fee
+        }
+    }
+
+    /// Split the fee between the fee paying outputs, in proportion of their amounts
+    fn deduct_fee(&mut self, fee: u64) -> anyhow::Result<()> {
+        let payers: Vec<usize> = if self.fee_payers.is_empty() {
+            (0..self.tx.outputs.len()).collect()
+        } else {
+            self.fee_payers.clone()
+        };
+        let total: u64 = payers.iter().map(|&i| self.tx.outputs[i].amount).sum();
+        if total < fee {
+            anyhow::bail!(
+                "Recipient amounts are too small to pay the fee of {} zats",
+                fee
+            );
+        }
+        let mut remaining_fee = fee;
+        for (k, &i) in payers.iter().enumerate() {
+            let output = &mut self.tx.outputs[i];
+            // The last payer takes the rounding error
+            let share = if k == payers.len() - 1 {
+                remaining_fee
+            } else {
+                (fee as u128 * output.amount as u128 / total as u128) as u64
+            };
+            if share > output.amount {
+                anyhow::bail!("Amount to {} is too small to pay its fee", output.addr);
+            }
+            output.amount -= share;
+            output.fee = share;
+            remaining_fee -= share;
+        }
+        Ok(())
     }
 
     /// Add outputs
     ///
     /// Expand the recipients if their amount exceeds the max amount per note
     /// Set the change
+    ///
+    /// A max recipient gets a single output whose amount is set
+    /// when the inputs are selected
     pub fn select_outputs(
         &mut self,
         fvk: &ExtendedFullViewingKey,
+        orchard_fvk: Option<&OrchardFullViewingKey>,
         recipients: &[RecipientMemo],
     ) -> anyhow::Result<()> {
         let ovk = &fvk.fvk.ovk;
+        let orchard_ovk = orchard_fvk.map(|fvk| fvk.to_ovk(Scope::External));
         let (_, change) = fvk.default_address();
         self.set_change(ovk, &change)?;
+        if let Some(orchard_fvk) = orchard_fvk {
+            self.set_orchard_change(orchard_fvk);
+        }
 
         for r in recipients.iter() {
-            let to_addr = RecipientAddress::decode(self.chain().network(), &r.address)
+            let to_addr = decode_destination(self.chain().network(), &r.address)
                 .ok_or(anyhow::anyhow!("Invalid address"))?;
             let memo = &r.memo;
 
-            let amount = Amount::from_u64(r.amount).unwrap();
+            if r.max {
+                if self.max_output.is_some() {
+                    anyhow::bail!("Only one recipient can receive the max amount");
+                }
+                self.max_output = Some(self.tx.outputs.len());
+            }
+            let amount = if r.max {
+                Amount::zero()
+            } else {
+                Amount::from_u64(r.amount).unwrap()
+            };
             let max_amount_per_note = r.max_amount_per_note;
-            let max_amount_per_note = if max_amount_per_note != 0 {
+            let max_amount_per_note = if max_amount_per_note != 0 && !r.max {
                 Amount::from_u64(max_amount_per_note).unwrap()
             } else {
                 Amount::from_i64(MAX_MONEY).unwrap()
@@ -281,13 +757,20 @@ impl TxBuilder {
                 is_first = false;
                 let note_amount = remaining_amount.min(max_amount_per_note);
                 remaining_amount -= note_amount;
+                if r.pays_fee {
+                    self.fee_payers.push(self.tx.outputs.len());
+                }
 
                 match &to_addr {
-                    RecipientAddress::Shielded(_pa) => {
+                    Destination::Orchard(_address) => {
+                        log::info!("Orchard output: {}", r.amount);
+                        self.add_orchard_output(&r.address, orchard_ovk.as_ref(), note_amount, memo)
+                    }
+                    Destination::Sapling(_pa) => {
                         log::info!("Sapling output: {}", r.amount);
                         self.add_z_output(&r.address, ovk, note_amount, memo)
                     }
-                    RecipientAddress::Transparent(_address) => {
+                    Destination::Transparent(_address) => {
                         self.add_t_output(&r.address, note_amount)
                     }
                 }?;
@@ -305,17 +788,52 @@ impl TxBuilder {
 impl Tx {
     /// Sign the transaction with the transparent and shielded secret keys
     ///
+    /// The Orchard secret key is only needed if the transaction spends Orchard notes
+    ///
     /// Returns the raw transaction bytes
     pub fn sign(
         &self,
         tsk: Option<SecretKey>,
         zsk: &ExtendedSpendingKey,
+        osk: Option<&OrchardSpendingKey>,
         prover: &impl TxProver,
         progress_callback: impl Fn(Progress) + Send + 'static,
     ) -> anyhow::Result<Vec<u8>> {
         let chain = get_coin_chain(self.coin_type);
+        let network = chain.network();
         let last_height = BlockHeight::from_u32(self.height as u32);
-        let mut builder = Builder::new(*chain.network(), last_height);
+
+        let recipients = self
+            .outputs
+            .iter()
+            .map(|txout| {
+                decode_destination(network, &txout.addr)
+                    .ok_or_else(|| anyhow::anyhow!("Invalid address {}", txout.addr))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let orchard_spends = self
+            .orchard_inputs
+            .iter()
+            .map(decode_orchard_input)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let has_orchard = !orchard_spends.is_empty()
+            || recipients
+                .iter()
+                .any(|r| matches!(r, Destination::Orchard(_)));
+        let orchard_anchor = if has_orchard {
+            // All the witnesses are taken at the same height and share the same root.
+            // Without spends, the root of the empty tree is a valid anchor
+            let anchor = orchard_spends
+                .first()
+                .map(|(_, _, witness)| Anchor::from(witness.root()))
+                .unwrap_or_else(Anchor::empty_tree);
+            Some(anchor)
+        } else {
+            None
+        };
+
+        let mut builder = Builder::new(*network, last_height, orchard_anchor);
+        builder.set_fee(Amount::from_u64(self.fee).map_err(|_| anyhow!("Invalid fee"))?);
         let efvk = ExtendedFullViewingKey::from(zsk);
 
         let ovk = hex_to_hash(&self.ovk)?;
@@ -369,23 +887,63 @@ impl Tx {
             builder.add_sapling_spend(zsk.clone(), diversifier, note, merkle_path)?;
         }
 
-        for txout in self.outputs.iter() {
-            let recipient = RecipientAddress::decode(chain.network(), &txout.addr).unwrap();
+        if !orchard_spends.is_empty() {
+            let osk = osk.ok_or_else(|| anyhow::anyhow!("Missing Orchard secret key"))?;
+            let ofvk = OrchardFullViewingKey::from(osk);
+            for (fvk, note, witness) in orchard_spends {
+                if fvk != ofvk {
+                    anyhow::bail!("Incorrect account - Orchard secret key mismatch")
+                }
+                let merkle_path = orchard_merkle_path(&witness)?;
+                builder.add_orchard_spend(osk.clone(), note, merkle_path)?;
+            }
+        }
+
+        for (txout, recipient) in self.outputs.iter().zip(recipients) {
             let amount = Amount::from_u64(txout.amount).unwrap();
             match recipient {
-                RecipientAddress::Transparent(ta) => {
+                Destination::Transparent(ta) => {
                     builder.add_transparent_output(&ta, amount)?;
                 }
-                RecipientAddress::Shielded(pa) => {
+                Destination::Sapling(pa) => {
                     let mut ovk = [0u8; 32];
                     hex::decode_to_slice(&txout.ovk, &mut ovk)?;
                     let ovk = OutgoingViewingKey(ovk);
-                    let mut memo = vec![0; 512];
-                    let m = hex::decode(&txout.memo)?;
-                    memo[..m.len()].copy_from_slice(&m);
-                    let memo = MemoBytes::from_bytes(&memo)?;
+                    let memo = decode_memo_bytes(&txout.memo)?;
                     builder.add_sapling_output(Some(ovk), pa, amount, memo)?;
                 }
+                Destination::Orchard(address) => {
+                    let ovk = if txout.ovk.is_empty() {
+                        None
+                    } else {
+                        let mut ovk = [0u8; 32];
+                        hex::decode_to_slice(&txout.ovk, &mut ovk)?;
+                        Some(OrchardOutgoingViewingKey::from(ovk))
+                    };
+                    let memo = decode_memo_bytes(&txout.memo)?;
+                    builder.add_orchard_output(ovk, address, txout.amount, memo)?;
+                }
+            }
+        }
+
+        if self.has_orchard_change() {
+            // the builder only makes Sapling change
+            let input_amount: u64 = self.t_inputs.iter().map(|i| i.amount).sum::<u64>()
+                + self.inputs.iter().map(|i| i.amount).sum::<u64>()
+                + self.orchard_inputs.iter().map(|i| i.amount).sum::<u64>();
+            let output_amount: u64 = self.outputs.iter().map(|o| o.amount).sum();
+            let change = input_amount
+                .checked_sub(output_amount + self.fee)
+                .ok_or_else(|| anyhow::anyhow!("Not enough balance"))?;
+            if change > 0 {
+                let mut address = [0u8; 43];
+                hex::decode_to_slice(&self.orchard_change, &mut address)?;
+                let address: Option<orchard::Address> =
+                    orchard::Address::from_raw_address_bytes(&address).into();
+                let address =
+                    address.ok_or_else(|| anyhow::anyhow!("Invalid Orchard change address"))?;
+                let ovk = OrchardOutgoingViewingKey::from(decode_hex_32(&self.orchard_ovk)?);
+                builder.add_orchard_output(Some(ovk), address, change, MemoBytes::empty())?;
             }
         }
 
@@ -406,6 +964,60 @@ impl Tx {
     }
 }
 
+fn decode_memo_bytes(memo: &str) -> anyhow::Result<MemoBytes> {
+    let mut memo_bytes = vec![0; 512];
+    let m = hex::decode(memo)?;
+    memo_bytes[..m.len()].copy_from_slice(&m);
+    Ok(MemoBytes::from_bytes(&memo_bytes)?)
+}
+
+fn decode_hex_32(s: &str) -> anyhow::Result<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(s, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn decode_orchard_input(
+    txin: &OrchardTxIn,
+) -> anyhow::Result<(
+    OrchardFullViewingKey,
+    orchard::Note,
+    IncrementalWitness<MerkleHashOrchard>,
+)> {
+    let invalid = || anyhow::anyhow!("Invalid Orchard input");
+    let mut fvk = [0u8; 96];
+    hex::decode_to_slice(&txin.fvk, &mut fvk)?;
+    let fvk = OrchardFullViewingKey::from_bytes(&fvk).ok_or_else(invalid)?;
+    let mut recipient = [0u8; 43];
+    hex::decode_to_slice(&txin.recipient, &mut recipient)?;
+    let recipient: Option<orchard::Address> =
+        orchard::Address::from_raw_address_bytes(&recipient).into();
+    let recipient = recipient.ok_or_else(invalid)?;
+    let rho: Option<Nullifier> = Nullifier::from_bytes(&decode_hex_32(&txin.rho)?).into();
+    let rho = rho.ok_or_else(invalid)?;
+    let rseed: Option<RandomSeed> =
+        RandomSeed::from_bytes(decode_hex_32(&txin.rseed)?, &rho).into();
+    let rseed = rseed.ok_or_else(invalid)?;
+    let note = orchard::Note::from_parts(recipient, NoteValue::from_raw(txin.amount), rho, rseed);
+    let w = hex::decode(&txin.witness)?;
+    let witness = IncrementalWitness::<MerkleHashOrchard>::read(&*w)?;
+    Ok((fvk, note, witness))
+}
+
+fn orchard_merkle_path(
+    witness: &IncrementalWitness<MerkleHashOrchard>,
+) -> anyhow::Result<OrchardMerklePath> {
+    let path = witness
+        .path()
+        .ok_or_else(|| anyhow::anyhow!("Invalid Orchard witness"))?;
+    let mut auth_path = [MerkleHashOrchard::empty_leaf(); 32];
+    for (i, (node, _)) in path.auth_path.iter().enumerate() {
+        auth_path[i] = *node;
+    }
+    let position = u32::try_from(path.position)?;
+    Ok(OrchardMerklePath::from_parts(position, auth_path))
+}
+
 pub async fn broadcast_tx(tx: &[u8]) -> anyhow::Result<String> {
     let c = CoinConfig::get_active();
     let mut client = c.connect_lwd().await?;
@@ -432,7 +1044,138 @@ pub fn get_tx_summary(tx: &Tx) -> anyhow::Result<TxSummary> {
         recipients.push(RecipientSummary {
             address: tx_out.addr.clone(),
             amount: tx_out.amount,
+            fee: tx_out.fee,
         });
     }
-    Ok(TxSummary { recipients })
+    Ok(TxSummary {
+        recipients,
+        fee: tx.fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pay::{OrchardTxIn, Tx, TxBuilder, TxIn, TxOut};
+    use orchard::keys::{FullViewingKey, Scope, SpendingKey};
+    use zcash_params::coin::CoinType;
+
+    fn tx_out(addr: &str) -> TxOut {
+        TxOut {
+            addr: addr.to_string(),
+            amount: 100_000,
+            ovk: String::new(),
+            memo: String::new(),
+            fee: 0,
+        }
+    }
+
+    fn tx_in() -> TxIn {
+        TxIn {
+            diversifier: String::new(),
+            fvk: String::new(),
+            amount: 100_000,
+            rseed: String::new(),
+            witness: String::new(),
+        }
+    }
+
+    fn orchard_in() -> OrchardTxIn {
+        OrchardTxIn {
+            fvk: String::new(),
+            recipient: String::new(),
+            amount: 100_000,
+            rho: String::new(),
+            rseed: String::new(),
+            witness: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_conventional_fee() -> anyhow::Result<()> {
+        let mut tx = Tx::new(CoinType::Zcash, 0);
+        // grace actions
+        tx.outputs.push(tx_out(
+            "zs1lvzgfzzwl9n85446j292zg0valw2p47hmxnw42wnqsehsmyuvjk0mhxktcs0pqrplacm2vchh35",
+        ));
+        tx.inputs.push(tx_in());
+        assert_eq!(tx.conventional_fee(true)?, 10_000);
+
+        // 5 sapling spends and 2 sapling outputs (incl. change) + 1 transparent output
+        for _ in 0..4 {
+            tx.inputs.push(tx_in());
+        }
+        tx.outputs
+            .push(tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"));
+        assert_eq!(tx.conventional_fee(true)?, 30_000);
+        assert_eq!(tx.conventional_fee(false)?, 30_000);
+
+        // 1 sapling spend to a transparent address: the sapling outputs are
+        // padded to 2, with or without change
+        let mut tx = Tx::new(CoinType::Zcash, 0);
+        tx.inputs.push(tx_in());
+        tx.outputs
+            .push(tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"));
+        assert_eq!(tx.conventional_fee(true)?, 15_000);
+        assert_eq!(tx.conventional_fee(false)?, 15_000);
+
+        // transparent only: no sapling bundle
+        let mut tx = Tx::new(CoinType::Zcash, 0);
+        tx.outputs
+            .push(tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"));
+        assert_eq!(tx.conventional_fee(false)?, 10_000);
+        assert_eq!(tx.conventional_fee(true)?, 15_000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_orchard_change() -> anyhow::Result<()> {
+        let sk = SpendingKey::from_zip32_seed(&[1u8; 32], 133, 0).unwrap();
+        let fvk = FullViewingKey::from(&sk);
+        let mut builder = TxBuilder::new(CoinType::Zcash, 0);
+        builder.set_orchard_change(&fvk);
+        let change = fvk.address_at(0u32, Scope::Internal);
+        assert_eq!(
+            builder.tx.orchard_change,
+            hex::encode(change.to_raw_address_bytes())
+        );
+        builder
+            .tx
+            .outputs
+            .push(tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"));
+
+        // Sapling spends: the change stays in the Sapling pool
+        builder.tx.inputs.push(tx_in());
+        assert!(!builder.tx.has_orchard_change());
+
+        // Orchard spends: the change is one of the padded Orchard actions
+        builder.tx.inputs.clear();
+        builder.tx.orchard_inputs.push(orchard_in());
+        assert!(builder.tx.has_orchard_change());
+        assert_eq!(builder.tx.conventional_fee(true)?, 15_000);
+        assert_eq!(builder.tx.conventional_fee(false)?, 15_000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deduct_fee() -> anyhow::Result<()> {
+        let mut builder = TxBuilder::new(CoinType::Zcash, 0);
+        builder
+            .tx
+            .outputs
+            .push(tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"));
+        let mut out = tx_out("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo");
+        out.amount = 300_001;
+        builder.tx.outputs.push(out);
+        builder.deduct_fee(10_000)?;
+        let outputs = &builder.tx.outputs;
+        assert_eq!(outputs[0].fee, 2_499);
+        assert_eq!(outputs[1].fee, 7_501);
+        assert_eq!(outputs[0].amount + outputs[1].amount, 390_001);
+
+        builder.fee_payers = vec![0];
+        assert!(builder.deduct_fee(100_000).is_err());
+
+        Ok(())
+    }
 }
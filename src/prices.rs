@@ -1,8 +1,10 @@
+use crate::proxy::{http_client, Proxy};
 use crate::DbAdapter;
 use chrono::NaiveDateTime;
 use zcash_params::coin::get_coin_chain;
 
 const DAY_SEC: i64 = 24 * 3600;
+const COINGECKO_URL: &str = "https://api.coingecko.com/api/v3";
 
 #[derive(Debug)]
 pub struct Quote {
@@ -15,6 +17,19 @@ pub async fn fetch_historical_prices(
     days: u32,
     currency: &str,
     db: &DbAdapter,
+    proxy: Option<&Proxy>,
+) -> anyhow::Result<Vec<Quote>> {
+    fetch_prices_from(COINGECKO_URL, now, days, currency, db, proxy).await
+}
+
+/// Fetch the daily prices from a server with the CoinGecko API at `api_url`
+async fn fetch_prices_from(
+    api_url: &str,
+    now: i64,
+    days: u32,
+    currency: &str,
+    db: &DbAdapter,
+    proxy: Option<&Proxy>,
 ) -> anyhow::Result<Vec<Quote>> {
     let chain = get_coin_chain(db.coin_type);
     let json_error = || anyhow::anyhow!("Invalid JSON");
@@ -32,11 +47,8 @@ pub async fn fetch_historical_prices(
     if latest_day < today {
         let from = (latest_day + 1) * DAY_SEC;
         let to = today * DAY_SEC;
-        let client = reqwest::Client::new();
-        let url = format!(
-            "https://api.coingecko.com/api/v3/coins/{}/market_chart/range",
-            chain.ticker()
-        );
+        let client = http_client(proxy)?;
+        let url = format!("{}/coins/{}/market_chart/range", api_url, chain.ticker());
         let params = [
             ("from", from.to_string()),
             ("to", to.to_string()),
@@ -67,26 +79,68 @@ pub async fn fetch_historical_prices(
 
 #[cfg(test)]
 mod tests {
-    use crate::db::DEFAULT_DB_PATH;
-    use crate::prices::fetch_historical_prices;
-    use crate::DbAdapter;
-    use std::time::SystemTime;
-    use zcash_params::coin::CoinType;
+    use crate::prices::{fetch_prices_from, DAY_SEC};
+    use crate::synthetic::fixtures::TestDb;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use zcash_params::coin::get_coin_chain;
+
+    // Minimal HTTP server that answers a single request with `body`
+    // and returns the request line
+    async fn serve_once(listener: TcpListener, body: String) -> anyhow::Result<String> {
+        let (mut s, _) = listener.accept().await?;
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let len = s.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..len]);
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        s.write_all(response.as_bytes()).await?;
+        let request = String::from_utf8_lossy(&request).to_string();
+        Ok(request.lines().next().unwrap_or_default().to_string())
+    }
 
     #[tokio::test]
-    async fn test_fetch_quotes() {
+    async fn test_fetch_quotes() -> anyhow::Result<()> {
         let currency = "EUR";
-        let mut db = DbAdapter::new(CoinType::Zcash, DEFAULT_DB_PATH).unwrap();
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        let quotes = fetch_historical_prices(now, 365, currency, &db)
-            .await
-            .unwrap();
-        for q in quotes.iter() {
-            println!("{:?}", q);
-        }
-        db.store_historical_prices(&quotes, currency).unwrap();
+        let test_db = TestDb::new("quotes", &[])?;
+        let mut db = test_db.open()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let api_url = format!("http://{}", listener.local_addr()?);
+        // two prices on the first day and one on the next
+        let ms = |day: i64, hour: i64| (day * DAY_SEC + hour * 3600) * 1000;
+        let body = format!(
+            r#"{{"prices": [[{}, 40.5], [{}, 41.0], [{}, 42.25]]}}"#,
+            ms(8, 1),
+            ms(8, 13),
+            ms(9, 1)
+        );
+        let server = tokio::spawn(serve_once(listener, body));
+
+        let now = 10 * DAY_SEC + 100;
+        let quotes = fetch_prices_from(&api_url, now, 3, currency, &db, None).await?;
+        let request = server.await??;
+        let ticker = get_coin_chain(db.coin_type).ticker();
+        assert!(request.starts_with(&format!("GET /coins/{}/market_chart/range?", ticker)));
+        assert!(request.contains(&format!("from={}", 8 * DAY_SEC)));
+        assert!(request.contains(&format!("to={}", 10 * DAY_SEC)));
+        assert!(request.contains("vs_currency=EUR"));
+        let quotes_ts: Vec<_> = quotes.iter().map(|q| (q.timestamp, q.price)).collect();
+        assert_eq!(quotes_ts, vec![(8 * DAY_SEC, 40.5), (9 * DAY_SEC, 42.25)]);
+
+        db.store_historical_prices(&quotes, currency)?;
+        assert_eq!(
+            db.get_latest_quote(currency)?.unwrap().timestamp,
+            9 * DAY_SEC
+        );
+        Ok(())
     }
 }
@@ -0,0 +1,183 @@
+use rand::RngCore;
+use std::io;
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tonic::transport::Uri;
+
+/*
+SOCKS5 proxy for the connections of a coin, for example a local Tor client.
+
+Host names are resolved by the proxy. With stream isolation, every connection
+authenticates with new random credentials so that Tor sends it through its own
+circuit and the server cannot link the requests of a user by their exit node.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    /// host:port of the SOCKS5 server
+    pub address: String,
+    pub isolate: bool,
+}
+
+impl Proxy {
+    pub fn new(address: &str, isolate: bool) -> Self {
+        Proxy {
+            address: address.to_string(),
+            isolate,
+        }
+    }
+
+    /// Credentials of a new connection, if streams are isolated
+    fn credentials(&self) -> Option<(String, String)> {
+        if self.isolate {
+            let mut rng = rand::thread_rng();
+            let mut user = [0u8; 8];
+            let mut password = [0u8; 8];
+            rng.fill_bytes(&mut user);
+            rng.fill_bytes(&mut password);
+            Some((hex::encode(user), hex::encode(password)))
+        } else {
+            None
+        }
+    }
+
+    /// Connector for a tonic endpoint that opens its connections through the proxy
+    pub fn connector(&self) -> Socks5Connector {
+        Socks5Connector {
+            address: self.address.clone(),
+            credentials: self.credentials(),
+        }
+    }
+
+    /// Proxy for reqwest
+    pub fn to_reqwest(&self) -> anyhow::Result<reqwest::Proxy> {
+        let url = match self.credentials() {
+            Some((user, password)) => format!("socks5h://{}:{}@{}", user, password, self.address),
+            None => format!("socks5h://{}", self.address),
+        };
+        Ok(reqwest::Proxy::all(url)?)
+    }
+}
+
+#[derive(Clone)]
+pub struct Socks5Connector {
+    address: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Connector {
+    pub async fn connect(self, uri: Uri) -> io::Result<Socks5Stream<TcpStream>> {
+        let host = uri
+            .host()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Missing host"))?;
+        let port = uri
+            .port_u16()
+            .unwrap_or(if uri.scheme_str() == Some("https") {
+                443
+            } else {
+                80
+            });
+        let target = (host, port);
+        let stream = match &self.credentials {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(&*self.address, target, user, password).await
+            }
+            None => Socks5Stream::connect(&*self.address, target).await,
+        };
+        stream.map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+/// HTTP client that goes through `proxy` if there is one
+pub fn http_client(proxy: Option<&Proxy>) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::Proxy;
+    use crate::{
+        connect_lightwalletd_with, get_latest_height, ConnectionSettings, MockLightwalletd,
+    };
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn read_string(s: &mut TcpStream) -> io::Result<String> {
+        let len = s.read_u8().await?;
+        let mut buf = vec![0u8; len as usize];
+        s.read_exact(&mut buf).await?;
+        Ok(String::from_utf8_lossy(&buf).to_string())
+    }
+
+    // Minimal SOCKS5 server that records the user names
+    async fn handle_socks(mut s: TcpStream, users: Arc<Mutex<Vec<String>>>) -> io::Result<()> {
+        let mut header = [0u8; 2];
+        s.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        s.read_exact(&mut methods).await?;
+        if methods.contains(&2) {
+            s.write_all(&[5, 2]).await?;
+            s.read_u8().await?;
+            let user = read_string(&mut s).await?;
+            read_string(&mut s).await?;
+            users.lock().unwrap().push(user);
+            s.write_all(&[1, 0]).await?;
+        } else {
+            s.write_all(&[5, 0]).await?;
+        }
+        let mut request = [0u8; 4];
+        s.read_exact(&mut request).await?;
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0u8; 4];
+                s.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            3 => read_string(&mut s).await?,
+            _ => return Err(io::Error::new(io::ErrorKind::Other, "Unsupported address")),
+        };
+        let port = s.read_u16().await?;
+        let mut target = TcpStream::connect((host.as_str(), port)).await?;
+        s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        tokio::io::copy_bidirectional(&mut s, &mut target).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks_proxy() -> anyhow::Result<()> {
+        let lwd = MockLightwalletd::new("main", 419_200);
+        lwd.mine_blocks(5);
+        let server = lwd.start().await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?.to_string();
+        let users = Arc::new(Mutex::new(vec![]));
+        let users2 = users.clone();
+        tokio::spawn(async move {
+            while let Ok((s, _)) = listener.accept().await {
+                tokio::spawn(handle_socks(s, users2.clone()));
+            }
+        });
+
+        let settings = ConnectionSettings {
+            proxy: Some(Proxy::new(&address, true)),
+            ..ConnectionSettings::default()
+        };
+        for _ in 0..2 {
+            let mut client = connect_lightwalletd_with(server.url(), &settings).await?;
+            assert_eq!(get_latest_height(&mut client).await?, 419_204);
+        }
+        let users = users.lock().unwrap().clone();
+        assert_eq!(users.len(), 2);
+        assert_ne!(users[0], users[1]);
+
+        let proxy = Proxy::new(&address, false);
+        assert!(proxy.credentials().is_none());
+        Ok(())
+    }
+}
@@ -1,23 +1,32 @@
-use crate::builder::BlockProcessor;
-use crate::chain::{Nf, NfRef};
-use crate::db::{DbAdapter, ReceivedNote};
+use crate::block_cache::BlockCache;
+use crate::builder::{BlockProcessor, OrchardBlockProcessor};
+use crate::chain::{get_block_hash, get_tree_states, ConnectionSettings, Nf, NfRef};
+use crate::commitment::OrchardWitness;
+use crate::db::{DbAdapter, Pool, ReceivedNote, ReceivedOrchardNote, BLOCK_TIME_INTERVAL};
 
 use crate::transaction::retrieve_tx_info;
 use crate::{
-    connect_lightwalletd, download_chain, get_latest_height, CompactBlock, DecryptNode, Witness,
+    connect_lightwalletd_with, download_chain, get_latest_height, CTree, ChainError, CompactBlock,
+    CompactTxStreamerClient, DecryptNode, Witness,
 };
 use ff::PrimeField;
+use orchard::note::ExtractedNoteCommitment;
+use orchard::tree::MerkleHashOrchard;
+use prost::Message;
+use serde::Serialize;
 
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::panic;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
+use tonic::transport::Channel;
 use zcash_params::coin::{get_coin_chain, CoinType};
 
+use zcash_primitives::consensus::{NetworkUpgrade, Parameters};
 use zcash_primitives::sapling::Node;
 
 pub struct Blocks(pub Vec<CompactBlock>);
@@ -31,7 +40,131 @@ impl std::fmt::Debug for Blocks {
     }
 }
 
-pub type ProgressCallback = dyn Fn(u32) + Send;
+/// Current step of the processing of a chunk of blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SyncPhase {
+    Downloading,
+    Decrypting,
+    Witnesses,
+    TxDetails,
+    Done,
+}
+
+/// Time spent in each phase since the sync started, in ms
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PhaseTimings {
+    pub download: u64,
+    pub decrypt: u64,
+    pub witnesses: u64,
+    pub tx_details: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncProgress {
+    pub phase: SyncPhase,
+    pub start_height: u32,
+    pub height: u32,
+    pub target_height: u32,
+    pub blocks_per_second: f64,
+    pub outputs_per_second: f64,
+    pub downloaded_bytes: u64,
+    pub notes_found: u32,
+    /// Estimated time left in seconds, once some blocks have been processed
+    pub eta: Option<u64>,
+    pub elapsed: PhaseTimings,
+}
+
+/// Accumulates the statistics reported in [SyncProgress]
+struct ProgressTracker {
+    start: Instant,
+    start_height: u32,
+    target_height: u32,
+    outputs: u64,
+    downloaded_bytes: u64,
+    notes_found: u32,
+    elapsed: PhaseTimings,
+}
+
+impl ProgressTracker {
+    fn new(start_height: u32, target_height: u32) -> Self {
+        ProgressTracker {
+            start: Instant::now(),
+            start_height,
+            target_height,
+            outputs: 0,
+            downloaded_bytes: 0,
+            notes_found: 0,
+            elapsed: PhaseTimings::default(),
+        }
+    }
+
+    fn progress(&self, phase: SyncPhase, height: u32) -> SyncProgress {
+        let secs = self.start.elapsed().as_secs_f64();
+        let blocks = height.saturating_sub(self.start_height) as f64;
+        let (blocks_per_second, outputs_per_second) = if secs > 0.0 {
+            (blocks / secs, self.outputs as f64 / secs)
+        } else {
+            (0.0, 0.0)
+        };
+        let eta = if blocks_per_second > 0.0 {
+            let remaining = self.target_height.saturating_sub(height) as f64;
+            Some((remaining / blocks_per_second) as u64)
+        } else {
+            None
+        };
+        SyncProgress {
+            phase,
+            start_height: self.start_height,
+            height,
+            target_height: self.target_height,
+            blocks_per_second,
+            outputs_per_second,
+            downloaded_bytes: self.downloaded_bytes,
+            notes_found: self.notes_found,
+            eta,
+            elapsed: self.elapsed.clone(),
+        }
+    }
+}
+
+pub type ProgressCallback = dyn Fn(SyncProgress) + Send;
+
+/// Cancel and pause flags shared between a running sync and its controller
+#[derive(Default)]
+pub struct SyncControl {
+    canceled: AtomicBool,
+    paused: AtomicBool,
+}
+
+impl SyncControl {
+    pub fn cancel(&self) {
+        self.canceled.store(true, AtomicOrdering::Release);
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(AtomicOrdering::Acquire)
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, AtomicOrdering::Release);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, AtomicOrdering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(AtomicOrdering::Acquire)
+    }
+
+    /// Wait while the sync is paused. Returns false if it was canceled
+    pub async fn proceed(&self) -> bool {
+        while self.is_paused() && !self.is_canceled() {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        !self.is_canceled()
+    }
+}
 pub type AMProgressCallback = Arc<Mutex<ProgressCallback>>;
 
 #[derive(PartialEq, PartialOrd, Debug, Hash, Eq)]
@@ -43,24 +176,145 @@ pub struct TxIdHeight {
 
 pub const MAX_OUTPUTS_PER_CHUNK: usize = 200_000;
 
+const MAX_REORG_ATTEMPTS: u32 = 5;
+
+/// Sync the wallet db with the server chain
+///
+/// When `tree_check_interval` is not 0, the Sapling tree built locally is compared
+/// with the server tree state at the end of a chunk once `tree_check_interval` blocks
+/// have passed since the last check, and at the end of the sync.
+/// On mismatch, the db is rolled back to the last verified height and the sync
+/// fails with [ChainError::TreeMismatch]
+#[allow(clippy::too_many_arguments)]
 pub async fn sync_async(
+    coin_type: CoinType,
+    chunk_size: u32,
+    get_tx: bool,
+    db_path: &str,
+    target_height_offset: u32,
+    progress_callback: AMProgressCallback,
+    control: Arc<SyncControl>,
+    block_cache: Option<Arc<BlockCache>>,
+    tree_check_interval: u32,
+    ld_url: &str,
+    settings: &ConnectionSettings,
+) -> anyhow::Result<()> {
+    let mut reorg_attempts = 0;
+    loop {
+        let res = sync_async_once(
+            coin_type,
+            chunk_size,
+            get_tx,
+            db_path,
+            target_height_offset,
+            progress_callback.clone(),
+            control.clone(),
+            block_cache.clone(),
+            tree_check_interval,
+            ld_url,
+            settings,
+        )
+        .await;
+        match res {
+            Err(err) if is_reorg(&err) && reorg_attempts < MAX_REORG_ATTEMPTS => {
+                reorg_attempts += 1;
+                let fork_height = rewind_to_fork(coin_type, db_path, ld_url, settings).await?;
+                if let Some(block_cache) = &block_cache {
+                    block_cache.truncate(fork_height + 1)?;
+                }
+                log::warn!("Reorg detected: resuming sync from {}", fork_height);
+            }
+            res => return res,
+        }
+    }
+}
+
+fn is_reorg(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<ChainError>(), Some(ChainError::Reorg))
+}
+
+/// Find the most recent stored block that is still on the server chain
+/// and drop everything above it. The tree state and witnesses of that block
+/// become the starting point of the next sync.
+///
+/// Returns the height of the fork point
+async fn rewind_to_fork(
+    coin_type: CoinType,
+    db_path: &str,
+    ld_url: &str,
+    settings: &ConnectionSettings,
+) -> anyhow::Result<u32> {
+    let mut client = connect_lightwalletd_with(ld_url, settings).await?;
+    let mut db = DbAdapter::new(coin_type, db_path)?;
+    let mut height = db.get_db_height()?;
+    while let Some(checkpoint_height) = db.get_checkpoint_height(height)? {
+        let db_hash = db.get_db_hash(checkpoint_height)?;
+        let server_hash = get_block_hash(&mut client, checkpoint_height).await?;
+        if db_hash == Some(server_hash) {
+            db.trim_to_height(checkpoint_height + 1)?;
+            return Ok(checkpoint_height);
+        }
+        log::info!("Block {} is not on the server chain", checkpoint_height);
+        if checkpoint_height == 0 {
+            break;
+        }
+        height = checkpoint_height - 1;
+    }
+    log::error!("No common ancestor found in local blocks");
+    anyhow::bail!(ChainError::Reorg)
+}
+
+/// Blocks stored before the Orchard migration have no Orchard tree. Get the tree
+/// of the last block from the server so that new Orchard notes have the right
+/// positions and witnesses
+async fn fill_orchard_tree(
+    coin_type: CoinType,
+    db_path: &str,
+    client: &mut CompactTxStreamerClient<Channel>,
+) -> anyhow::Result<()> {
+    let height = DbAdapter::new(coin_type, db_path)?.get_missing_orchard_tree_height()?;
+    if let Some(height) = height {
+        log::info!("Fetching the Orchard tree of block {}", height);
+        let (_, orchard_tree) = get_tree_states(client, height).await?;
+        DbAdapter::new(coin_type, db_path)?.store_orchard_tree(height, &orchard_tree)?;
+    }
+    Ok(())
+}
+
+/// Compare the Sapling tree built locally with the tree state of the server
+async fn check_tree(
+    client: &mut CompactTxStreamerClient<Channel>,
+    tree: &CTree,
+    height: u32,
+) -> anyhow::Result<bool> {
+    let (server_tree, _) = get_tree_states(client, height).await?;
+    Ok(server_tree.to_commitment_tree().root() == tree.to_commitment_tree().root())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn sync_async_once(
     coin_type: CoinType,
     _chunk_size: u32,
     get_tx: bool,
     db_path: &str,
     target_height_offset: u32,
     progress_callback: AMProgressCallback,
-    cancel: &'static AtomicBool,
+    control: Arc<SyncControl>,
+    block_cache: Option<Arc<BlockCache>>,
+    tree_check_interval: u32,
     ld_url: &str,
+    settings: &ConnectionSettings,
 ) -> anyhow::Result<()> {
     let ld_url = ld_url.to_owned();
+    let settings = settings.clone();
     let db_path = db_path.to_string();
     let network = {
         let chain = get_coin_chain(coin_type);
         *chain.network()
     };
 
-    let mut client = connect_lightwalletd(&ld_url).await?;
+    let mut client = connect_lightwalletd_with(&ld_url, &settings).await?;
+    fill_orchard_tree(coin_type, &db_path, &mut client).await?;
     let (start_height, prev_hash, vks) = {
         let db = DbAdapter::new(coin_type, &db_path)?;
         let height = db.get_db_height()?;
@@ -79,6 +333,7 @@ pub async fn sync_async(
     let (processor_tx, mut processor_rx) = mpsc::channel::<Blocks>(1);
 
     let db_path2 = db_path.clone();
+    let block_cache2 = block_cache.clone();
 
     let downloader = tokio::spawn(async move {
         log::info!("download_scheduler");
@@ -88,7 +343,8 @@ pub async fn sync_async(
             end_height,
             prev_hash,
             processor_tx,
-            cancel,
+            &control,
+            block_cache.as_deref(),
         )
         .await?;
         Ok::<_, anyhow::Error>(())
@@ -99,14 +355,37 @@ pub async fn sync_async(
     let processor = tokio::spawn(async move {
         let mut db = DbAdapter::new(coin_type, &db_path2)?;
         let mut nfs = db.get_nullifiers()?;
+        let mut orchard_nfs = db.get_orchard_nullifiers()?;
+        let mut tracker = ProgressTracker::new(start_height, end_height);
+        let mut current_height = start_height;
+        let mut verified_height = start_height;
 
-        while let Some(blocks) = processor_rx.recv().await {
+        loop {
+            {
+                let callback = proc_callback.lock().await;
+                callback(tracker.progress(SyncPhase::Downloading, current_height));
+            }
+            let start = Instant::now();
+            let blocks = match processor_rx.recv().await {
+                Some(blocks) => blocks,
+                None => break,
+            };
+            tracker.elapsed.download += start.elapsed().as_millis() as u64;
             if blocks.0.is_empty() {
                 continue;
             }
+            tracker.downloaded_bytes +=
+                blocks.0.iter().map(|b| b.encoded_len() as u64).sum::<u64>();
+            {
+                let callback = proc_callback.lock().await;
+                callback(tracker.progress(SyncPhase::Decrypting, current_height));
+            }
             let (mut tree, witnesses) = db.get_tree()?;
             let mut bp = BlockProcessor::new(&tree, &witnesses);
             let mut absolute_position_at_block_start = tree.get_position();
+            let (orchard_tree, orchard_witnesses) = db.get_orchard_tree()?;
+            let mut orchard_position_at_block_start = orchard_tree.size();
+            let mut orchard_new_notes: HashMap<usize, u32> = HashMap::new();
 
             log::info!("start processing - {}", blocks.0[0].height);
             log::info!("Time {:?}", chrono::offset::Local::now());
@@ -218,12 +497,109 @@ pub async fn sync_async(
                         }
                     }
 
+                    let mut my_orchard_nfs: Vec<Nf> = vec![];
+                    for nf in b.orchard_spends.iter() {
+                        if let Some(&nf_ref) = orchard_nfs.get(nf) {
+                            log::info!("Orchard NF FOUND {} {}", nf_ref.id_note, b.height);
+                            DbAdapter::mark_orchard_spent(nf_ref.id_note, b.height, &db_tx)?;
+                            my_orchard_nfs.push(*nf);
+                            orchard_nfs.remove(nf);
+                        }
+                    }
+
+                    for n in b.orchard_notes.iter() {
+                        let p = orchard_position_at_block_start + n.position_in_block;
+
+                        let note = &n.note;
+                        let nf = note.nullifier(&n.fvk).to_bytes();
+
+                        let id_tx = DbAdapter::store_transaction(
+                            &n.txid,
+                            n.account,
+                            n.height,
+                            b.compact_block.time,
+                            n.tx_index as u32,
+                            &db_tx,
+                        )?;
+                        new_ids_tx.insert(
+                            id_tx,
+                            TxIdHeight {
+                                id_tx,
+                                height: n.height,
+                                index: n.tx_index as u32,
+                            },
+                        );
+                        let id_note = DbAdapter::store_received_orchard_note(
+                            &ReceivedOrchardNote {
+                                account: n.account,
+                                height: n.height,
+                                output_index: n.output_index as u32,
+                                diversifier: note.recipient().diversifier().as_array().to_vec(),
+                                value: note.value().inner(),
+                                rho: note.rho().to_bytes().to_vec(),
+                                rseed: note.rseed().as_bytes().to_vec(),
+                                nf: nf.to_vec(),
+                            },
+                            id_tx,
+                            p,
+                            &db_tx,
+                        )?;
+                        DbAdapter::add_value(id_tx, note.value().inner() as i64, &db_tx)?;
+                        orchard_nfs.insert(
+                            Nf(nf),
+                            NfRef {
+                                id_note,
+                                account: n.account,
+                            },
+                        );
+                        orchard_new_notes.insert(p, id_note);
+                    }
+
+                    if !my_orchard_nfs.is_empty() {
+                        for (tx_index, tx) in b.compact_block.vtx.iter().enumerate() {
+                            for ca in tx.actions.iter() {
+                                let mut nf = [0u8; 32];
+                                nf.copy_from_slice(&ca.nullifier);
+                                let nf = Nf(nf);
+                                if my_orchard_nfs.contains(&nf) {
+                                    let (account, note_value) =
+                                        DbAdapter::get_received_orchard_note_value(&nf, &db_tx)?;
+                                    let id_tx = DbAdapter::store_transaction(
+                                        &tx.hash,
+                                        account,
+                                        b.height,
+                                        b.compact_block.time,
+                                        tx_index as u32,
+                                        &db_tx,
+                                    )?;
+                                    new_ids_tx.insert(
+                                        id_tx,
+                                        TxIdHeight {
+                                            id_tx,
+                                            height: b.height,
+                                            index: tx_index as u32,
+                                        },
+                                    );
+                                    DbAdapter::add_value(id_tx, -note_value, &db_tx)?;
+                                }
+                            }
+                        }
+                    }
+
                     absolute_position_at_block_start += b.count_outputs as usize;
+                    orchard_position_at_block_start += b.count_actions as usize;
+                    tracker.outputs += (b.count_outputs + b.count_actions) as u64;
+                    tracker.notes_found += (b.notes.len() + b.orchard_notes.len()) as u32;
                 }
                 log::info!("Dec end : {}", start.elapsed().as_millis());
 
                 db_tx.commit()?;
             }
+            tracker.elapsed.decrypt += start.elapsed().as_millis() as u64;
+            {
+                let callback = proc_callback.lock().await;
+                callback(tracker.progress(SyncPhase::Witnesses, current_height));
+            }
 
             let start = Instant::now();
             let mut nodes: Vec<Node> = vec![];
@@ -243,10 +619,33 @@ pub async fn sync_async(
             }
             // println!("NOTES = {}", nodes.len());
 
+            let mut obp = OrchardBlockProcessor::new(&orchard_tree, &orchard_witnesses);
+            let mut orchard_position = orchard_tree.size();
+            for cb in blocks.0.iter() {
+                for tx in cb.vtx.iter() {
+                    for ca in tx.actions.iter() {
+                        let mut cmx = [0u8; 32];
+                        cmx.copy_from_slice(&ca.cmx);
+                        let cmx: Option<ExtractedNoteCommitment> =
+                            ExtractedNoteCommitment::from_bytes(&cmx).into();
+                        let cmx = cmx.ok_or_else(|| anyhow::anyhow!("Invalid cmx"))?;
+                        let node = MerkleHashOrchard::from_cmx(&cmx);
+                        obp.add_node(node, orchard_new_notes.get(&orchard_position).copied());
+                        orchard_position += 1;
+                    }
+                }
+            }
+            let (orchard_tree, orchard_witnesses) = obp.finalize();
+
             log::info!("Witness : {}", start.elapsed().as_millis());
+            tracker.elapsed.witnesses += start.elapsed().as_millis() as u64;
 
             let start = Instant::now();
             if get_tx && !new_ids_tx.is_empty() {
+                {
+                    let callback = proc_callback.lock().await;
+                    callback(tracker.progress(SyncPhase::TxDetails, current_height));
+                }
                 let mut ids: Vec<_> = new_ids_tx.into_iter().map(|(_, v)| v).collect();
                 ids.sort_by(|a, b| {
                     let c = a.height.cmp(&b.height);
@@ -256,44 +655,75 @@ pub async fn sync_async(
                     c
                 });
                 let ids: Vec<_> = ids.into_iter().map(|e| e.id_tx).collect();
-                let mut client = connect_lightwalletd(&ld_url).await?;
-                retrieve_tx_info(coin_type, &mut client, &db_path2, &ids)
-                    .await
-                    .unwrap();
+                let mut client = connect_lightwalletd_with(&ld_url, &settings).await?;
+                // the transactions stay pending and are retrieved by a later sync
+                if let Err(err) = retrieve_tx_info(coin_type, &mut client, &db_path2, &ids).await {
+                    log::warn!("Transaction details not retrieved: {}", err);
+                }
             }
             log::info!("Transaction Details : {}", start.elapsed().as_millis());
+            tracker.elapsed.tx_details += start.elapsed().as_millis() as u64;
 
             let (new_tree, new_witnesses) = bp.finalize();
             tree = new_tree;
             witnesses = new_witnesses;
 
             if let Some(block) = blocks.0.last() {
+                let height = block.height as u32;
+                if tree_check_interval != 0
+                    && (height - verified_height >= tree_check_interval || height == end_height)
+                {
+                    let mut client = connect_lightwalletd_with(&ld_url, &settings).await?;
+                    if !check_tree(&mut client, &tree, height).await? {
+                        log::error!(
+                            "Sapling tree mismatch at {}: rolling back to {}",
+                            height,
+                            verified_height
+                        );
+                        // the notes of the chunk are already stored
+                        db.trim_to_height(verified_height + 1)?;
+                        if let Some(block_cache) = &block_cache2 {
+                            block_cache.truncate(verified_height + 1)?;
+                        }
+                        anyhow::bail!(ChainError::TreeMismatch { height });
+                    }
+                    verified_height = height;
+                }
                 {
                     let mut db_transaction = db.begin_transaction()?;
-                    let height = block.height as u32;
                     for w in witnesses.iter() {
                         DbAdapter::store_witnesses(&db_transaction, w, height, w.id_note)?;
                     }
+                    for w in orchard_witnesses.iter() {
+                        DbAdapter::store_orchard_witnesses(&db_transaction, w, height)?;
+                    }
                     DbAdapter::store_block(
                         &mut db_transaction,
                         height,
                         &block.hash,
                         block.time,
                         &tree,
+                        &orchard_tree,
                     )?;
+                    let mut block_times: Vec<_> = blocks
+                        .0
+                        .iter()
+                        .filter(|b| b.height as u32 % BLOCK_TIME_INTERVAL == 0)
+                        .map(|b| (b.height as u32, b.time))
+                        .collect();
+                    block_times.push((height, block.time));
+                    DbAdapter::store_block_times(&db_transaction, &block_times)?;
+                    DbAdapter::update_pending_txs(&db_transaction, height)?;
                     db_transaction.commit()?;
                     // db_transaction is dropped here
                 }
                 log::info!("progress: {}", block.height);
-                let callback = proc_callback.lock().await;
-                callback(block.height as u32);
+                current_height = block.height as u32;
             }
         }
 
         let callback = progress_callback.lock().await;
-        callback(end_height);
-
-        db.purge_old_witnesses(end_height - 100)?;
+        callback(tracker.progress(SyncPhase::Done, end_height));
 
         Ok::<_, anyhow::Error>(())
     });
@@ -324,8 +754,584 @@ pub async fn sync_async(
     Ok(())
 }
 
-pub async fn latest_height(ld_url: &str) -> anyhow::Result<u32> {
-    let mut client = connect_lightwalletd(ld_url).await?;
+/// Rewind the db to `height` from the nearest local checkpoint at or below it
+/// and replay the blocks up to `height`
+///
+/// Returns false if there is no such checkpoint
+pub async fn rewind_to_checkpoint(
+    coin_type: CoinType,
+    get_tx: bool,
+    db_path: &str,
+    height: u32,
+    block_cache: Option<Arc<BlockCache>>,
+    ld_url: &str,
+    settings: &ConnectionSettings,
+) -> anyhow::Result<bool> {
+    let checkpoint_height = {
+        let mut db = DbAdapter::new(coin_type, db_path)?;
+        let checkpoint_height = match db.get_checkpoint_height(height)? {
+            Some(checkpoint_height) => checkpoint_height,
+            None => return Ok(false),
+        };
+        db.trim_to_height(checkpoint_height + 1)?;
+        checkpoint_height
+    };
+    if checkpoint_height < height {
+        log::info!("Replaying blocks from checkpoint {}", checkpoint_height);
+        let mut client = connect_lightwalletd_with(ld_url, settings).await?;
+        let latest_height = get_latest_height(&mut client).await?;
+        let cb: AMProgressCallback = Arc::new(Mutex::new(|_: SyncProgress| {}));
+        sync_async(
+            coin_type,
+            0,
+            get_tx,
+            db_path,
+            latest_height.saturating_sub(height),
+            cb,
+            Arc::new(SyncControl::default()),
+            block_cache,
+            0,
+            ld_url,
+            settings,
+        )
+        .await?;
+    }
+    Ok(true)
+}
+
+/// Transaction of a note found or spent during an account rescan
+struct RescanTx {
+    txid: Vec<u8>,
+    height: u32,
+    timestamp: u32,
+    tx_index: u32,
+}
+
+enum RescanNote {
+    Sapling(ReceivedNote, usize),
+    Orchard(ReceivedOrchardNote, usize),
+}
+
+/// A note spent during the rescan: either already in the db or found by the rescan
+#[derive(Clone, Copy)]
+enum RescanNoteRef {
+    Stored(u32),
+    Found(usize),
+}
+
+/// Scan the blocks from `from_height` to the synced height for a single account
+///
+/// Notes that were missed because the account was added after they were mined
+/// are stored with their witnesses at the synced height. The notes of the other
+/// accounts and the stored tree states are left untouched. Nothing is written
+/// unless the whole range is scanned
+#[allow(clippy::too_many_arguments)]
+pub async fn rescan_account(
+    coin_type: CoinType,
+    get_tx: bool,
+    db_path: &str,
+    account: u32,
+    from_height: u32,
+    progress_callback: AMProgressCallback,
+    control: Arc<SyncControl>,
+    block_cache: Option<Arc<BlockCache>>,
+    ld_url: &str,
+    settings: &ConnectionSettings,
+) -> anyhow::Result<()> {
+    let network = {
+        let chain = get_coin_chain(coin_type);
+        *chain.network()
+    };
+    let mut db = DbAdapter::new(coin_type, db_path)?;
+    let end_height = db.get_db_height()?;
+    if from_height == 0 || from_height > end_height {
+        return Ok(());
+    }
+    let vk = db
+        .get_fvks()?
+        .remove(&account)
+        .ok_or_else(|| anyhow::anyhow!("Unknown account {}", account))?;
+    let mut vks = HashMap::new();
+    vks.insert(account, vk);
+    let decrypter = DecryptNode::new(vks);
+    let known_nfs = db.get_account_nullifiers(account)?;
+    let mut nfs: HashMap<Nf, RescanNoteRef> = db
+        .get_nullifiers()?
+        .into_iter()
+        .filter(|(_, nf_ref)| nf_ref.account == account)
+        .map(|(nf, nf_ref)| (nf, RescanNoteRef::Stored(nf_ref.id_note)))
+        .collect();
+    let mut orchard_nfs: HashMap<Nf, RescanNoteRef> = db
+        .get_orchard_nullifiers()?
+        .into_iter()
+        .filter(|(_, nf_ref)| nf_ref.account == account)
+        .map(|(nf, nf_ref)| (nf, RescanNoteRef::Stored(nf_ref.id_note)))
+        .collect();
+    let mut client = connect_lightwalletd_with(ld_url, settings).await?;
+    fill_orchard_tree(coin_type, db_path, &mut client).await?;
+    let (db_tree, _) = db.get_tree()?;
+    let (db_orchard_tree, _) = db.get_orchard_tree()?;
+
+    // There is no Sapling tree state before the activation height
+    let sapling_height: u32 = network
+        .activation_height(NetworkUpgrade::Sapling)
+        .unwrap()
+        .into();
+    let start_height = (from_height - 1).max(sapling_height);
+    let prev_hash = get_block_hash(&mut client, start_height).await?;
+    let (mut tree, mut orchard_tree) = get_tree_states(&mut client, start_height).await?;
+    let mut witnesses: Vec<Witness> = vec![];
+    let mut orchard_witnesses: Vec<OrchardWitness> = vec![];
+    // A rewind trims the witnesses above the block it goes back to. Keep the
+    // witnesses of the found notes at every stored block, not only the last one
+    let mut checkpoint_heights: HashSet<u32> = db
+        .get_checkpoint_heights(start_height)?
+        .into_iter()
+        .collect();
+    checkpoint_heights.insert(end_height);
+    let mut checkpoint_witnesses: Vec<(u32, Vec<Witness>, Vec<OrchardWitness>)> = vec![];
+
+    let (processor_tx, mut processor_rx) = mpsc::channel::<Blocks>(1);
+    let downloader = tokio::spawn(async move {
+        download_chain(
+            &mut client,
+            start_height,
+            end_height,
+            Some(prev_hash),
+            processor_tx,
+            &control,
+            block_cache.as_deref(),
+        )
+        .await
+    });
+
+    // Witnesses refer to the found notes by their index until they are stored
+    let mut found_notes: Vec<(RescanTx, RescanNote)> = vec![];
+    let mut spends: Vec<(RescanTx, Pool, RescanNoteRef, Nf)> = vec![];
+    let mut tracker = ProgressTracker::new(start_height, end_height);
+    let mut current_height = start_height;
+    while let Some(blocks) = processor_rx.recv().await {
+        if blocks.0.is_empty() {
+            continue;
+        }
+        tracker.downloaded_bytes += blocks.0.iter().map(|b| b.encoded_len() as u64).sum::<u64>();
+        // Stop at every stored block so that its witnesses can be kept
+        for blocks in blocks
+            .0
+            .split_inclusive(|b| checkpoint_heights.contains(&(b.height as u32)))
+        {
+            let mut position = tree.get_position();
+            let mut orchard_position = orchard_tree.size();
+            let mut new_witnesses: Vec<Witness> = vec![];
+            let mut orchard_new_notes: HashMap<usize, u32> = HashMap::new();
+            {
+                let dec_blocks = decrypter.decrypt_blocks(&network, blocks);
+                for b in dec_blocks.iter() {
+                    let rescan_tx = |txid: &[u8], tx_index: usize| RescanTx {
+                        txid: txid.to_vec(),
+                        height: b.height,
+                        timestamp: b.compact_block.time,
+                        tx_index: tx_index as u32,
+                    };
+                    for (tx_index, tx) in b.compact_block.vtx.iter().enumerate() {
+                        for cs in tx.spends.iter() {
+                            let mut nf = [0u8; 32];
+                            nf.copy_from_slice(&cs.nf);
+                            if let Some(note_ref) = nfs.remove(&Nf(nf)) {
+                                spends.push((
+                                    rescan_tx(&tx.hash, tx_index),
+                                    Pool::Sapling,
+                                    note_ref,
+                                    Nf(nf),
+                                ));
+                            }
+                        }
+                        for ca in tx.actions.iter() {
+                            let mut nf = [0u8; 32];
+                            nf.copy_from_slice(&ca.nullifier);
+                            if let Some(note_ref) = orchard_nfs.remove(&Nf(nf)) {
+                                spends.push((
+                                    rescan_tx(&tx.hash, tx_index),
+                                    Pool::Orchard,
+                                    note_ref,
+                                    Nf(nf),
+                                ));
+                            }
+                        }
+                    }
+
+                    for n in b.notes.iter() {
+                        let p = position + n.position_in_block;
+                        let note = &n.note;
+                        let nf = note.nf(&n.ivk.fvk.vk, p as u64);
+                        if known_nfs.contains(&Nf(nf.0)) {
+                            continue;
+                        }
+                        let index = found_notes.len();
+                        found_notes.push((
+                            rescan_tx(&n.txid, n.tx_index),
+                            RescanNote::Sapling(
+                                ReceivedNote {
+                                    account,
+                                    height: n.height,
+                                    output_index: n.output_index as u32,
+                                    diversifier: n.pa.diversifier().0.to_vec(),
+                                    value: note.value,
+                                    rcm: note.rcm().to_repr().to_vec(),
+                                    nf: nf.0.to_vec(),
+                                    spent: None,
+                                },
+                                n.position_in_block,
+                            ),
+                        ));
+                        nfs.insert(Nf(nf.0), RescanNoteRef::Found(index));
+                        new_witnesses.push(Witness::new(p, index as u32, Some(n.clone())));
+                        tracker.notes_found += 1;
+                    }
+
+                    for n in b.orchard_notes.iter() {
+                        let p = orchard_position + n.position_in_block;
+                        let note = &n.note;
+                        let nf = note.nullifier(&n.fvk).to_bytes();
+                        if known_nfs.contains(&Nf(nf)) {
+                            continue;
+                        }
+                        let index = found_notes.len();
+                        found_notes.push((
+                            rescan_tx(&n.txid, n.tx_index),
+                            RescanNote::Orchard(
+                                ReceivedOrchardNote {
+                                    account,
+                                    height: n.height,
+                                    output_index: n.output_index as u32,
+                                    diversifier: note.recipient().diversifier().as_array().to_vec(),
+                                    value: note.value().inner(),
+                                    rho: note.rho().to_bytes().to_vec(),
+                                    rseed: note.rseed().as_bytes().to_vec(),
+                                    nf: nf.to_vec(),
+                                },
+                                p,
+                            ),
+                        ));
+                        orchard_nfs.insert(Nf(nf), RescanNoteRef::Found(index));
+                        orchard_new_notes.insert(p, index as u32);
+                        tracker.notes_found += 1;
+                    }
+
+                    position += b.count_outputs as usize;
+                    orchard_position += b.count_actions as usize;
+                    tracker.outputs += (b.count_outputs + b.count_actions) as u64;
+                }
+            }
+
+            let mut nodes: Vec<Node> = vec![];
+            let mut obp = OrchardBlockProcessor::new(&orchard_tree, &orchard_witnesses);
+            let mut orchard_position = orchard_tree.size();
+            for cb in blocks.iter() {
+                for tx in cb.vtx.iter() {
+                    for co in tx.outputs.iter() {
+                        let mut cmu = [0u8; 32];
+                        cmu.copy_from_slice(&co.cmu);
+                        nodes.push(Node::new(cmu));
+                    }
+                    for ca in tx.actions.iter() {
+                        let mut cmx = [0u8; 32];
+                        cmx.copy_from_slice(&ca.cmx);
+                        let cmx: Option<ExtractedNoteCommitment> =
+                            ExtractedNoteCommitment::from_bytes(&cmx).into();
+                        let cmx = cmx.ok_or_else(|| anyhow::anyhow!("Invalid cmx"))?;
+                        let node = MerkleHashOrchard::from_cmx(&cmx);
+                        obp.add_node(node, orchard_new_notes.get(&orchard_position).copied());
+                        orchard_position += 1;
+                    }
+                }
+            }
+            let mut bp = BlockProcessor::new(&tree, &witnesses);
+            bp.add_nodes(&mut nodes, &new_witnesses);
+            let (new_tree, new_ws) = bp.finalize();
+            tree = new_tree;
+            witnesses = new_ws;
+            let (new_orchard_tree, new_orchard_ws) = obp.finalize();
+            orchard_tree = new_orchard_tree;
+            orchard_witnesses = new_orchard_ws;
+
+            if let Some(block) = blocks.last() {
+                current_height = block.height as u32;
+            }
+            if checkpoint_heights.contains(&current_height) {
+                checkpoint_witnesses.push((
+                    current_height,
+                    witnesses.clone(),
+                    orchard_witnesses.clone(),
+                ));
+            }
+        }
+        let callback = progress_callback.lock().await;
+        callback(tracker.progress(SyncPhase::Witnesses, current_height));
+    }
+    downloader.await??;
+
+    if current_height != end_height {
+        log::info!("Rescan of account {} interrupted", account);
+        return Ok(());
+    }
+
+    // The tree we rebuilt must end up where the synced tree is
+    let mut tree_bytes = vec![];
+    tree.write(&mut tree_bytes)?;
+    let mut db_tree_bytes = vec![];
+    db_tree.write(&mut db_tree_bytes)?;
+    if tree_bytes != db_tree_bytes || orchard_tree.root() != db_orchard_tree.root() {
+        anyhow::bail!("Rescanned tree does not match the synced tree");
+    }
+
+    let mut new_ids_tx: HashMap<u32, TxIdHeight> = HashMap::new();
+    {
+        let db_tx = db.begin_transaction()?;
+        let mut store_tx = |tx: &RescanTx| -> anyhow::Result<u32> {
+            let id_tx = DbAdapter::store_transaction(
+                &tx.txid,
+                account,
+                tx.height,
+                tx.timestamp,
+                tx.tx_index,
+                &db_tx,
+            )?;
+            new_ids_tx.insert(
+                id_tx,
+                TxIdHeight {
+                    id_tx,
+                    height: tx.height,
+                    index: tx.tx_index,
+                },
+            );
+            Ok(id_tx)
+        };
+        let mut note_ids: Vec<u32> = vec![];
+        for (tx, note) in found_notes.iter() {
+            let id_tx = store_tx(tx)?;
+            let (id_note, value) = match note {
+                RescanNote::Sapling(note, position) => (
+                    DbAdapter::store_received_note(note, id_tx, *position, &db_tx)?,
+                    note.value,
+                ),
+                RescanNote::Orchard(note, position) => (
+                    DbAdapter::store_received_orchard_note(note, id_tx, *position, &db_tx)?,
+                    note.value,
+                ),
+            };
+            DbAdapter::add_value(id_tx, value as i64, &db_tx)?;
+            note_ids.push(id_note);
+        }
+        for (tx, pool, note_ref, nf) in spends.iter() {
+            let id_note = match note_ref {
+                RescanNoteRef::Stored(id_note) => *id_note,
+                RescanNoteRef::Found(index) => note_ids[*index],
+            };
+            let id_tx = store_tx(tx)?;
+            let (_, value) = match pool {
+                Pool::Sapling => {
+                    DbAdapter::mark_spent(id_note, tx.height, &db_tx)?;
+                    DbAdapter::get_received_note_value(nf, &db_tx)?
+                }
+                Pool::Orchard => {
+                    DbAdapter::mark_orchard_spent(id_note, tx.height, &db_tx)?;
+                    DbAdapter::get_received_orchard_note_value(nf, &db_tx)?
+                }
+            };
+            DbAdapter::add_value(id_tx, -value, &db_tx)?;
+        }
+        for (height, witnesses, orchard_witnesses) in checkpoint_witnesses.iter() {
+            for w in witnesses.iter() {
+                let id_note = note_ids[w.id_note as usize];
+                DbAdapter::store_witnesses(&db_tx, w, *height, id_note)?;
+            }
+            for w in orchard_witnesses.iter() {
+                let w = OrchardWitness {
+                    id_note: note_ids[w.id_note as usize],
+                    witness: w.witness.clone(),
+                };
+                DbAdapter::store_orchard_witnesses(&db_tx, &w, *height)?;
+            }
+        }
+        db_tx.commit()?;
+    }
+
+    if get_tx && !new_ids_tx.is_empty() {
+        {
+            let callback = progress_callback.lock().await;
+            callback(tracker.progress(SyncPhase::TxDetails, end_height));
+        }
+        let mut ids: Vec<_> = new_ids_tx.into_iter().map(|(_, v)| v).collect();
+        ids.sort_by(|a, b| a.height.cmp(&b.height).then(a.index.cmp(&b.index)));
+        let ids: Vec<_> = ids.into_iter().map(|e| e.id_tx).collect();
+        let mut client = connect_lightwalletd_with(ld_url, settings).await?;
+        retrieve_tx_info(coin_type, &mut client, db_path, &ids).await?;
+    }
+
+    let callback = progress_callback.lock().await;
+    callback(tracker.progress(SyncPhase::Done, end_height));
+    log::info!("Rescan of account {} completed", account);
+
+    Ok(())
+}
+
+pub async fn latest_height(ld_url: &str, settings: &ConnectionSettings) -> anyhow::Result<u32> {
+    let mut client = connect_lightwalletd_with(ld_url, settings).await?;
     let height = get_latest_height(&mut client).await?;
     Ok(height)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::scan::{rescan_account, rewind_to_checkpoint, AMProgressCallback, SyncProgress};
+    use crate::synthetic::fixtures::{TestChain, NETWORK};
+    use crate::{CTree, ChainError, CoinType, ConnectionSettings};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use zcash_client_backend::encoding::{
+        encode_extended_full_viewing_key, encode_payment_address,
+    };
+    use zcash_primitives::consensus::Parameters;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tree_mismatch() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let chain = &tc.chain;
+        let lwd = chain.to_mock();
+        // the server reports the empty tree at the tip
+        let mut tree_state = chain.tree_states()[0].clone();
+        tree_state.height = chain.height() as u64;
+        lwd.add_tree_state(tree_state);
+        let server = lwd.start().await?;
+
+        let test_db = tc.create_db("tree_mismatch", 1)?;
+        let db = test_db.open()?;
+        let start_height = db.get_db_height()?;
+        let err = test_db.sync(server.url(), 0).await.unwrap_err();
+        match err.downcast_ref::<ChainError>() {
+            Some(ChainError::TreeMismatch { height }) => assert_eq!(*height, chain.height()),
+            _ => panic!("Unexpected error {}", err),
+        }
+
+        // nothing past the last verified height is kept
+        assert_eq!(db.get_db_height()?, start_height);
+        assert_eq!(db.get_balance(test_db.accounts[0])?, 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rewind_to_checkpoint() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let (chain, fvk1) = (&tc.chain, &tc.fvk1);
+        let server = tc.start().await?;
+        let test_db = tc.create_db("rewind_checkpoint", 1)?;
+        let account = test_db.accounts[0];
+        let mut db = test_db.open()?;
+        let height = chain.height();
+        test_db.sync(server.url(), 3).await?;
+        test_db.sync(server.url(), 0).await?;
+        assert_eq!(db.get_checkpoint_height(height - 1)?, Some(height - 3));
+
+        // the checkpoint is the only one of its interval and is kept
+        db.purge_old_witnesses(height, 1_000)?;
+        assert_eq!(db.get_checkpoint_height(height - 1)?, Some(height - 3));
+
+        let rewound = rewind_to_checkpoint(
+            CoinType::Zcash,
+            false,
+            &test_db.path,
+            height - 1,
+            None,
+            server.url(),
+            &ConnectionSettings::default(),
+        )
+        .await?;
+        assert!(rewound);
+        assert_eq!(db.get_db_height()?, height - 1);
+        assert_eq!(db.get_balance(account)?, chain.balance(fvk1));
+
+        let tree_state = chain
+            .tree_states()
+            .iter()
+            .find(|ts| ts.height == (height - 1) as u64)
+            .unwrap();
+        let root = CTree::read(&*hex::decode(&tree_state.sapling_tree)?)?
+            .to_commitment_tree()
+            .root();
+        let notes = db.get_spendable_notes(account, height - 1, fvk1)?;
+        assert_eq!(notes.len(), 2);
+        for n in notes.iter() {
+            assert_eq!(n.witness.root(), root);
+        }
+
+        // without checkpoints, only the most recent tree state remains
+        db.purge_old_witnesses(height - 1, 0)?;
+        assert_eq!(db.get_checkpoint_height(height - 2)?, None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rescan_then_rewind() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let (chain, fvk2) = (&tc.chain, &tc.fvk2);
+        let server = tc.start().await?;
+        let test_db = tc.create_db("rescan_rewind", 1)?;
+        let db = test_db.open()?;
+        let height = chain.height();
+        test_db.sync(server.url(), 3).await?;
+        test_db.sync(server.url(), 0).await?;
+
+        // an account added after the sync only gets its notes from a rescan
+        let ivk =
+            encode_extended_full_viewing_key(NETWORK.hrp_sapling_extended_full_viewing_key(), fvk2);
+        let address = encode_payment_address(
+            NETWORK.hrp_sapling_payment_address(),
+            &fvk2.default_address().1,
+        );
+        let (account, _) = db.store_account("account2", None, 0, None, &ivk, &address)?;
+        let cb: AMProgressCallback = Arc::new(Mutex::new(|_: SyncProgress| {}));
+        rescan_account(
+            CoinType::Zcash,
+            false,
+            &test_db.path,
+            account,
+            chain.blocks()[0].height as u32,
+            cb,
+            Arc::new(Default::default()),
+            None,
+            server.url(),
+            &ConnectionSettings::default(),
+        )
+        .await?;
+        assert_eq!(db.get_balance(account)?, chain.balance(fvk2));
+
+        // the rewind goes back to the checkpoint below the last block
+        let rewound = rewind_to_checkpoint(
+            CoinType::Zcash,
+            false,
+            &test_db.path,
+            height - 1,
+            None,
+            server.url(),
+            &ConnectionSettings::default(),
+        )
+        .await?;
+        assert!(rewound);
+        assert_eq!(db.get_balance(account)?, chain.balance(fvk2));
+        let tree_state = chain
+            .tree_states()
+            .iter()
+            .find(|ts| ts.height == (height - 1) as u64)
+            .unwrap();
+        let root = CTree::read(&*hex::decode(&tree_state.sapling_tree)?)?
+            .to_commitment_tree()
+            .root();
+        let notes = db.get_spendable_notes(account, height - 1, fvk2)?;
+        assert_eq!(notes.len(), 2);
+        for n in notes.iter() {
+            assert_eq!(n.witness.root(), root);
+        }
+        Ok(())
+    }
+}
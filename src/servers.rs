@@ -0,0 +1,299 @@
+use crate::chain::{get_block_hash, get_latest_height};
+use crate::{connect_lightwalletd_with, ChainError, CompactTxStreamerClient, ConnectionSettings};
+use futures::future;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+use tonic::transport::Channel;
+
+/*
+Cross validation of the lightwalletd servers of a coin.
+
+A single server can report a fake tip, or serve a chain of its own to hide
+transactions. Every server is asked for its latest height, and the servers
+too far from the median height are set aside. The others must return the same
+block hashes at the lowest of their heights and at heights sampled at random
+below it. The servers that agree with a strict majority can be used, the other
+ones are flagged.
+ */
+
+/// Tolerated distance between the height of a server and the median height
+pub const MAX_HEIGHT_DRIFT: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum ServerStatus {
+    Agree,
+    Disagree,
+    Behind,
+    Ahead,
+    Unreachable,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerReport {
+    pub url: String,
+    pub height: Option<u32>,
+    pub status: ServerStatus,
+}
+
+fn request_timeout(settings: &ConnectionSettings) -> Duration {
+    // connections through a proxy such as Tor take longer to establish
+    if settings.proxy.is_some() {
+        Duration::from_secs(10)
+    } else {
+        Duration::from_secs(1)
+    }
+}
+
+async fn connect_server(
+    url: &str,
+    settings: &ConnectionSettings,
+    delay: Duration,
+) -> Option<(CompactTxStreamerClient<Channel>, u32)> {
+    let mut client = timeout(delay, connect_lightwalletd_with(url, settings))
+        .await
+        .ok()?
+        .ok()?;
+    let height = timeout(delay, get_latest_height(&mut client))
+        .await
+        .ok()?
+        .ok()?;
+    log::info!("{} {}", url, height);
+    Some((client, height))
+}
+
+async fn get_block_hashes(
+    mut client: CompactTxStreamerClient<Channel>,
+    heights: &[u32],
+    delay: Duration,
+) -> Option<Vec<[u8; 32]>> {
+    let mut hashes = vec![];
+    for &height in heights.iter() {
+        let hash = timeout(delay, get_block_hash(&mut client, height))
+            .await
+            .ok()?
+            .ok()?;
+        hashes.push(hash);
+    }
+    Some(hashes)
+}
+
+/// Compare the chains of `servers` at their common tip and at `samples` random
+/// heights from `min_height`
+pub async fn check_servers(
+    servers: &[String],
+    settings: &ConnectionSettings,
+    min_height: u32,
+    samples: u32,
+) -> Vec<ServerReport> {
+    let delay = request_timeout(settings);
+    let connections =
+        future::join_all(servers.iter().map(|s| connect_server(s, settings, delay))).await;
+    let mut reports: Vec<_> = servers
+        .iter()
+        .zip(connections.iter())
+        .map(|(url, c)| ServerReport {
+            url: url.clone(),
+            height: c.as_ref().map(|(_, h)| *h),
+            status: ServerStatus::Unreachable,
+        })
+        .collect();
+
+    let mut heights: Vec<_> = reports.iter().filter_map(|r| r.height).collect();
+    if heights.is_empty() {
+        return reports;
+    }
+    heights.sort_unstable();
+    let median = heights[heights.len() / 2];
+    let mut clients = vec![];
+    let mut tip = u32::MAX;
+    for (i, c) in connections.into_iter().enumerate() {
+        if let Some((client, height)) = c {
+            if height + MAX_HEIGHT_DRIFT < median {
+                reports[i].status = ServerStatus::Behind;
+            } else if height > median + MAX_HEIGHT_DRIFT {
+                reports[i].status = ServerStatus::Ahead;
+            } else {
+                tip = tip.min(height);
+                clients.push((i, client));
+            }
+        }
+    }
+
+    let mut heights = vec![tip];
+    if tip > min_height {
+        let mut rng = rand::thread_rng();
+        for _ in 0..samples {
+            heights.push(rng.gen_range(min_height..tip));
+        }
+    }
+    let heights = &heights;
+    let hashes = future::join_all(
+        clients
+            .into_iter()
+            .map(|(i, client)| async move { (i, get_block_hashes(client, heights, delay).await) }),
+    )
+    .await;
+
+    let mut groups: HashMap<Vec<[u8; 32]>, Vec<usize>> = HashMap::new();
+    let mut count = 0;
+    for (i, hashes) in hashes {
+        if let Some(hashes) = hashes {
+            groups.entry(hashes).or_default().push(i);
+            count += 1;
+        }
+    }
+    for group in groups.values() {
+        let status = if group.len() * 2 > count {
+            ServerStatus::Agree
+        } else {
+            ServerStatus::Disagree
+        };
+        for &i in group.iter() {
+            reports[i].status = status;
+        }
+    }
+    for r in reports.iter() {
+        if r.status != ServerStatus::Agree {
+            log::warn!("Server {} {:?} at height {:?}", r.url, r.status, r.height);
+        }
+    }
+    reports
+}
+
+/// The server with the highest height among those that agree on the chain
+pub async fn get_best_server(servers: &[String], settings: &ConnectionSettings) -> Option<String> {
+    check_servers(servers, settings, 0, 0)
+        .await
+        .into_iter()
+        .filter(|r| r.status == ServerStatus::Agree)
+        .max_by_key(|r| r.height)
+        .map(|r| r.url)
+}
+
+/// Whether the error comes from the server, the connection to it or the data
+/// it sent, so that another server may succeed
+pub fn is_server_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.is::<tonic::Status>()
+            || e.is::<tonic::transport::Error>()
+            || e.is::<Elapsed>()
+            || matches!(
+                e.downcast_ref::<ChainError>(),
+                Some(ChainError::InvalidData { .. })
+            )
+    })
+}
+
+/// Run `f` with each server in turn until it succeeds or fails for another
+/// reason than the server
+///
+/// Returns the server used and the result of `f`
+pub async fn with_failover<T, F, R>(servers: &[String], mut f: F) -> anyhow::Result<(String, T)>
+where
+    F: FnMut(String) -> R,
+    R: Future<Output = anyhow::Result<T>>,
+{
+    let mut servers = servers.iter().peekable();
+    while let Some(url) = servers.next() {
+        match f(url.clone()).await {
+            Ok(res) => return Ok((url.clone(), res)),
+            Err(err) if is_server_error(&err) && servers.peek().is_some() => {
+                log::warn!("Server {} failed: {}", url, err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    anyhow::bail!("No server")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::servers::{check_servers, get_best_server, with_failover, ServerStatus};
+    use crate::synthetic::fixtures::TestChain;
+    use crate::{ChainError, ConnectionSettings, MockLightwalletd};
+
+    #[tokio::test]
+    async fn test_check_servers() -> anyhow::Result<()> {
+        let mut mocks = vec![];
+        for _ in 0..3 {
+            let lwd = MockLightwalletd::new("main", 419_200);
+            lwd.mine_blocks(20);
+            mocks.push(lwd);
+        }
+        // a server on another chain
+        mocks[1].rollback(419_210);
+        mocks[1].mine_blocks(10);
+        // a server far behind
+        let lagging = MockLightwalletd::new("main", 419_200);
+        lagging.mine_blocks(5);
+        mocks.push(lagging);
+
+        let mut servers = vec![];
+        for lwd in mocks.iter() {
+            servers.push(lwd.start().await?);
+        }
+        let mut urls: Vec<_> = servers.iter().map(|s| s.url().to_string()).collect();
+        urls.push("http://127.0.0.1:1".to_string());
+
+        let settings = ConnectionSettings::default();
+        let reports = check_servers(&urls, &settings, 419_200, 5).await;
+        let statuses: Vec<_> = reports.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                ServerStatus::Agree,
+                ServerStatus::Disagree,
+                ServerStatus::Agree,
+                ServerStatus::Behind,
+                ServerStatus::Unreachable
+            ]
+        );
+        assert_eq!(reports[0].height, Some(419_219));
+        assert_eq!(reports[4].height, None);
+
+        let best = get_best_server(&urls[1..=2], &settings).await;
+        assert!(best.is_none());
+        let best = get_best_server(&urls[2..], &settings).await;
+        assert_eq!(best.as_deref(), Some(urls[2].as_str()));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_failover() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let chain = &tc.chain;
+        let server1 = tc.start().await?;
+        let server2 = tc.start().await?;
+        let test_db = tc.create_db("failover", 2)?;
+        test_db.sync(server1.url(), 3).await?;
+        let servers = vec![server1.url().to_string(), server2.url().to_string()];
+        server1.stop().await?;
+
+        // the second server picks up where the first one stopped
+        let test_db2 = &test_db;
+        let (url, _) =
+            with_failover(&servers, |url| async move { test_db2.sync(&url, 0).await }).await?;
+        assert_eq!(url, servers[1]);
+        let db = test_db.open()?;
+        assert_eq!(db.get_db_height()?, chain.height());
+        let accounts = &test_db.accounts;
+        assert_eq!(db.get_balance(accounts[0])?, chain.balance(&tc.fvk1));
+        assert_eq!(db.get_balance(accounts[1])?, chain.balance(&tc.fvk2));
+
+        // other errors are not retried
+        let mut attempts = 0;
+        let res = with_failover(&servers, |_| {
+            attempts += 1;
+            async { Err::<(), _>(anyhow::anyhow!(ChainError::Busy)) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,486 @@
+use crate::builder::BlockProcessor;
+use crate::commitment::CTree;
+use crate::lw_rpc::{
+    CompactBlock, CompactSaplingOutput, CompactSaplingSpend, CompactTx, TreeState,
+};
+use crate::lwd_mock::MockLightwalletd;
+use ff::{Field, PrimeField};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaChaRng;
+use zcash_note_encryption::COMPACT_NOTE_SIZE;
+use zcash_primitives::consensus::{BlockHeight, Network, NetworkUpgrade, Parameters};
+use zcash_primitives::memo::Memo;
+use zcash_primitives::sapling::note_encryption::sapling_note_encryption;
+use zcash_primitives::sapling::{Node, PaymentAddress, Rseed};
+use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+/// A Sapling note created by `SyntheticChain`
+#[derive(Clone)]
+pub struct SyntheticNote {
+    pub height: u32,
+    pub position: u64,
+    pub value: u64,
+    pub nf: [u8; 32],
+    pub spent: Option<u32>,
+    address: PaymentAddress,
+}
+
+/*
+Generator of synthetic compact blocks for scanning tests and benchmarks
+
+Transactions are added to the next block with `add_tx` (or the `add_note`,
+`spend_note` and `add_filler_outputs` shortcuts) and `mine_block` closes it.
+Notes are really encrypted to the default address of the given viewing keys
+and their nullifiers are derived from their position in the commitment tree,
+so the blocks can go through the regular scanner. The tree state after each
+block is computed with `CTree`.
+
+The randomness comes from a seeded rng: the same calls produce the same chain.
+ */
+pub struct SyntheticChain {
+    network: Network,
+    height: u32,
+    prev_hash: [u8; 32],
+    time: u32,
+    tree: CTree,
+    position: u64,
+    rng: ChaChaRng,
+    filler_fvk: ExtendedFullViewingKey,
+    vtx: Vec<CompactTx>,
+    blocks: Vec<CompactBlock>,
+    tree_states: Vec<TreeState>,
+    notes: Vec<SyntheticNote>,
+}
+
+impl SyntheticChain {
+    /// The first block is mined at `start_height + 1`, on top of an empty commitment tree
+    pub fn new(network: Network, start_height: u32, seed: u64) -> Self {
+        let mut rng = ChaChaRng::seed_from_u64(seed);
+        let mut filler_seed = [0u8; 32];
+        rng.fill_bytes(&mut filler_seed);
+        let filler_fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&filler_seed));
+        let mut chain = SyntheticChain {
+            network,
+            height: start_height,
+            prev_hash: [0u8; 32],
+            time: 1_600_000_000,
+            tree: CTree::new(),
+            position: 0,
+            rng,
+            filler_fvk,
+            vtx: vec![],
+            blocks: vec![],
+            tree_states: vec![],
+            notes: vec![],
+        };
+        chain.push_tree_state();
+        chain
+    }
+
+    /// Add a transaction that spends `spends` and creates notes for `outputs`
+    ///
+    /// Returns the ids of the new notes
+    pub fn add_tx(
+        &mut self,
+        spends: &[usize],
+        outputs: &[(&ExtendedFullViewingKey, u64)],
+    ) -> Vec<usize> {
+        let height = self.height + 1;
+        let mut tx = self.new_tx();
+        for &id_note in spends.iter() {
+            let note = &mut self.notes[id_note];
+            assert!(note.spent.is_none(), "Note {} is already spent", id_note);
+            note.spent = Some(height);
+            tx.spends.push(CompactSaplingSpend {
+                nf: note.nf.to_vec(),
+            });
+        }
+        let mut ids = vec![];
+        for (fvk, value) in outputs.iter() {
+            let (output, nf, address) = self.new_output(fvk, *value);
+            tx.outputs.push(output);
+            ids.push(self.notes.len());
+            self.notes.push(SyntheticNote {
+                height,
+                position: self.position,
+                value: *value,
+                nf,
+                spent: None,
+                address,
+            });
+            self.position += 1;
+        }
+        self.vtx.push(tx);
+        ids
+    }
+
+    pub fn add_note(&mut self, fvk: &ExtendedFullViewingKey, value: u64) -> usize {
+        self.add_tx(&[], &[(fvk, value)])[0]
+    }
+
+    pub fn spend_note(&mut self, id_note: usize) {
+        self.add_tx(&[id_note], &[]);
+    }
+
+    /// Add a transaction with `count` outputs that belong to nobody we know
+    pub fn add_filler_outputs(&mut self, count: usize) {
+        let mut tx = self.new_tx();
+        let fvk = self.filler_fvk.clone();
+        for _ in 0..count {
+            let (output, _, _) = self.new_output(&fvk, 10_000);
+            tx.outputs.push(output);
+            self.position += 1;
+        }
+        self.vtx.push(tx);
+    }
+
+    /// Close the current block and return its height
+    pub fn mine_block(&mut self) -> u32 {
+        self.height += 1;
+        self.time += 75;
+        let vtx = std::mem::take(&mut self.vtx);
+        let mut nodes: Vec<Node> = vec![];
+        for tx in vtx.iter() {
+            for co in tx.outputs.iter() {
+                let mut cmu = [0u8; 32];
+                cmu.copy_from_slice(&co.cmu);
+                nodes.push(Node::new(cmu));
+            }
+        }
+        let mut bp = BlockProcessor::new(&self.tree, &[]);
+        bp.add_nodes(&mut nodes, &[]);
+        let (tree, _) = bp.finalize();
+        self.tree = tree;
+
+        let mut hash = [0u8; 32];
+        self.rng.fill_bytes(&mut hash);
+        let block = CompactBlock {
+            height: self.height as u64,
+            hash: hash.to_vec(),
+            prev_hash: self.prev_hash.to_vec(),
+            time: self.time,
+            vtx,
+            ..CompactBlock::default()
+        };
+        self.prev_hash = hash;
+        self.blocks.push(block);
+        self.push_tree_state();
+        self.height
+    }
+
+    pub fn mine_blocks(&mut self, count: u32) -> u32 {
+        for _ in 0..count {
+            self.mine_block();
+        }
+        self.height
+    }
+
+    /// Height of the last mined block
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn blocks(&self) -> &[CompactBlock] {
+        &self.blocks
+    }
+
+    /// Tree states from the start height to the last mined block
+    pub fn tree_states(&self) -> &[TreeState] {
+        &self.tree_states
+    }
+
+    pub fn tree(&self) -> &CTree {
+        &self.tree
+    }
+
+    pub fn notes(&self) -> &[SyntheticNote] {
+        &self.notes
+    }
+
+    /// Value of the unspent notes of the default address of `fvk`
+    pub fn balance(&self, fvk: &ExtendedFullViewingKey) -> u64 {
+        let (_, address) = fvk.default_address();
+        self.notes
+            .iter()
+            .filter(|n| n.spent.is_none() && n.address == address)
+            .map(|n| n.value)
+            .sum()
+    }
+
+    /// A mock lightwalletd that serves the mined blocks and their tree states
+    pub fn to_mock(&self) -> MockLightwalletd {
+        let sapling_activation_height: u32 = self
+            .network
+            .activation_height(NetworkUpgrade::Sapling)
+            .unwrap()
+            .into();
+        let lwd = MockLightwalletd::new(self.chain_name(), sapling_activation_height);
+        for b in self.blocks.iter() {
+            lwd.add_block(b.clone());
+        }
+        for ts in self.tree_states.iter() {
+            lwd.add_tree_state(ts.clone());
+        }
+        lwd
+    }
+
+    fn chain_name(&self) -> &'static str {
+        if self.network == Network::TestNetwork {
+            "test"
+        } else {
+            "main"
+        }
+    }
+
+    fn new_tx(&mut self) -> CompactTx {
+        let mut hash = [0u8; 32];
+        self.rng.fill_bytes(&mut hash);
+        CompactTx {
+            index: self.vtx.len() as u64,
+            hash: hash.to_vec(),
+            ..CompactTx::default()
+        }
+    }
+
+    fn new_output(
+        &mut self,
+        fvk: &ExtendedFullViewingKey,
+        value: u64,
+    ) -> (CompactSaplingOutput, [u8; 32], PaymentAddress) {
+        let (_, address) = fvk.default_address();
+        let height = BlockHeight::from_u32(self.height + 1);
+        let rseed = if self.network.is_nu_active(NetworkUpgrade::Canopy, height) {
+            let mut rseed = [0u8; 32];
+            self.rng.fill_bytes(&mut rseed);
+            Rseed::AfterZip212(rseed)
+        } else {
+            Rseed::BeforeZip212(jubjub::Fr::random(&mut self.rng))
+        };
+        let note = address.create_note(value, rseed).unwrap();
+        let nf = note.nf(&fvk.fvk.vk, self.position);
+        let encryptor = sapling_note_encryption::<_, Network>(
+            None,
+            note.clone(),
+            address.clone(),
+            Memo::Empty.encode(),
+            &mut self.rng,
+        );
+        let enc_ciphertext = encryptor.encrypt_note_plaintext();
+        let output = CompactSaplingOutput {
+            cmu: note.cmu().to_repr().to_vec(),
+            epk: encryptor.epk().to_bytes().to_vec(),
+            ciphertext: enc_ciphertext[..COMPACT_NOTE_SIZE].to_vec(),
+        };
+        (output, nf.0, address)
+    }
+
+    fn push_tree_state(&mut self) {
+        let mut tree = vec![];
+        self.tree.write(&mut tree).unwrap();
+        let mut hash = self.prev_hash;
+        hash.reverse();
+        self.tree_states.push(TreeState {
+            network: self.chain_name().to_string(),
+            height: self.height as u64,
+            hash: hex::encode(hash),
+            time: self.time,
+            sapling_tree: hex::encode(tree),
+            orchard_tree: String::new(),
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use crate::scan::{AMProgressCallback, SyncControl, SyncProgress};
+    use crate::synthetic::SyntheticChain;
+    use crate::{sync_async, CoinType, ConnectionSettings, DbAdapter, MockServer};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use zcash_client_backend::encoding::{
+        encode_extended_full_viewing_key, encode_payment_address,
+    };
+    use zcash_primitives::consensus::{Network, NetworkUpgrade, Parameters};
+    use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+    pub const NETWORK: Network = Network::MainNetwork;
+
+    pub fn fvk(seed: u8) -> ExtendedFullViewingKey {
+        ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[seed; 32]))
+    }
+
+    /// Notes for both viewing keys over 7 blocks, with a spend of the first note
+    pub fn make_chain(
+        fvk1: &ExtendedFullViewingKey,
+        fvk2: &ExtendedFullViewingKey,
+    ) -> SyntheticChain {
+        let start_height: u32 = NETWORK
+            .activation_height(NetworkUpgrade::Sapling)
+            .unwrap()
+            .into();
+        let mut chain = SyntheticChain::new(NETWORK, start_height, 42);
+        let n1 = chain.add_note(fvk1, 100_000);
+        chain.add_filler_outputs(5);
+        chain.add_note(fvk2, 50_000);
+        chain.mine_block();
+        chain.add_filler_outputs(3);
+        chain.mine_blocks(3);
+        chain.add_tx(&[n1], &[(fvk2, 30_000), (fvk1, 60_000)]);
+        chain.mine_block();
+        chain.add_note(fvk1, 7_000);
+        chain.mine_blocks(2);
+        chain
+    }
+
+    /// The chain of [make_chain] and its viewing keys, for the tests that sync a wallet
+    pub struct TestChain {
+        pub fvk1: ExtendedFullViewingKey,
+        pub fvk2: ExtendedFullViewingKey,
+        pub chain: SyntheticChain,
+    }
+
+    impl Default for TestChain {
+        fn default() -> Self {
+            let fvk1 = fvk(1);
+            let fvk2 = fvk(2);
+            let chain = make_chain(&fvk1, &fvk2);
+            TestChain { fvk1, fvk2, chain }
+        }
+    }
+
+    impl TestChain {
+        /// A database with accounts for the first `count` viewing keys
+        pub fn create_db(&self, name: &str, count: usize) -> anyhow::Result<TestDb> {
+            let fvks = [&self.fvk1, &self.fvk2];
+            TestDb::new(name, &fvks[..count])
+        }
+
+        /// Serve the chain from a new mock lightwalletd
+        pub async fn start(&self) -> anyhow::Result<MockServer> {
+            self.chain.to_mock().start().await
+        }
+    }
+
+    /// A wallet database in the temp directory with one account per viewing key.
+    /// The file is removed when it is dropped, even if the test fails: open the
+    /// connections after creating it so that they are closed first
+    pub struct TestDb {
+        pub path: String,
+        pub accounts: Vec<u32>,
+    }
+
+    impl TestDb {
+        pub fn new(name: &str, fvks: &[&ExtendedFullViewingKey]) -> anyhow::Result<Self> {
+            let path = std::env::temp_dir().join(format!("{}_{}.db", name, std::process::id()));
+            let path = path.to_str().unwrap().to_string();
+            let _ = std::fs::remove_file(&path);
+            let mut test_db = TestDb {
+                path,
+                accounts: vec![],
+            };
+            let db = test_db.open()?;
+            db.init_db()?;
+            for (i, fvk) in fvks.iter().enumerate() {
+                let ivk = encode_extended_full_viewing_key(
+                    NETWORK.hrp_sapling_extended_full_viewing_key(),
+                    fvk,
+                );
+                let (_, pa) = fvk.default_address();
+                let address = encode_payment_address(NETWORK.hrp_sapling_payment_address(), &pa);
+                let (id_account, _) =
+                    db.store_account(&format!("account{}", i), None, 0, None, &ivk, &address)?;
+                test_db.accounts.push(id_account);
+            }
+            Ok(test_db)
+        }
+
+        pub fn open(&self) -> anyhow::Result<DbAdapter> {
+            DbAdapter::new(CoinType::Zcash, &self.path)
+        }
+
+        /// Sync all the accounts with the server at `url`
+        pub async fn sync(&self, url: &str, target_height_offset: u32) -> anyhow::Result<()> {
+            let cb: AMProgressCallback = Arc::new(Mutex::new(|_: SyncProgress| {}));
+            sync_async(
+                CoinType::Zcash,
+                0,
+                false,
+                &self.path,
+                target_height_offset,
+                cb,
+                Arc::new(SyncControl::default()),
+                None,
+                1,
+                url,
+                &ConnectionSettings::default(),
+            )
+            .await
+        }
+    }
+
+    impl Drop for TestDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+            // files of the write-ahead log
+            let _ = std::fs::remove_file(format!("{}-wal", self.path));
+            let _ = std::fs::remove_file(format!("{}-shm", self.path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::AccountViewKey;
+    use crate::synthetic::fixtures::{fvk, make_chain, TestChain, NETWORK};
+    use crate::DecryptNode;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_synthetic_decrypt() {
+        let fvk1 = fvk(1);
+        let fvk2 = fvk(2);
+        let chain = make_chain(&fvk1, &fvk2);
+        assert_eq!(chain.balance(&fvk1), 67_000);
+        assert_eq!(chain.balance(&fvk2), 80_000);
+
+        let mut vks = HashMap::new();
+        vks.insert(1, AccountViewKey::from_fvk(&fvk1));
+        let decrypter = DecryptNode::new(vks);
+        let blocks = decrypter.decrypt_blocks(&NETWORK, chain.blocks());
+        let values: Vec<u64> = blocks
+            .iter()
+            .flat_map(|b| b.notes.iter().map(|n| n.note.value))
+            .collect();
+        assert_eq!(values, vec![100_000, 60_000, 7_000]);
+        let spent_nf = chain.notes()[0].nf;
+        assert!(blocks
+            .iter()
+            .any(|b| b.spends.iter().any(|nf| nf.0 == spent_nf)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_synthetic_sync() -> anyhow::Result<()> {
+        let tc = TestChain::default();
+        let (chain, fvk1, fvk2) = (&tc.chain, &tc.fvk1, &tc.fvk2);
+        let server = tc.start().await?;
+        let test_db = tc.create_db("synthetic", 2)?;
+        let accounts = &test_db.accounts;
+        test_db.sync(server.url(), 0).await?;
+        let db = test_db.open()?;
+
+        assert_eq!(db.get_db_height()?, chain.height());
+        let tip_time = chain.blocks().last().unwrap().time;
+        assert_eq!(db.get_block_time(chain.height())?, Some(tip_time));
+        assert_eq!(db.get_balance(accounts[0])?, chain.balance(fvk1));
+        assert_eq!(db.get_balance(accounts[1])?, chain.balance(fvk2));
+
+        let root = chain.tree().to_commitment_tree().root();
+        let notes = db.get_spendable_notes(accounts[0], chain.height(), fvk1)?;
+        let value: u64 = notes.iter().map(|n| n.note.value).sum();
+        assert_eq!(value, chain.balance(fvk1));
+        for n in notes.iter() {
+            assert_eq!(n.witness.root(), root);
+        }
+        Ok(())
+    }
+}
@@ -1,17 +1,23 @@
 use crate::coinconfig::CoinConfig;
-use crate::{AddressList, CompactTxStreamerClient, GetAddressUtxosArg, GetAddressUtxosReply};
+use crate::db::DbAdapter;
+use crate::{
+    get_branch, AddressList, BlockId, BlockRange, CompactTxStreamerClient, GetAddressUtxosArg,
+    GetAddressUtxosReply, TransparentAddressBlockFilter,
+};
 use anyhow::anyhow;
 use base58check::FromBase58Check;
 use bip39::{Language, Mnemonic, Seed};
 use ripemd::{Digest, Ripemd160};
 use secp256k1::{All, PublicKey, Secp256k1, SecretKey};
 use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
 use tiny_hderive::bip32::ExtendedPrivKey;
 use tonic::transport::Channel;
 use tonic::Request;
-use zcash_client_backend::encoding::encode_transparent_address;
+use zcash_client_backend::encoding::{decode_transparent_address, encode_transparent_address};
 use zcash_primitives::consensus::{Network, Parameters};
 use zcash_primitives::legacy::TransparentAddress;
+use zcash_primitives::transaction::Transaction;
 
 pub async fn get_taddr_balance(
     client: &mut CompactTxStreamerClient<Channel>,
@@ -73,7 +79,185 @@ pub async fn scan_transparent_accounts(
             index += 1;
         }
     }
-    db.store_t_scan(&addresses)?;
+    db.store_t_scan(c.id_account, &addresses)?;
+    Ok(())
+}
+
+/// Download the transactions of the transparent addresses of every account
+/// up to `end_height` and update the utxos and the transaction history
+pub async fn sync_transparent(
+    network: &Network,
+    client: &mut CompactTxStreamerClient<Channel>,
+    db: &mut DbAdapter,
+    end_height: u32,
+) -> anyhow::Result<()> {
+    let mut account_addresses: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+    for (account, address) in db.get_transparent_addresses()? {
+        account_addresses.entry(account).or_default().push(address);
+    }
+    for (account, addresses) in account_addresses.iter() {
+        // a transaction of several addresses of the account is only stored once
+        let mut txs: BTreeMap<(u32, [u8; 32]), Transaction> = BTreeMap::new();
+        for address in addresses.iter() {
+            // the transactions at the last height are fetched again but they are only counted once
+            let start_height = db
+                .get_transparent_sync_height(*account, address)?
+                .unwrap_or(1);
+            if start_height > end_height {
+                continue;
+            }
+            let filter = TransparentAddressBlockFilter {
+                address: address.clone(),
+                range: Some(BlockRange {
+                    start: Some(BlockId {
+                        height: start_height as u64,
+                        hash: vec![],
+                    }),
+                    end: Some(BlockId {
+                        height: end_height as u64,
+                        hash: vec![],
+                    }),
+                }),
+            };
+            let mut rep = client
+                .get_taddress_txids(Request::new(filter))
+                .await?
+                .into_inner();
+            while let Some(raw_tx) = rep.message().await? {
+                let height = raw_tx.height as u32;
+                let tx = Transaction::read(&*raw_tx.data, get_branch(network, height))?;
+                txs.insert((height, *tx.txid().as_ref()), tx);
+            }
+        }
+
+        let mut timestamps: HashMap<u32, u32> = HashMap::new();
+        for (height, _) in txs.keys() {
+            if !timestamps.contains_key(height) {
+                let block = client
+                    .get_block(Request::new(BlockId {
+                        height: *height as u64,
+                        hash: vec![],
+                    }))
+                    .await?
+                    .into_inner();
+                timestamps.insert(*height, block.time);
+            }
+        }
+
+        let txs: Vec<_> = txs
+            .into_iter()
+            .map(|((height, _), tx)| (height, tx))
+            .collect();
+        store_transparent_txs(network, db, *account, addresses, &txs, &timestamps)?;
+    }
+    Ok(())
+}
+
+/// Store the utxos and the history of the transactions of the transparent `addresses`
+/// of an account. The value of a transaction is its net change over all these addresses
+fn store_transparent_txs(
+    network: &Network,
+    db: &mut DbAdapter,
+    account: u32,
+    addresses: &[String],
+    txs: &[(u32, Transaction)],
+    timestamps: &HashMap<u32, u32>,
+) -> anyhow::Result<()> {
+    let mut taddrs: Vec<(TransparentAddress, &str)> = vec![];
+    for address in addresses.iter() {
+        let taddr = decode_transparent_address(
+            &network.b58_pubkey_address_prefix(),
+            &network.b58_script_address_prefix(),
+            address,
+        )?
+        .ok_or_else(|| anyhow!("Invalid transparent address"))?;
+        taddrs.push((taddr, address));
+    }
+    let our_address = |taddr: Option<TransparentAddress>| {
+        taddrs
+            .iter()
+            .find(|(a, _)| Some(*a) == taddr)
+            .map(|(_, address)| *address)
+    };
+    let db_tx = db.begin_transaction()?;
+    // store the outputs first: a transaction may spend an output of the same block
+    for (height, tx) in txs.iter() {
+        let txid = tx.txid();
+        if let Some(bundle) = tx.transparent_bundle() {
+            for (vout, txout) in bundle.vout.iter().enumerate() {
+                if let Some(address) = our_address(txout.script_pubkey.address()) {
+                    DbAdapter::store_utxo(
+                        account,
+                        txid.as_ref(),
+                        vout as u32,
+                        address,
+                        &txout.script_pubkey.0,
+                        i64::from(txout.value),
+                        *height,
+                        &db_tx,
+                    )?;
+                }
+            }
+        }
+    }
+    for (height, tx) in txs.iter() {
+        let bundle = match tx.transparent_bundle() {
+            Some(bundle) => bundle,
+            None => continue,
+        };
+        let mut value = 0i64;
+        let mut tx_address = None;
+        for txin in bundle.vin.iter() {
+            let prevout = &txin.prevout;
+            for (_, address) in taddrs.iter() {
+                if let Some(v) = DbAdapter::spend_utxo(
+                    account,
+                    address,
+                    prevout.hash(),
+                    prevout.n(),
+                    *height,
+                    &db_tx,
+                )? {
+                    value -= v;
+                    tx_address = tx_address.or(Some(*address));
+                    break;
+                }
+            }
+        }
+        // for a payment, show the first recipient
+        let mut counterparty = None;
+        for txout in bundle.vout.iter() {
+            let address = txout.script_pubkey.address();
+            match our_address(address) {
+                Some(a) => {
+                    value += i64::from(txout.value);
+                    tx_address = tx_address.or(Some(a));
+                }
+                None if counterparty.is_none() => counterparty = address,
+                None => {}
+            }
+        }
+        let tx_address = match (tx_address, counterparty) {
+            (None, _) => continue,
+            (_, Some(a)) if value < 0 => encode_transparent_address(
+                &network.b58_pubkey_address_prefix(),
+                &network.b58_script_address_prefix(),
+                &a,
+            ),
+            (Some(address), _) => address.to_string(),
+        };
+        let timestamp = timestamps.get(height).copied().unwrap_or(0);
+        DbAdapter::store_transparent_tx(
+            account,
+            tx.txid().as_ref(),
+            *height,
+            timestamp,
+            value,
+            &tx_address,
+            &db_tx,
+        )?;
+    }
+    db_tx.commit()?;
     Ok(())
 }
 
@@ -121,3 +305,193 @@ pub struct TBalance {
     pub address: String,
     pub balance: u64,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DbAdapter;
+    use crate::taddr::{derive_from_secretkey, sync_transparent};
+    use crate::{connect_lightwalletd, get_branch, CoinType, MockLightwalletd};
+    use byteorder::{WriteBytesExt, LE};
+    use rusqlite::params;
+    use secp256k1::SecretKey;
+    use std::io::Write;
+    use zcash_client_backend::encoding::decode_transparent_address;
+    use zcash_primitives::consensus::{Network, Parameters};
+    use zcash_primitives::transaction::Transaction;
+
+    const NETWORK: Network = Network::MainNetwork;
+
+    fn script(address: &str) -> Vec<u8> {
+        let taddr = decode_transparent_address(
+            &NETWORK.b58_pubkey_address_prefix(),
+            &NETWORK.b58_script_address_prefix(),
+            address,
+        )
+        .unwrap()
+        .unwrap();
+        taddr.script().0
+    }
+
+    /// Serialize a transparent only v4 transaction
+    fn make_tx(inputs: &[([u8; 32], u32)], outputs: &[(i64, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![];
+        data.write_u32::<LE>(0x8000_0004).unwrap();
+        data.write_u32::<LE>(0x892F_2085).unwrap();
+        data.write_u8(inputs.len() as u8).unwrap();
+        for (hash, n) in inputs.iter() {
+            data.write_all(hash).unwrap();
+            data.write_u32::<LE>(*n).unwrap();
+            data.write_u8(0).unwrap();
+            data.write_u32::<LE>(0xFFFF_FFFF).unwrap();
+        }
+        data.write_u8(outputs.len() as u8).unwrap();
+        for (value, script) in outputs.iter() {
+            data.write_i64::<LE>(*value).unwrap();
+            data.write_u8(script.len() as u8).unwrap();
+            data.write_all(script).unwrap();
+        }
+        data.write_u32::<LE>(0).unwrap(); // lock time
+        data.write_u32::<LE>(0).unwrap(); // expiry height
+        data.write_i64::<LE>(0).unwrap(); // sapling value balance
+        data.write_all(&[0, 0, 0]).unwrap(); // no spends, outputs or joinsplits
+        data
+    }
+
+    fn txid(data: &[u8], height: u32) -> [u8; 32] {
+        let tx = Transaction::read(data, get_branch(&NETWORK, height)).unwrap();
+        *tx.txid().as_ref()
+    }
+
+    fn get_txs(db: &DbAdapter, account: u32) -> anyhow::Result<Vec<(u32, i64, String)>> {
+        let mut s = db.connection.prepare(
+            "SELECT height, value, address FROM transactions WHERE account = ?1 ORDER BY height",
+        )?;
+        let rows = s.query_map(params![account], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        let mut txs = vec![];
+        for r in rows {
+            txs.push(r?);
+        }
+        Ok(txs)
+    }
+
+    #[tokio::test]
+    async fn test_sync_transparent() -> anyhow::Result<()> {
+        let (sk, address) = derive_from_secretkey(&NETWORK, &SecretKey::from_slice(&[1u8; 32])?)?;
+        let (_, other_address) =
+            derive_from_secretkey(&NETWORK, &SecretKey::from_slice(&[2u8; 32])?)?;
+
+        let lwd = MockLightwalletd::new("main", 419_200);
+        lwd.mine_blocks(20);
+        let deposit = make_tx(&[([7u8; 32], 0)], &[(50_000, script(&address))]);
+        let deposit_txid = txid(&deposit, 419_205);
+        let payment = make_tx(
+            &[(deposit_txid, 0)],
+            &[(30_000, script(&other_address)), (15_000, script(&address))],
+        );
+        let payment_txid = txid(&payment, 419_210);
+        lwd.add_transaction(&deposit_txid, &deposit, 419_205);
+        lwd.add_transaction(&payment_txid, &payment, 419_210);
+        lwd.add_taddress_tx(&address, &deposit_txid)?;
+        lwd.add_taddress_tx(&address, &payment_txid)?;
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
+
+        let db_path = std::env::temp_dir().join(format!("taddr_{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap().to_string();
+        let mut db = DbAdapter::new(CoinType::Zcash, &db_path)?;
+        db.init_db()?;
+        let (account, _) = db.store_account("t", None, 0, None, "ivk", "zaddr")?;
+        db.connection.execute(
+            "INSERT INTO taddrs(account, sk, address) VALUES (?1, ?2, ?3)",
+            params![account, sk, address],
+        )?;
+
+        // a second sync does not count the transactions twice
+        for _ in 0..2 {
+            sync_transparent(&NETWORK, &mut client, &mut db, 419_219).await?;
+            assert_eq!(db.get_transparent_balance(account)?, 15_000);
+            let txs = get_txs(&db, account)?;
+            assert_eq!(
+                txs,
+                vec![
+                    (419_205, 50_000, address.clone()),
+                    (419_210, -35_000, other_address.clone())
+                ]
+            );
+        }
+
+        // a rewind drops the payment and the deposit is unspent again
+        db.trim_to_height(419_206)?;
+        assert_eq!(db.get_transparent_balance(account)?, 50_000);
+        assert_eq!(get_txs(&db, account)?.len(), 1);
+
+        drop(db);
+        let _ = std::fs::remove_file(&db_path);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_transparent_addresses() -> anyhow::Result<()> {
+        let (sk, address) = derive_from_secretkey(&NETWORK, &SecretKey::from_slice(&[1u8; 32])?)?;
+        let (_, scanned_address) =
+            derive_from_secretkey(&NETWORK, &SecretKey::from_slice(&[3u8; 32])?)?;
+        let (_, other_address) =
+            derive_from_secretkey(&NETWORK, &SecretKey::from_slice(&[2u8; 32])?)?;
+
+        // a payment that spends the utxos of both addresses of the account
+        let lwd = MockLightwalletd::new("main", 419_200);
+        lwd.mine_blocks(20);
+        let deposit = make_tx(&[([7u8; 32], 0)], &[(50_000, script(&address))]);
+        let deposit_txid = txid(&deposit, 419_205);
+        let scanned_deposit = make_tx(&[([8u8; 32], 0)], &[(20_000, script(&scanned_address))]);
+        let scanned_deposit_txid = txid(&scanned_deposit, 419_206);
+        let payment = make_tx(
+            &[(deposit_txid, 0), (scanned_deposit_txid, 0)],
+            &[
+                (60_000, script(&other_address)),
+                (5_000, script(&scanned_address)),
+            ],
+        );
+        let payment_txid = txid(&payment, 419_210);
+        lwd.add_transaction(&deposit_txid, &deposit, 419_205);
+        lwd.add_transaction(&scanned_deposit_txid, &scanned_deposit, 419_206);
+        lwd.add_transaction(&payment_txid, &payment, 419_210);
+        lwd.add_taddress_tx(&address, &deposit_txid)?;
+        lwd.add_taddress_tx(&address, &payment_txid)?;
+        lwd.add_taddress_tx(&scanned_address, &scanned_deposit_txid)?;
+        lwd.add_taddress_tx(&scanned_address, &payment_txid)?;
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
+
+        let db_path = std::env::temp_dir().join(format!("taddrs_{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap().to_string();
+        let mut db = DbAdapter::new(CoinType::Zcash, &db_path)?;
+        db.init_db()?;
+        let (account, _) = db.store_account("t", None, 0, None, "ivk", "zaddr")?;
+        db.connection.execute(
+            "INSERT INTO taddrs(account, sk, address) VALUES (?1, ?2, ?3)",
+            params![account, sk, address],
+        )?;
+        db.connection.execute(
+            "INSERT INTO taddr_scan(address, value, aindex, account) VALUES (?1, 0, 1, ?2)",
+            params![scanned_address, account],
+        )?;
+
+        sync_transparent(&NETWORK, &mut client, &mut db, 419_219).await?;
+        assert_eq!(db.get_transparent_balance(account)?, 5_000);
+        assert_eq!(
+            get_txs(&db, account)?,
+            vec![
+                (419_205, 50_000, address.clone()),
+                (419_206, 20_000, scanned_address.clone()),
+                (419_210, -65_000, other_address.clone())
+            ]
+        );
+
+        drop(db);
+        let _ = std::fs::remove_file(&db_path);
+        Ok(())
+    }
+}
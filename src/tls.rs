@@ -0,0 +1,198 @@
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tonic::transport::ClientTlsConfig;
+
+/*
+TLS trust settings of a lightwalletd server.
+
+The certificate built into the binary (ca.pem) is always trusted. The server
+certificate may also chain to a root of the system store or to one of the
+certificates of `ca_file`. When `spki_pins` is not empty, one of the certificates
+presented by the server must also have a public key whose SHA-256 hash is
+in the list (base64, optionally prefixed by "sha256/").
+
+`insecure` disables every check. It is meant for local regtest servers only.
+ */
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    pub system_roots: bool,
+    pub ca_file: Option<String>,
+    pub spki_pins: Vec<String>,
+    pub insecure: bool,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        TlsSettings {
+            system_roots: true,
+            ca_file: None,
+            spki_pins: vec![],
+            insecure: false,
+        }
+    }
+}
+
+impl TlsSettings {
+    pub fn client_config(&self) -> anyhow::Result<ClientTlsConfig> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let mut config = if self.insecure {
+            log::warn!("TLS certificate verification is disabled");
+            builder
+                .with_custom_certificate_verifier(Arc::new(InsecureVerifier))
+                .with_no_client_auth()
+        } else {
+            let roots = self.root_store()?;
+            if self.spki_pins.is_empty() {
+                builder.with_root_certificates(roots).with_no_client_auth()
+            } else {
+                let pins = self
+                    .spki_pins
+                    .iter()
+                    .map(|p| parse_pin(p))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let verifier = PinnedVerifier {
+                    inner: WebPkiVerifier::new(roots, None),
+                    pins,
+                };
+                builder
+                    .with_custom_certificate_verifier(Arc::new(verifier))
+                    .with_no_client_auth()
+            }
+        };
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(ClientTlsConfig::new().rustls_client_config(config))
+    }
+
+    fn root_store(&self) -> anyhow::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        let pem = include_bytes!("ca.pem");
+        let certs = rustls_pemfile::certs(&mut &pem[..])?;
+        roots.add_parsable_certificates(&certs);
+        if self.system_roots {
+            for cert in rustls_native_certs::load_native_certs()? {
+                let _ = roots.add(&Certificate(cert.0));
+            }
+        }
+        if let Some(ca_file) = &self.ca_file {
+            let mut reader = BufReader::new(File::open(ca_file)?);
+            let certs = rustls_pemfile::certs(&mut reader)?;
+            if certs.is_empty() {
+                anyhow::bail!("No certificate in {}", ca_file);
+            }
+            for cert in certs.iter() {
+                roots.add(&Certificate(cert.clone()))?;
+            }
+        }
+        Ok(roots)
+    }
+}
+
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+    let hash = base64::decode(pin)?;
+    if hash.len() != 32 {
+        anyhow::bail!("Invalid SPKI pin {}", pin);
+    }
+    let mut h = [0u8; 32];
+    h.copy_from_slice(&hash);
+    Ok(h)
+}
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of a DER certificate
+pub fn spki_hash(cert: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert)
+        .map_err(|_| anyhow::anyhow!("Invalid certificate"))?;
+    let hash: [u8; 32] = Sha256::digest(cert.tbs_certificate.subject_pki.raw).into();
+    Ok(hash)
+}
+
+/// Checks the certificate chain, then that one of its keys is pinned
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates.iter())
+            .filter_map(|c| spki_hash(&c.0).ok())
+            .any(|h| self.pins.contains(&h));
+        if !pinned {
+            return Err(rustls::Error::General(
+                "Server key does not match the pins".to_string(),
+            ));
+        }
+        Ok(verified)
+    }
+}
+
+struct InsecureVerifier;
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tls::{parse_pin, spki_hash, TlsSettings};
+
+    #[test]
+    fn test_tls_settings() -> anyhow::Result<()> {
+        let settings: TlsSettings = serde_json::from_str(
+            r#"{"spki_pins": ["sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="]}"#,
+        )?;
+        assert!(settings.system_roots);
+        assert!(!settings.insecure);
+        assert_eq!(parse_pin(&settings.spki_pins[0])?, [0u8; 32]);
+        assert!(parse_pin("AAAA").is_err());
+        settings.client_config()?;
+
+        let settings = TlsSettings {
+            ca_file: Some("missing.pem".to_string()),
+            ..TlsSettings::default()
+        };
+        assert!(settings.client_config().is_err());
+
+        let pem = include_bytes!("ca.pem");
+        let certs = rustls_pemfile::certs(&mut &pem[..])?;
+        assert!(!certs.is_empty());
+        for cert in certs.iter() {
+            spki_hash(cert)?;
+        }
+        Ok(())
+    }
+}
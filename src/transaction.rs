@@ -3,6 +3,7 @@ use crate::contact::{Contact, ContactDecoder};
 use crate::api::payment::decode_memo;
 use crate::{CompactTxStreamerClient, DbAdapter, TxFilter};
 use anyhow::anyhow;
+use chrono::Utc;
 use futures::StreamExt;
 use std::collections::HashMap;
 use std::convert::TryFrom;
@@ -169,6 +170,12 @@ struct DecodeTxParams<'a> {
     timestamp: u32,
 }
 
+/// Delay before the first retry of a failed transaction details retrieval, in seconds
+const TX_DETAILS_RETRY_DELAY: u32 = 60;
+
+/// Retrieve the memo, address and contacts of transactions
+///
+/// Transactions that fail stay pending in the db and can be retried later
 pub async fn retrieve_tx_info(
     coin_type: CoinType,
     client: &mut CompactTxStreamerClient<Channel>,
@@ -188,7 +195,7 @@ pub async fn retrieve_tx_info(
     }
     let mut fvk_cache: HashMap<u32, ExtendedFullViewingKey> = HashMap::new();
     let mut decode_tx_params: Vec<DecodeTxParams> = vec![];
-    let (tx, rx) = mpsc::sync_channel::<TransactionInfo>(4);
+    let (tx, rx) = mpsc::sync_channel::<(u32, anyhow::Result<TransactionInfo>)>(4);
     for (index, &id_tx) in tx_ids.iter().enumerate() {
         let (account, height, timestamp, tx_hash, ivk) = db.get_txhash(id_tx)?;
         let fvk: &ExtendedFullViewingKey = fvk_cache.entry(account).or_insert_with(|| {
@@ -212,7 +219,7 @@ pub async fn retrieve_tx_info(
     }
 
     let res = tokio_stream::iter(decode_tx_params).for_each_concurrent(None, |mut p| async move {
-        if let Ok(tx_info) = decode_transaction(
+        let tx_info = decode_transaction(
             &network,
             &mut p.client,
             p.nf_map,
@@ -224,16 +231,24 @@ pub async fn retrieve_tx_info(
             p.timestamp,
             p.index,
         )
-        .await
-        {
-            p.tx.send(tx_info).unwrap();
-            drop(p.tx);
-        }
+        .await;
+        p.tx.send((p.id_tx, tx_info)).unwrap();
+        drop(p.tx);
     });
 
     let f = tokio::spawn(async move {
         let mut contacts: Vec<ContactRef> = vec![];
-        while let Ok(tx_info) = rx.recv() {
+        while let Ok((id_tx, tx_info)) = rx.recv() {
+            let tx_info = match tx_info {
+                Ok(tx_info) => tx_info,
+                Err(err) => {
+                    // keep the transaction pending and retry later
+                    log::warn!("Cannot retrieve details of tx {}: {}", id_tx, err);
+                    let now = Utc::now().timestamp() as u32;
+                    db.set_tx_details_failed(id_tx, now, TX_DETAILS_RETRY_DELAY)?;
+                    continue;
+                }
+            };
             for c in tx_info.contacts.iter() {
                 contacts.push(ContactRef {
                     height: tx_info.height,
@@ -270,48 +285,99 @@ pub async fn retrieve_tx_info(
 #[cfg(test)]
 mod tests {
     use crate::transaction::decode_transaction;
-    use crate::{connect_lightwalletd, DbAdapter, LWD_URL};
+    use crate::{connect_lightwalletd, MockLightwalletd};
+    use byteorder::{WriteBytesExt, LE};
+    use ff::PrimeField;
+    use rand::SeedableRng;
+    use rand_chacha::ChaChaRng;
     use std::collections::HashMap;
-    use zcash_client_backend::encoding::decode_extended_full_viewing_key;
-    use zcash_params::coin::CoinType;
+    use std::io::Write;
+    use std::str::FromStr;
+    use zcash_client_backend::encoding::encode_payment_address;
+    use zcash_params::coin::get_branch;
     use zcash_primitives::consensus::{Network, Parameters};
+    use zcash_primitives::memo::Memo;
+    use zcash_primitives::sapling::note_encryption::sapling_note_encryption;
+    use zcash_primitives::sapling::Rseed;
+    use zcash_primitives::transaction::Transaction;
+    use zcash_primitives::zip32::{ExtendedFullViewingKey, ExtendedSpendingKey};
+
+    const NETWORK: Network = Network::MainNetwork;
+
+    /// Serialize a v4 transaction with a single Sapling output and no proof
+    fn make_tx(fvk: &ExtendedFullViewingKey, value: u64, memo: &str) -> Vec<u8> {
+        let mut rng = ChaChaRng::seed_from_u64(0);
+        let (_, address) = fvk.default_address();
+        let note = address
+            .create_note(value, Rseed::AfterZip212([3u8; 32]))
+            .unwrap();
+        let encryptor = sapling_note_encryption::<_, Network>(
+            Some(fvk.fvk.ovk),
+            note.clone(),
+            address,
+            Memo::from_str(memo).unwrap().encode(),
+            &mut rng,
+        );
+        // the value commitment is not checked: any point of the subgroup will do
+        let cv = *encryptor.epk();
+        let cmu = note.cmu();
+
+        let mut data = vec![];
+        data.write_u32::<LE>(0x8000_0004).unwrap();
+        data.write_u32::<LE>(0x892F_2085).unwrap();
+        data.write_all(&[0, 0]).unwrap(); // no transparent inputs or outputs
+        data.write_u32::<LE>(0).unwrap(); // lock time
+        data.write_u32::<LE>(0).unwrap(); // expiry height
+        data.write_i64::<LE>(0).unwrap(); // sapling value balance
+        data.write_all(&[0, 1]).unwrap(); // no spends, one output
+        data.write_all(&cv.to_bytes()).unwrap();
+        data.write_all(&cmu.to_repr()).unwrap();
+        data.write_all(&encryptor.epk().to_bytes()).unwrap();
+        data.write_all(&encryptor.encrypt_note_plaintext()).unwrap();
+        data.write_all(&encryptor.encrypt_outgoing_plaintext(&cv, &cmu, &mut rng))
+            .unwrap();
+        data.write_all(&[0u8; 192]).unwrap(); // proof
+        data.write_u8(0).unwrap(); // no joinsplits
+        data.write_all(&[0u8; 64]).unwrap(); // binding signature
+        data
+    }
 
     #[tokio::test]
-    async fn test_decode_transaction() {
-        let tx_hash =
-            hex::decode("b47da170329dc311b98892eac23e83025f8bb3ce10bb07535698c91fb37e1e54")
-                .unwrap();
-        let mut client = connect_lightwalletd(LWD_URL).await.unwrap();
-        let db = DbAdapter::new(CoinType::Zcash, "./zec.db").unwrap();
-        let account = 1;
-        let nfs = db.get_nullifiers_raw().unwrap();
-        let mut nf_map: HashMap<(u32, Vec<u8>), u64> = HashMap::new();
-        for nf in nfs.iter() {
-            if nf.0 == account {
-                nf_map.insert((nf.0, nf.2.clone()), nf.1);
-            }
-        }
-        let fvk = db.get_ivk(account).unwrap();
-        let fvk = decode_extended_full_viewing_key(
-            Network::MainNetwork.hrp_sapling_extended_full_viewing_key(),
-            &fvk,
-        )
-        .unwrap()
-        .unwrap();
+    async fn test_decode_transaction() -> anyhow::Result<()> {
+        let fvk = ExtendedFullViewingKey::from(&ExtendedSpendingKey::master(&[1u8; 32]));
+        let height = 1_100_000;
+        let data = make_tx(&fvk, 20_000, "Hello");
+        let tx = Transaction::read(&*data, get_branch(&NETWORK, height))?;
+        let tx_hash = tx.txid().as_ref().to_vec();
+
+        let lwd = MockLightwalletd::new("main", 419_200);
+        lwd.add_transaction(&tx_hash, &data, height);
+        let server = lwd.start().await?;
+        let mut client = connect_lightwalletd(server.url()).await?;
+
+        let nf_map: HashMap<(u32, Vec<u8>), u64> = HashMap::new();
         let tx_info = decode_transaction(
-            &Network::MainNetwork,
+            &NETWORK,
             &mut client,
             &nf_map,
             1,
-            account,
+            1,
             &fvk,
             &tx_hash,
-            1313212,
+            height,
             1000,
             1,
         )
-        .await
-        .unwrap();
-        println!("{:?}", tx_info);
+        .await?;
+        let (_, address) = fvk.default_address();
+        assert_eq!(tx_info.height, height);
+        assert_eq!(tx_info.amount, 20_000);
+        assert_eq!(
+            tx_info.address,
+            encode_payment_address(NETWORK.hrp_sapling_payment_address(), &address)
+        );
+        assert_eq!(tx_info.memo, "Hello");
+        assert!(tx_info.contacts.is_empty());
+        Ok(())
     }
 }
@@ -1,6 +1,17 @@
-use zcash_address::unified::{Address, Container, Receiver};
+use zcash_address::unified::{Address, Container, Encoding, Receiver};
 use zcash_address::{FromAddress, Network, ToAddress, UnsupportedAddress, ZcashAddress};
 
+/// Supports no address type: its conversions are the defaults of the trait
+struct NoReceiver;
+
+impl FromAddress for NoReceiver {}
+
+/// Error for a unified address that lacks the wanted receiver. It comes from
+/// the default conversion because `UnsupportedAddress` cannot be built here
+fn no_receiver<T>(net: Network, data: Address) -> Result<T, UnsupportedAddress> {
+    NoReceiver::from_unified(net, data).map(|_| unreachable!())
+}
+
 #[derive(Debug, Clone)]
 pub struct MyReceiver {
     pub net: Network,
@@ -27,7 +38,7 @@ impl FromAddress for MyReceiver {
                 _ => (),
             }
         }
-        FromAddress::from_unified(net, data)
+        no_receiver(net, data)
     }
 
     fn from_transparent_p2pkh(net: Network, data: [u8; 20]) -> Result<Self, UnsupportedAddress> {
@@ -38,19 +49,58 @@ impl FromAddress for MyReceiver {
     }
 }
 
-pub fn get_ua(_sapling_addr: &str, _transparent_addr: &str) -> anyhow::Result<ZcashAddress> {
-    todo!()
-    // let sapling_addr = ZcashAddress::try_from_encoded(sapling_addr)?;
-    // let transparent_addr = ZcashAddress::try_from_encoded(transparent_addr)?;
-    // let receivers: Vec<_> = vec![sapling_addr, transparent_addr]
-    //     .iter()
-    //     .map(|r| r.clone().convert::<MyReceiver>().unwrap())
-    //     .collect();
-    // let net = receivers.first().unwrap().net.clone();
-    // let receivers: Vec<_> = receivers.iter().map(|r| r.receiver.clone()).collect();
-    // let ua: Address = Address::from_inner(receivers)?;
-    // let ua_address = ZcashAddress::from_unified(net, ua);
-    // Ok(ua_address)
+struct OrchardReceiver([u8; 43]);
+
+impl FromAddress for OrchardReceiver {
+    fn from_unified(net: Network, data: Address) -> Result<Self, UnsupportedAddress> {
+        for r in data.items_as_parsed().iter() {
+            if let Receiver::Orchard(data) = r {
+                return Ok(OrchardReceiver(*data));
+            }
+        }
+        no_receiver(net, data)
+    }
+}
+
+/// Build a unified address from its receivers
+///
+/// The Sapling receiver is mandatory, the transparent and Orchard receivers
+/// are optional
+pub fn get_ua(
+    sapling_addr: &str,
+    transparent_addr: Option<&str>,
+    orchard_addr: Option<&orchard::Address>,
+) -> anyhow::Result<ZcashAddress> {
+    let sapling_addr = ZcashAddress::try_from_encoded(sapling_addr)?.convert::<MyReceiver>()?;
+    let net = sapling_addr.net.clone();
+    let mut receivers: Vec<Receiver> = vec![];
+    if let Some(orchard_addr) = orchard_addr {
+        receivers.push(Receiver::Orchard(orchard_addr.to_raw_address_bytes()));
+    }
+    receivers.push(sapling_addr.receiver);
+    if let Some(transparent_addr) = transparent_addr {
+        let transparent_addr =
+            ZcashAddress::try_from_encoded(transparent_addr)?.convert::<MyReceiver>()?;
+        if transparent_addr.net != net {
+            anyhow::bail!("Receivers belong to different networks");
+        }
+        receivers.push(transparent_addr.receiver);
+    }
+    let ua = Address::try_from_items(receivers)?;
+    let ua_address = ZcashAddress::from_unified(net, ua);
+    Ok(ua_address)
+}
+
+/// Unified address that only has an Orchard receiver, on the network of `sapling_addr`
+pub fn get_orchard_ua(
+    sapling_addr: &str,
+    orchard_addr: &orchard::Address,
+) -> anyhow::Result<ZcashAddress> {
+    let net = ZcashAddress::try_from_encoded(sapling_addr)?
+        .convert::<MyReceiver>()?
+        .net;
+    let ua = Address::try_from_items(vec![Receiver::Orchard(orchard_addr.to_raw_address_bytes())])?;
+    Ok(ZcashAddress::from_unified(net, ua))
 }
 
 pub fn get_sapling(ua_addr: &str) -> anyhow::Result<ZcashAddress> {
@@ -62,20 +112,52 @@ pub fn get_sapling(ua_addr: &str) -> anyhow::Result<ZcashAddress> {
     anyhow::bail!("Invalid UA");
 }
 
+pub fn get_orchard(ua_addr: &str) -> anyhow::Result<orchard::Address> {
+    let ua_addr = ZcashAddress::try_from_encoded(ua_addr)?;
+    let r = ua_addr.convert::<OrchardReceiver>()?;
+    let address: Option<orchard::Address> = orchard::Address::from_raw_address_bytes(&r.0).into();
+    address.ok_or_else(|| anyhow::anyhow!("Invalid Orchard receiver"))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::ua::{get_sapling, get_ua};
+    use crate::ua::{get_orchard, get_orchard_ua, get_sapling, get_ua};
+    use orchard::keys::{FullViewingKey, Scope, SpendingKey};
+
+    const SAPLING_ADDR: &str =
+        "zs1lvzgfzzwl9n85446j292zg0valw2p47hmxnw42wnqsehsmyuvjk0mhxktcs0pqrplacm2vchh35";
 
     #[test]
     fn test_ua() -> anyhow::Result<()> {
         let ua = get_ua(
-            "zs1lvzgfzzwl9n85446j292zg0valw2p47hmxnw42wnqsehsmyuvjk0mhxktcs0pqrplacm2vchh35",
-            "t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo",
+            SAPLING_ADDR,
+            Some("t1UWSSWaojmV5dgDhrSfZC6MAfCwVQ9LLoo"),
+            None,
         )?;
         let ua_str = ua.to_string();
         println!("{}", ua);
+        assert!(ua_str.starts_with("u1"));
         let za = get_sapling(&ua_str)?;
         println!("{}", za);
+        assert_eq!(za.to_string(), SAPLING_ADDR);
+        // no Orchard receiver: an error, not a stack overflow
+        assert!(get_orchard(&ua_str).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ua_orchard() -> anyhow::Result<()> {
+        let sk = SpendingKey::from_zip32_seed(&[7u8; 32], 133, 0).unwrap();
+        let fvk = FullViewingKey::from(&sk);
+        let orchard_addr = fvk.address_at(0u32, Scope::External);
+        let ua = get_ua(SAPLING_ADDR, None, Some(&orchard_addr))?;
+        let ua_str = ua.to_string();
+        assert_eq!(get_sapling(&ua_str)?.to_string(), SAPLING_ADDR);
+        assert_eq!(get_orchard(&ua_str)?, orchard_addr);
+
+        let orchard_ua = get_orchard_ua(SAPLING_ADDR, &orchard_addr)?.to_string();
+        assert!(get_sapling(&orchard_ua).is_err());
 
         Ok(())
     }